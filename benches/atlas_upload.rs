@@ -0,0 +1,77 @@
+//! Counts the bytes the glyph atlas sends to textures while scrolling
+//! through a file of mostly CJK text, against sending every page whole in
+//! RGBA whenever one changed, as it did before gray glyphs had R8 pages and
+//! only what changed went. Needs a font with CJK glyphs to fall back on.
+// Without a test harness, the tests of the module are left out but not
+// their imports
+#[allow(dead_code, unused_imports)]
+#[path = "../src/atlas.rs"]
+mod atlas;
+
+use std::time::Instant;
+
+use atlas::GlyphAtlas;
+
+const LINES: usize = 2000;
+const LINE_LEN: usize = 40;
+/// Lines on screen, the view moving down one every frame
+const VIEW_LINES: usize = 40;
+/// Scale of the text, as `max_scale` sets it
+const SCALE: f32 = 32.;
+
+fn main() {
+    let lines = cjk_lines();
+    let mut atlas = GlyphAtlas::new();
+    atlas.select_scale(SCALE, 1);
+    let mut layers = [0; 2];
+    let (mut full_rgba, mut dirty_r8) = (0, 0);
+    let start = Instant::now();
+    for view in lines.windows(VIEW_LINES) {
+        atlas.start_frame();
+        atlas.add_characters(view.iter().flat_map(|line| line.chars()));
+        let bitmap = atlas.bitmap();
+        let dirty = bitmap.take_uploads(&mut layers, false);
+        if dirty.is_empty() {
+            continue;
+        }
+        dirty_r8 += dirty.iter().map(|upload| upload.bytes()).sum::<usize>();
+        let everything = bitmap.take_uploads(&mut layers, true);
+        full_rgba += everything
+            .iter()
+            .map(|upload| upload.bytes() / upload.format.channels() * 4)
+            .sum::<usize>();
+    }
+
+    let frames = lines.len() - VIEW_LINES + 1;
+    println!("{frames} frames in {:.2?}", start.elapsed());
+    println!("RGBA full pages: {:>10} bytes", full_rgba);
+    println!("R8 dirty rects:  {:>10} bytes", dirty_r8);
+    if dirty_r8 > 0 {
+        println!("{:.1} times less", full_rgba as f64 / dirty_r8 as f64);
+    }
+}
+
+/// Lines of CJK ideographs, among the first 3000 of Unicode, and a little
+/// ASCII, the same on every run
+fn cjk_lines() -> Vec<String> {
+    let mut seed = 0x5eed_u64;
+    let mut next = |n: u64| {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (seed >> 33) % n
+    };
+    (0..LINES)
+        .map(|row| {
+            let mut line = format!("{row:4} ");
+            for _ in 0..LINE_LEN {
+                let c = match next(10) {
+                    0 => char::from(b'a' + next(26) as u8),
+                    _ => char::from_u32(0x4e00 + next(3000) as u32).expect("Ideographs are chars"),
+                };
+                line.push(c);
+            }
+            line
+        })
+        .collect()
+}
@@ -1,9 +1,11 @@
-use gl::types::{GLfloat, GLuint};
+use gl::types::{GLenum, GLfloat, GLuint};
 use std::{
-    cell::{Ref, RefCell, RefMut},
-    collections::{BTreeMap, HashMap},
-    iter::repeat,
-    ops::{Deref, DerefMut},
+    cell::{Cell, Ref, RefCell, RefMut},
+    collections::{btree_map::Entry, BTreeMap, HashMap, HashSet},
+    fmt, iter,
+    ops::DerefMut,
+    ptr,
+    time::{Duration, Instant},
 };
 
 use crossfont::{
@@ -11,11 +13,7 @@ use crossfont::{
     BitmapBuffer, Error, FontDesc, FontKey, GlyphKey, Rasterize, RasterizedGlyph, Rasterizer, Size,
     Slant, Style, Weight,
 };
-
-use crate::shader::Shader;
-
-#[derive(Clone, Copy)]
-struct Rgba([u8; 4]);
+use serde::Deserialize;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Font<'a>(&'a str);
@@ -26,6 +24,9 @@ impl<'a> std::fmt::Display for Font<'a> {
 }
 
 impl<'a> Font<'a> {
+    /// Font the editor starts with, unless the config names another
+    pub const DEFAULT: Font<'static> = Font("Liberation Mono");
+
     pub fn query() -> impl Iterator<Item = Font<'a>> {
         // TODO: get a different abstraction layer
         let ft_cfg = Config::get_current();
@@ -42,12 +43,102 @@ impl<'a> Font<'a> {
             })
             .map(Font)
     }
+
+    /// Font called `name`, ignoring case
+    pub fn find(name: &str) -> Option<Font<'a>> {
+        let name = name.to_lowercase();
+        Self::query().find(|font| font.0.to_lowercase() == name)
+    }
+}
+
+/// How glyphs are rasterised
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GlyphMode {
+    /// Coverage bitmaps, rasterised again for each scale
+    #[default]
+    Bitmap,
+    /// Signed distance fields, rasterised once at `SDF_SCALE` and sharp at
+    /// any scale
+    Sdf,
+}
+
+/// Scale distance fields are rasterised at
+const SDF_SCALE: u32 = (32. / GlyphAtlas::SCALE_STEP) as u32;
+/// How far from the edges of glyphs distance fields go, in pixels
+const SDF_SPREAD: usize = 6;
+
+/// How much memory the maps of all the scales can take before the least
+/// recently drawn ones get evicted, in bytes
+const CACHE_BUDGET: usize = 32 << 20;
+/// How long each frame can spend rasterising glyphs for the scale the
+/// animation is going to
+const PREPARE_TIME: Duration = Duration::from_millis(2);
+
+/// Side of the square pages glyphs are packed in, in pixels
+const PAGE_SIZE: usize = 1024;
+/// How many pages a scale can have before the least recently drawn one gets
+/// evicted
+const MAX_PAGES: usize = 4;
+
+/// How the pixels of a page are stored
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// Coverage or distance, in one byte
+    #[default]
+    Gray,
+    /// Coverage of each subpixel or colour, in four bytes
+    Rgba,
+}
+
+impl Format {
+    const ALL: [Self; 2] = [Self::Gray, Self::Rgba];
+
+    pub fn channels(self) -> usize {
+        match self {
+            Self::Gray => 1,
+            Self::Rgba => 4,
+        }
+    }
+
+    /// Internal format of the texture, and format of the pixels sent to it
+    fn gl_formats(self) -> (GLenum, GLenum) {
+        match self {
+            Self::Gray => (gl::R8, gl::RED),
+            Self::Rgba => (gl::RGBA8, gl::RGBA),
+        }
+    }
+}
+
+/// Part of a page sent to the texture array of its format
+#[derive(Debug, Clone, Copy)]
+pub struct Upload {
+    pub format: Format,
+    /// Page among those of `format`
+    pub layer: usize,
+    /// `[x0, y0, x1, y1]` of the pixels sent
+    pub rect: [usize; 4],
+}
+
+impl Upload {
+    pub fn bytes(&self) -> usize {
+        let [x0, y0, x1, y1] = self.rect;
+        (x1 - x0) * (y1 - y0) * self.format.channels()
+    }
 }
 
 /// Represents where a glyph is in memory
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Default)]
 struct AtlasIndex {
-    y_index: usize,
+    format: Format,
+    /// Page among those of `format`
+    page: usize,
+    /// Whether the glyph has colours of its own, like emoji, instead of
+    /// coverage to tint
+    colour: bool,
+    /// Top left of the glyph's pixels in its page
+    x: usize,
+    y: usize,
     top: f32,
     left: f32,
     width: f32,
@@ -56,12 +147,48 @@ struct AtlasIndex {
     ay: f32,
 }
 
+/// How the maps of the different scales were used, for debugging
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    /// Lookups of the map glyphs come from that found it already there
+    pub hits: u64,
+    /// Lookups that had to make the map first
+    pub misses: u64,
+    /// Maps evicted to stay under `CACHE_BUDGET`
+    pub evictions: u64,
+    /// Memory the maps take
+    pub bytes: usize,
+    /// Bytes uploaded to textures
+    pub uploaded: usize,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} hits, {} misses, {} evictions, {:.1} MiB, {:.1} MiB uploaded",
+            self.hits,
+            self.misses,
+            self.evictions,
+            self.bytes as f32 / (1 << 20) as f32,
+            self.uploaded as f32 / (1 << 20) as f32
+        )
+    }
+}
+
 pub struct GlyphAtlas {
     /// Contains the computed sizes
     sizes: RefCell<BTreeMap<u32, GlyphMap>>,
     rasteriser: RefCell<Rasterizer>,
     font_key: FontKey,
-    texture1: GLuint,
+    /// Identifies the next map made
+    next_id: Cell<u64>,
+    /// Counts frames, to know which glyphs were drawn recently
+    frame: u64,
+    mode: GlyphMode,
+    stats: Cell<CacheStats>,
+    /// Scale the animation is going to, while its map is being prepared
+    target_scale: Option<u32>,
     /// Determines the factor TODO: explain these better
     current_scale: u32,
     /// Determines how big the letters will be on screen
@@ -71,67 +198,74 @@ pub struct GlyphAtlas {
 impl GlyphAtlas {
     pub const SCALE_STEP: f32 = 1. / 32.;
     pub const MIN_SCALE: u32 = (4. / Self::SCALE_STEP) as u32;
-    fn get_current(&self) -> impl Deref<Target = GlyphMap> + '_ {
-        self.sizes
-            .borrow_mut()
-            .entry(self.current_scale)
-            .or_insert_with(|| {
-                let new_gmap = GlyphMap::new(
-                    &mut self.rasteriser.borrow_mut(),
-                    self.font_key,
-                    self.current_scale as f32 * Self::SCALE_STEP,
-                )
-                .unwrap(); // TODO: figure out how to handle errors
-                new_gmap
-            });
-
+    fn get_current(&self) -> Ref<'_, GlyphMap> {
+        let scale = self.current_map();
         Ref::map(self.sizes.borrow(), |sizes| {
-            sizes
-                .get(&self.current_scale)
-                .expect("Key should be present")
+            sizes.get(&scale).expect("Key should be present")
         })
     }
 
     fn get_current_mut(&self) -> impl DerefMut<Target = GlyphMap> + '_ {
-        self.sizes
-            .borrow_mut()
-            .entry(self.current_scale)
-            .or_insert_with(|| {
-                let new_gmap = GlyphMap::new(
-                    &mut self.rasteriser.borrow_mut(),
-                    self.font_key,
-                    self.current_scale as f32 * Self::SCALE_STEP,
-                )
-                .unwrap(); // TODO: figure out how to handle errors
-                new_gmap
-            });
-
+        let scale = self.current_map();
         RefMut::map(self.sizes.borrow_mut(), |sizes| {
-            sizes
-                .get_mut(&self.current_scale)
-                .expect("Key should be present")
+            sizes.get_mut(&scale).expect("Key should be present")
         })
     }
 
-    pub fn new(text_shader: &Shader<4>) -> Self {
-        let mut texture1 = 0;
-        unsafe {
-            text_shader.r#use();
-            gl::GenTextures(1, &mut texture1);
-            gl::BindTexture(gl::TEXTURE_2D, texture1);
+    /// Makes sure the map glyphs currently come from exists, and returns its
+    /// scale
+    fn current_map(&self) -> u32 {
+        let scale = self.map_scale();
+        let mut sizes = self.sizes.borrow_mut();
+        let mut stats = self.stats.get();
+        let map = match sizes.entry(scale) {
+            Entry::Occupied(entry) => {
+                stats.hits += 1;
+                entry.into_mut()
+            }
+            Entry::Vacant(entry) => {
+                stats.misses += 1;
+                entry.insert(self.new_map(scale))
+            }
+        };
+        self.stats.set(stats);
+        map.last_used = self.frame;
+        scale
+    }
 
-            // wrapping params
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
-            // filtering params
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+    fn new_map(&self, scale: u32) -> GlyphMap {
+        let id = self.next_id.replace(self.next_id.get() + 1);
+        GlyphMap::new(
+            &mut self.rasteriser.borrow_mut(),
+            self.font_key,
+            scale as f32 * Self::SCALE_STEP,
+            self.mode,
+            id,
+        )
+        .unwrap() // TODO: figure out how to handle errors
+    }
 
-            text_shader.uniform1i("texture1", 0);
+    /// Scale of the map glyphs currently come from
+    fn map_scale(&self) -> u32 {
+        if self.mode == GlyphMode::Sdf {
+            return SDF_SCALE;
+        }
+        let sizes = self.sizes.borrow();
+        if self.target_scale.is_none() || sizes.contains_key(&self.current_scale) {
+            return self.current_scale;
         }
+        // Rasterising every scale the animation goes through would stall it
+        sizes
+            .keys()
+            .copied()
+            .min_by_key(|scale| scale.abs_diff(self.current_scale))
+            .unwrap_or(self.current_scale)
+    }
+
+    pub fn new() -> Self {
         let mut rasteriser = Rasterizer::new(1.).expect("Could not set up rasterizer");
         let font_desc = FontDesc::new(
-            "Liberation Mono",
+            Font::DEFAULT.0,
             Style::Description {
                 slant: Slant::Normal,
                 weight: Weight::Normal,
@@ -144,7 +278,11 @@ impl GlyphAtlas {
             sizes: RefCell::new(BTreeMap::new()),
             rasteriser: RefCell::new(rasteriser),
             font_key,
-            texture1,
+            next_id: Cell::new(0),
+            frame: 0,
+            mode: GlyphMode::default(),
+            stats: Cell::default(),
+            target_scale: None,
             current_scale: Self::MIN_SCALE,
             letter_size: 2,
         }
@@ -166,29 +304,89 @@ impl GlyphAtlas {
             .expect("Font was found previously");
 
         self.font_key = font_key;
-        unsafe { GlyphMap::upload_texture(&self.get_current(), self.texture1) };
+    }
+
+    pub fn set_mode(&mut self, mode: GlyphMode) {
+        if mode != self.mode {
+            self.mode = mode;
+            self.sizes.replace(BTreeMap::new());
+        }
+    }
+
+    pub fn mode(&self) -> GlyphMode {
+        self.mode
     }
 
     pub fn select_scale(&mut self, scale: f32, letter_size: u32) -> f32 {
         self.letter_size = letter_size;
-        let scale_rounded = (letter_size as f32 * scale / Self::SCALE_STEP).round() as u32;
         let prev_scale = self.current_scale;
-        if prev_scale != scale_rounded {
-            self.current_scale = scale_rounded;
-            unsafe { GlyphMap::upload_texture(&self.get_current(), self.texture1) };
-        }
+        self.current_scale = Self::round_scale(scale, letter_size);
         prev_scale as f32 * Self::SCALE_STEP
     }
 
+    fn round_scale(scale: f32, letter_size: u32) -> u32 {
+        (letter_size as f32 * scale / Self::SCALE_STEP).round() as u32
+    }
+
+    /// Rasterises `chars` at `scale` for a little while, ahead of the scale
+    /// animation getting there. Until it does, scales without a map borrow
+    /// the closest one.
+    pub fn prepare_scale(&mut self, scale: f32, chars: impl Iterator<Item = char>) {
+        let scale = Self::round_scale(scale, self.letter_size);
+        if self.mode == GlyphMode::Sdf || scale == self.current_scale {
+            self.target_scale = None;
+            return;
+        }
+        self.target_scale = Some(scale);
+        let mut sizes = self.sizes.borrow_mut();
+        let map = sizes.entry(scale).or_insert_with(|| self.new_map(scale));
+        map.last_used = self.frame;
+        let start = Instant::now();
+        let chars = chars.take_while(|_| start.elapsed() < PREPARE_TIME);
+        map.add_characters(chars, &mut self.rasteriser.borrow_mut(), self.frame);
+    }
+
+    /// Starts drawing a new frame. Glyphs drawn in the current one are never
+    /// evicted, and neither are the maps drawn from in the last one.
+    pub fn start_frame(&mut self) {
+        self.frame += 1;
+        let sizes = self.sizes.get_mut();
+        let mut bytes: usize = sizes.values().map(GlyphMap::bytes).sum();
+        while bytes > CACHE_BUDGET {
+            let Some((scale, map_bytes)) = sizes
+                .iter()
+                .filter(|(_, map)| map.last_used + 1 < self.frame)
+                .min_by_key(|(_, map)| map.last_used)
+                .map(|(&scale, map)| (scale, map.bytes()))
+            else {
+                break;
+            };
+            sizes.remove(&scale);
+            bytes -= map_bytes;
+            let mut stats = self.stats.get();
+            stats.evictions += 1;
+            self.stats.set(stats);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            bytes: self.sizes.borrow().values().map(GlyphMap::bytes).sum(),
+            ..self.stats.get()
+        }
+    }
+
     pub fn add_characters<I: Iterator<Item = char>>(&mut self, chars: I) {
         let mut map = self.get_current_mut();
-        let old_height = GlyphMap::buffer_height(&map);
-        map.add_characters(chars, &mut self.rasteriser.borrow_mut());
-        let new_height = GlyphMap::buffer_height(&map);
-        if old_height != new_height {
-            unsafe {
-                GlyphMap::upload_texture(&map, self.texture1);
-            }
+        map.add_characters(chars, &mut self.rasteriser.borrow_mut(), self.frame);
+    }
+
+    /// Glyph bitmaps of the current scale, which the texture coordinates of
+    /// `get_glyph_data` point into
+    pub fn bitmap(&self) -> Bitmap<'_> {
+        Bitmap {
+            map: self.get_current(),
+            stats: &self.stats,
         }
     }
 
@@ -209,9 +407,18 @@ impl GlyphAtlas {
         (w * s, h * s)
     }
 
-    pub fn get_glyph_data(&mut self, c: char, x0: f32, y0: f32) -> ([[GLfloat; 4]; 4], f32, f32) {
-        self.get_current()
-            .get_glyph_data(c, x0, y0, self.letter_size as f32)
+    /// Vertices of `[x, y, s, t, page, format]` for `c` drawn at (`x0`, `y0`),
+    /// and how far it moves the next one. `format` is 0 for gray pages, 1
+    /// for RGBA ones and 2 for colour glyphs, which are in RGBA pages too.
+    pub fn get_glyph_data(&mut self, c: char, x0: f32, y0: f32) -> ([[GLfloat; 6]; 4], f32, f32) {
+        let mut map = self.get_current_mut();
+        let rasteriser = &mut self.rasteriser.borrow_mut();
+        // The glyph could have been evicted in an earlier frame
+        map.add_characters(iter::once(c), rasteriser, self.frame);
+        if !map.glyphs.contains_key(&c) {
+            map.add_characters(iter::once('?'), rasteriser, self.frame);
+        }
+        map.get_glyph_data(c, x0, y0, self.letter_size as f32)
     }
 
     pub fn ascender(&self) -> f32 {
@@ -223,14 +430,105 @@ impl GlyphAtlas {
     }
 }
 
+impl Default for GlyphAtlas {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Borrow of the glyph bitmaps of one scale
+pub struct Bitmap<'a> {
+    map: Ref<'a, GlyphMap>,
+    stats: &'a Cell<CacheStats>,
+}
+
+impl Bitmap<'_> {
+    /// Identifies the map, which no other scale or font shares
+    pub fn id(&self) -> u64 {
+        self.map.id
+    }
+
+    /// Width and height of the pages
+    pub fn page_size(&self) -> usize {
+        PAGE_SIZE
+    }
+
+    /// Value of the pixel at (`x`, `y`) of `page` among the pages of
+    /// `format`. Like in the text shaders, that's the red channel.
+    pub fn texel(&self, format: Format, page: usize, x: usize, y: usize) -> u8 {
+        self.channels(format, page, x, y)[0]
+    }
+
+    /// Colour of the pixel at (`x`, `y`) of an RGBA page, for colour
+    /// glyphs. They are stored premultiplied, which this undoes like the
+    /// text shaders.
+    pub fn colour(&self, page: usize, x: usize, y: usize) -> [f32; 4] {
+        let [r, g, b, a] = self.channels(Format::Rgba, page, x, y).map(f32::from);
+        if a == 0. {
+            return [0.; 4];
+        }
+        [r / a, g / a, b / a, a / 255.]
+    }
+
+    fn channels(&self, format: Format, page: usize, x: usize, y: usize) -> [u8; 4] {
+        let pages = &self.map.pages[format as usize];
+        let pixels = pages.get(page).map_or(&[][..], |page| &page.pixels);
+        let offset = (y * PAGE_SIZE + x) * format.channels();
+        let pixel = pixels.iter().skip(offset).take(format.channels());
+        let mut channels = [0; 4];
+        for (channel, value) in channels.iter_mut().zip(pixel) {
+            *channel = *value;
+        }
+        channels
+    }
+
+    /// How much a screen pixel sampling `texel` is covered, `texels` being
+    /// how many texels wide the pixel is. Mirrors the text shaders.
+    pub fn coverage(&self, texel: u8, texels: f32) -> f32 {
+        let value = f32::from(texel) / 255.;
+        if !self.map.distance_field {
+            return value;
+        }
+        // The edge is at 0.5, the field going from 0 to 1 over twice the
+        // spread
+        let width = 0.5 * texels / SDF_SPREAD as f32;
+        let x = ((value - 0.5) / width + 0.5).clamp(0., 1.);
+        x * x * (3. - 2. * x)
+    }
+
+    /// Uploads the pages to texture arrays, one for each format, with
+    /// `layers` layers allocated. Only what changed since the last upload
+    /// goes, unless `everything`.
+    /// ### Safety
+    /// Needs a current GL context, which `textures` were made in
+    pub unsafe fn upload(&self, textures: [GLuint; 2], layers: &mut [usize; 2], everything: bool) {
+        let uploaded = self.map.upload(textures, layers, everything);
+        let mut stats = self.stats.get();
+        stats.uploaded += uploaded;
+        self.stats.set(stats);
+    }
+
+    /// Parts of the pages `upload` would send, taken off what changed like
+    /// it does. Measures uploads without a GL context.
+    #[allow(dead_code)] // Only the benchmarks measure them
+    pub fn take_uploads(&self, layers: &mut [usize; 2], everything: bool) -> Vec<Upload> {
+        let (_, uploads) = self.map.uploads(layers, everything);
+        uploads
+    }
+}
+
 struct GlyphMap {
-    /// Stores the glyphs
-    pixel_buffer: Vec<Rgba>,
-    buffer_width: usize,
+    id: u64,
+    /// Stores the glyphs, for each format
+    pages: [Vec<Page>; 2],
     glyphs: HashMap<char, AtlasIndex>,
+    /// Chars the font can't rasterise, only reported once
+    missing: HashSet<char>,
+    /// Whether the pages have distance fields instead of coverage
+    distance_field: bool,
     font_key: FontKey,
-    /// Position of the "unknown character" glyph
-    unknown_position: AtlasIndex,
+    /// Frame the map was last drawn from in
+    last_used: u64,
     camera_scale: f32,
     line_height: f32,
     ascender: f32,
@@ -238,102 +536,274 @@ struct GlyphMap {
 }
 
 impl GlyphMap {
-    /// How large the internal vector's rows should be, compared to the first
-    /// character generated. self is mostly to avoid having to write resizing
-    /// code. And yes, 10 is absolutely overkill.
-    const MAX_WIDTH_RATIO: usize = 3;
     pub fn new(
         rasteriser: &mut Rasterizer,
         font_key: FontKey,
         camera_scale: f32,
+        mode: GlyphMode,
+        id: u64,
     ) -> Result<Self, Error> {
         rasteriser.update_dpr(Size::factor() * camera_scale);
         let glyph = get_glyph(rasteriser, font_key, '?')?;
-        let buffer_width = glyph.width as usize * Self::MAX_WIDTH_RATIO;
-
-        let mut pixel_buffer = Vec::new();
-        let scale = 1. / camera_scale;
-        let unknown_position = push_pixels(glyph, &mut pixel_buffer, buffer_width, scale);
         let metrics = rasteriser.metrics(font_key, Size::new(1.))?;
 
+        let scale = 1. / camera_scale;
         let line_height = metrics.line_height as f32 * scale;
         let descender = metrics.descent * scale;
         let mut res = Self {
+            id,
             camera_scale: scale,
-            pixel_buffer,
-            buffer_width,
+            pages: Default::default(),
             glyphs: HashMap::new(),
+            missing: HashSet::new(),
+            distance_field: mode == GlyphMode::Sdf,
             font_key,
+            last_used: 0,
             line_height,
             descender,
             ascender: descender + line_height,
-            unknown_position,
         };
+        // The "unknown character" glyph
+        res.insert('?', glyph, 0);
 
         let printable_ascii = (32..127_u8).map(|b| b as char);
-        res.add_characters(printable_ascii, rasteriser);
+        res.add_characters(printable_ascii, rasteriser, 0);
 
         Ok(res)
     }
 
-    fn buffer_height(&self) -> usize {
-        self.pixel_buffer.len() / self.buffer_width
-    }
-
-    pub fn add_characters<I: Iterator<Item = char>>(&mut self, chars: I, rast: &mut Rasterizer) {
+    /// Rasterises the chars that aren't in the map yet. Returns whether any
+    /// was added.
+    pub fn add_characters<I: Iterator<Item = char>>(
+        &mut self,
+        chars: I,
+        rast: &mut Rasterizer,
+        frame: u64,
+    ) -> bool {
+        let mut added = false;
         for c in chars {
-            if self.glyphs.contains_key(&c) {
+            if let Some(glyph) = self.glyphs.get(&c) {
+                self.pages[glyph.format as usize][glyph.page].last_used = frame;
+                continue;
+            }
+            if self.missing.contains(&c) {
                 continue;
             }
 
+            // Other scales may have been rasterised since
+            rast.update_dpr(Size::factor() / self.camera_scale);
             let glyph = match get_glyph(rast, self.font_key, c) {
                 Err(e) => {
                     eprintln!("Couldn't rasterise character {c}: {e}");
-                    return;
+                    self.missing.insert(c);
+                    continue;
                 }
                 Ok(g) => g,
             };
-
-            let glyph_info = push_pixels(
-                glyph,
-                &mut self.pixel_buffer,
-                self.buffer_width,
-                self.camera_scale,
-            );
-            self.glyphs.insert(c, glyph_info);
+            added |= self.insert(c, glyph, frame);
         }
+        added
     }
-    pub unsafe fn upload_texture(&self, texture1: GLuint) {
-        gl::ActiveTexture(gl::TEXTURE0);
-        gl::BindTexture(gl::TEXTURE_2D, texture1);
 
-        let flattened = self
-            .pixel_buffer
+    /// Memory the pages take
+    fn bytes(&self) -> usize {
+        self.pages
             .iter()
-            .flat_map(|rgba| rgba.0.iter().copied());
-
-        let fl = flattened.count();
-
-        assert!(fl == 4 * self.pixel_buffer.len());
-
-        // TODO: make sure buffer height doesn't go above gl::MAX_TEX_LAYERs or somn
-        gl::TexImage2D(
-            gl::TEXTURE_2D,
-            0,
-            gl::RED as i32,
-            self.buffer_width as i32,
-            self.buffer_height() as i32,
-            0,
-            gl::RGBA,
-            gl::UNSIGNED_BYTE,
-            self.pixel_buffer.as_ptr().cast(),
+            .flatten()
+            .map(|page| page.pixels.len())
+            .sum()
+    }
+
+    /// Packs `glyph` in a page, evicting one if they are all full
+    fn insert(&mut self, c: char, glyph: RasterizedGlyph, frame: u64) -> bool {
+        let (mut width, mut height) = (glyph.width as usize, glyph.height as usize);
+        let colour = matches!(glyph.buffer, BitmapBuffer::Rgba(_));
+        let (mut format, mut pixels) = pixels(glyph.buffer);
+        // Distance fields go on around the glyph. Colour glyphs have no
+        // edge to take the distance to, so they stay bitmaps.
+        let mut padding = 0;
+        if self.distance_field && !colour {
+            pixels = distance_field(&pixels, format, width, height);
+            format = Format::Gray;
+            padding = SDF_SPREAD;
+            width += 2 * SDF_SPREAD;
+            height += 2 * SDF_SPREAD;
+        }
+        // A pixel of padding keeps linear filtering from bleeding in the
+        // glyphs around
+        let Some((page, x, y)) = self.place(format, width + 1, height + 1, frame) else {
+            eprintln!("Character {c} doesn't fit in a page of the atlas");
+            self.missing.insert(c);
+            return false;
+        };
+        self.pages[format as usize][page].write(x, y, width, &pixels);
+        let scale = self.camera_scale;
+        let padding = padding as f32 * scale;
+        let (ax, ay) = glyph.advance;
+        self.glyphs.insert(
+            c,
+            AtlasIndex {
+                format,
+                page,
+                colour,
+                x,
+                y,
+                top: glyph.top as f32 * scale + padding,
+                left: glyph.left as f32 * scale - padding,
+                width: width as f32 * scale,
+                height: height as f32 * scale,
+                ax: ax as f32 * scale,
+                ay: ay as f32 * scale,
+            },
         );
+        true
+    }
+
+    /// Finds room for a `width` by `height` rectangle in a page of `format`,
+    /// as the page and top left corner. Pages are added up to `MAX_PAGES`,
+    /// then the one drawn from the longest ago is cleared, unless all of them
+    /// were drawn from in `frame`.
+    fn place(
+        &mut self,
+        format: Format,
+        width: usize,
+        height: usize,
+        frame: u64,
+    ) -> Option<(usize, usize, usize)> {
+        if width > PAGE_SIZE || height > PAGE_SIZE {
+            return None;
+        }
+        let pages = &mut self.pages[format as usize];
+        for (idx, page) in pages.iter_mut().enumerate() {
+            if let Some((x, y)) = page.skyline.place(width, height) {
+                page.last_used = frame;
+                return Some((idx, x, y));
+            }
+        }
+        let least_recent = pages
+            .iter()
+            .enumerate()
+            .filter(|(_, page)| page.last_used < frame)
+            .min_by_key(|(_, page)| page.last_used)
+            .map(|(idx, _)| idx);
+        let idx = match least_recent {
+            Some(idx) if pages.len() >= MAX_PAGES => {
+                pages[idx] = Page::new(format, frame);
+                self.glyphs
+                    .retain(|_, glyph| glyph.format != format || glyph.page != idx);
+                idx
+            }
+            _ => {
+                pages.push(Page::new(format, frame));
+                pages.len() - 1
+            }
+        };
+        let (x, y) = self.pages[format as usize][idx]
+            .skyline
+            .place(width, height)?;
+        Some((idx, x, y))
+    }
+
+    /// Parts of the pages that changed since the last call, or all of them
+    /// if `everything`. Formats with more pages than `layers` need their
+    /// texture array allocated again, which sends them whole, and are
+    /// returned first.
+    fn uploads(&self, layers: &mut [usize; 2], everything: bool) -> (Vec<Format>, Vec<Upload>) {
+        let mut allocated = Vec::new();
+        let mut uploads = Vec::new();
+        for format in Format::ALL {
+            let pages = &self.pages[format as usize];
+            let layers = &mut layers[format as usize];
+            let allocate = pages.len() > *layers;
+            if allocate {
+                *layers = pages.len().max(MAX_PAGES);
+                allocated.push(format);
+            }
+            for (layer, page) in pages.iter().enumerate() {
+                let dirty = page.dirty.take();
+                // Rows no glyph reached yet are left out
+                let rect = if everything || allocate {
+                    [0, 0, PAGE_SIZE, page.height()]
+                } else if let Some(dirty) = dirty {
+                    dirty
+                } else {
+                    continue;
+                };
+                let [x0, y0, x1, y1] = rect;
+                if x0 < x1 && y0 < y1 {
+                    uploads.push(Upload {
+                        format,
+                        layer,
+                        rect,
+                    });
+                }
+            }
+        }
+        (allocated, uploads)
+    }
+
+    /// Uploads what changed of the pages, or `everything`. Texture arrays
+    /// with too few `layers` for the pages are allocated again. Returns how
+    /// many bytes went.
+    unsafe fn upload(
+        &self,
+        textures: [GLuint; 2],
+        layers: &mut [usize; 2],
+        everything: bool,
+    ) -> usize {
+        let (allocated, uploads) = self.uploads(layers, everything);
+        for format in allocated {
+            let (internal_format, pixel_format) = format.gl_formats();
+            gl::BindTexture(gl::TEXTURE_2D_ARRAY, textures[format as usize]);
+            gl::TexImage3D(
+                gl::TEXTURE_2D_ARRAY,
+                0,
+                internal_format as i32,
+                PAGE_SIZE as i32,
+                PAGE_SIZE as i32,
+                layers[format as usize] as i32,
+                0,
+                pixel_format,
+                gl::UNSIGNED_BYTE,
+                ptr::null(),
+            );
+        }
+        // The rows of gray pages are only aligned to a byte
+        gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+        gl::PixelStorei(gl::UNPACK_ROW_LENGTH, PAGE_SIZE as i32);
+        for upload in &uploads {
+            let Upload {
+                format,
+                layer,
+                rect: [x0, y0, x1, y1],
+            } = *upload;
+            let (_, pixel_format) = format.gl_formats();
+            let page = &self.pages[format as usize][layer];
+            let start = (y0 * PAGE_SIZE + x0) * format.channels();
+            gl::BindTexture(gl::TEXTURE_2D_ARRAY, textures[format as usize]);
+            gl::TexSubImage3D(
+                gl::TEXTURE_2D_ARRAY,
+                0,
+                x0 as i32,
+                y0 as i32,
+                layer as i32,
+                (x1 - x0) as i32,
+                (y1 - y0) as i32,
+                1,
+                pixel_format,
+                gl::UNSIGNED_BYTE,
+                page.pixels[start..].as_ptr().cast(),
+            );
+        }
+        gl::PixelStorei(gl::UNPACK_ROW_LENGTH, 0);
+        gl::PixelStorei(gl::UNPACK_ALIGNMENT, 4);
+        uploads.iter().map(Upload::bytes).sum()
     }
 
     pub fn measure_dims<I: Iterator<Item = char>>(&self, chars: I) -> (f32, f32) {
         let (w, h) = chars
             .take_while(|c| *c != '\n')
-            .map(|c| self.glyphs.get(&c).unwrap_or(&self.unknown_position))
+            .filter_map(|c| self.glyphs.get(&c).or_else(|| self.glyphs.get(&'?')))
             .fold((0.0, 0.0), |(x, y), g| (x + g.ax, y + g.ay));
 
         (w, h + self.line_height)
@@ -345,8 +815,13 @@ impl GlyphMap {
         x0: f32,
         y0: f32,
         letter_scale: f32,
-    ) -> ([[GLfloat; 4]; 4], f32, f32) {
-        let pos = self.glyphs.get(&c).unwrap_or(&self.unknown_position);
+    ) -> ([[GLfloat; 6]; 4], f32, f32) {
+        let pos = self
+            .glyphs
+            .get(&c)
+            .or_else(|| self.glyphs.get(&'?'))
+            .copied()
+            .unwrap_or_default();
 
         let top = pos.top;
         let left = pos.left;
@@ -359,26 +834,177 @@ impl GlyphMap {
         let y1 = y0 - top * letter_scale;
         let y2 = y1 + height * letter_scale;
 
-        let num_lines = self.buffer_height() as f32;
-        let t1 = pos.y_index as f32 / num_lines;
+        let page_size = PAGE_SIZE as f32;
+        let t1 = pos.y as f32 / page_size;
         // TODO: find a less awkward way to do this
-        let t2 = t1 + (height / self.camera_scale) / num_lines;
+        let t2 = t1 + (height / self.camera_scale) / page_size;
 
-        let s1 = 0.;
-        let s2 = width / self.camera_scale / self.buffer_width as f32;
+        let s1 = pos.x as f32 / page_size;
+        let s2 = s1 + width / self.camera_scale / page_size;
 
+        let page = pos.page as f32;
+        // Colour glyphs are in RGBA pages, but are marked apart from
+        // subpixel coverage
+        let format = if pos.colour {
+            2.
+        } else {
+            pos.format as u8 as f32
+        };
         let verts = [
             //positions      // texture coordinates
-            [x2, y1, s2, t1], // top right
-            [x2, y2, s2, t2], // bottom right
-            [x1, y2, s1, t2], // bottom left
-            [x1, y1, s1, t1], // top left
+            [x2, y1, s2, t1, page, format], // top right
+            [x2, y2, s2, t2, page, format], // bottom right
+            [x1, y2, s1, t2, page, format], // bottom left
+            [x1, y1, s1, t1, page, format], // top left
         ];
 
         (verts, pos.ax * letter_scale, pos.ay * letter_scale)
     }
 }
 
+/// Glyphs packed in one layer of the atlas texture. Rows are only allocated
+/// once glyphs reach them.
+struct Page {
+    format: Format,
+    pixels: Vec<u8>,
+    skyline: Skyline,
+    /// Frame the page was last drawn from in
+    last_used: u64,
+    /// `[x0, y0, x1, y1]` around what was written since the last upload
+    dirty: Cell<Option<[usize; 4]>>,
+}
+
+impl Page {
+    fn new(format: Format, frame: u64) -> Self {
+        Self {
+            format,
+            pixels: Vec::new(),
+            skyline: Skyline::new(),
+            last_used: frame,
+            dirty: Cell::new(None),
+        }
+    }
+
+    fn height(&self) -> usize {
+        self.pixels.len() / (PAGE_SIZE * self.format.channels())
+    }
+
+    /// Copies the rows of `pixels`, `width` wide, at (`x`, `y`), and clears
+    /// the pixel of padding `place` left right of and below them. The
+    /// padding is uploaded too, as the texture may still hold an evicted
+    /// page there.
+    fn write(&mut self, x: usize, y: usize, width: usize, pixels: &[u8]) {
+        if width == 0 {
+            return;
+        }
+        let channels = self.format.channels();
+        let row_len = width * channels;
+        let rows = pixels.len() / row_len;
+        let (padded_width, padded_rows) = (width + 1, rows + 1);
+        if self.height() < y + padded_rows {
+            self.pixels
+                .resize((y + padded_rows) * PAGE_SIZE * channels, 0);
+        }
+        let mut src = pixels.chunks_exact(row_len);
+        for row in y..y + padded_rows {
+            let start = (row * PAGE_SIZE + x) * channels;
+            let dst = &mut self.pixels[start..start + padded_width * channels];
+            let padding = match src.next() {
+                Some(src) => {
+                    dst[..row_len].copy_from_slice(src);
+                    &mut dst[row_len..]
+                }
+                None => dst,
+            };
+            padding.fill(0);
+        }
+        let [x0, y0, x1, y1] = self.dirty.get().unwrap_or([x, y, x, y]);
+        let dirty = [
+            x0.min(x),
+            y0.min(y),
+            x1.max(x + padded_width),
+            y1.max(y + padded_rows),
+        ];
+        self.dirty.set(Some(dirty));
+    }
+}
+
+/// Bottom-left skyline packer. The top of the packed area is kept as
+/// segments from left to right, and rectangles go where they end up the
+/// lowest.
+struct Skyline {
+    segments: Vec<Segment>,
+}
+
+#[derive(Clone, Copy)]
+struct Segment {
+    x: usize,
+    y: usize,
+    width: usize,
+}
+
+impl Skyline {
+    fn new() -> Self {
+        Self {
+            segments: vec![Segment {
+                x: 0,
+                y: 0,
+                width: PAGE_SIZE,
+            }],
+        }
+    }
+
+    /// Finds room for a rectangle and marks it as used. Returns its top left
+    /// corner.
+    fn place(&mut self, width: usize, height: usize) -> Option<(usize, usize)> {
+        let (idx, y) = (0..self.segments.len())
+            .filter_map(|idx| Some((idx, self.fit(idx, width, height)?)))
+            .min_by_key(|&(_, y)| y)?;
+        let x = self.segments[idx].x;
+        let end = x + width;
+        let top = Segment {
+            x,
+            y: y + height,
+            width,
+        };
+        self.segments.insert(idx, top);
+        // The rectangle covers the segments under it
+        while idx + 1 < self.segments.len() && self.segments[idx + 1].x < end {
+            let next = &mut self.segments[idx + 1];
+            let next_end = next.x + next.width;
+            if next_end <= end {
+                self.segments.remove(idx + 1);
+            } else {
+                next.x = end;
+                next.width = next_end - end;
+            }
+        }
+        self.segments.dedup_by(|next, prev| {
+            let same_height = next.y == prev.y;
+            if same_height {
+                prev.width += next.width;
+            }
+            same_height
+        });
+        Some((x, y))
+    }
+
+    /// Where a rectangle starting at segment `idx` would go, resting on the
+    /// highest segment under it, if it fits
+    fn fit(&self, idx: usize, width: usize, height: usize) -> Option<usize> {
+        let x = self.segments[idx].x;
+        if x + width > PAGE_SIZE {
+            return None;
+        }
+        let y = self.segments[idx..]
+            .iter()
+            .take_while(|segment| segment.x < x + width)
+            .map(|segment| segment.y)
+            .max()?;
+        (y + height <= PAGE_SIZE).then_some(y)
+    }
+}
+
 fn get_glyph(
     rasteriser: &mut Rasterizer,
     font_key: FontKey,
@@ -392,60 +1018,362 @@ fn get_glyph(
     rasteriser.get_glyph(glyph_key)
 }
 
-fn expand_width(bitmap: Vec<Rgba>, src_width: usize, dest_width: usize) -> Vec<Rgba> {
-    debug_assert!(src_width <= dest_width);
-    if src_width == 0 {
-        return vec![Rgba([0x00; 4]); dest_width];
+/// Signed distance field of the coverage in the red channel of `pixels`,
+/// `SDF_SPREAD` pixels bigger on each side. The edges are at 0x80, inside
+/// being above.
+fn distance_field(pixels: &[u8], format: Format, width: usize, height: usize) -> Vec<u8> {
+    let (field_width, field_height) = (width + 2 * SDF_SPREAD, height + 2 * SDF_SPREAD);
+    let inside: Vec<bool> = (0..field_width * field_height)
+        .map(|idx| {
+            let (x, y) = (idx % field_width, idx / field_width);
+            let (Some(x), Some(y)) = (x.checked_sub(SDF_SPREAD), y.checked_sub(SDF_SPREAD)) else {
+                return false;
+            };
+            x < width && y < height && pixels[(y * width + x) * format.channels()] >= 0x80
+        })
+        .collect();
+    let to_inside = squared_distances(&inside, true, field_width);
+    let to_outside = squared_distances(&inside, false, field_width);
+    inside
+        .iter()
+        .zip(to_inside.iter().zip(&to_outside))
+        .map(|(&inside, (to_inside, to_outside))| {
+            // Distances go between pixel centres, the edge being half way
+            let distance = if inside {
+                to_outside.sqrt() - 0.5
+            } else {
+                0.5 - to_inside.sqrt()
+            };
+            let value = 0.5 + distance / (2 * SDF_SPREAD) as f32;
+            (value.clamp(0., 1.) * 255.).round() as u8
+        })
+        .collect()
+}
+
+/// Squared distance from each cell of the grid to the closest one that is
+/// `target`, by Felzenszwalb and Huttenlocher's transform
+fn squared_distances(cells: &[bool], target: bool, width: usize) -> Vec<f32> {
+    // Far enough for any grid, without overflowing to infinity
+    const FAR: f32 = 1e20;
+    let height = cells.len() / width;
+    let mut res: Vec<f32> = cells
+        .iter()
+        .map(|&cell| if cell == target { 0. } else { FAR })
+        .collect();
+    for x in 0..width {
+        let column: Vec<f32> = (0..height).map(|y| res[y * width + x]).collect();
+        for (y, distance) in squared_distances_1d(&column).into_iter().enumerate() {
+            res[y * width + x] = distance;
+        }
     }
-    bitmap
-        .chunks_exact(src_width)
-        .enumerate()
-        .flat_map(|(_, orig_row)| {
-            orig_row
-                .iter()
-                .copied()
-                .chain(repeat(Rgba([0x00; 4])))
-                .take(dest_width)
+    for row in res.chunks_exact_mut(width) {
+        let distances = squared_distances_1d(row);
+        row.copy_from_slice(&distances);
+    }
+    res
+}
+
+/// Lower envelope of the parabolas rooted at each cell, at the height `f`
+/// has there
+fn squared_distances_1d(f: &[f32]) -> Vec<f32> {
+    let parabola = |q: usize| f[q] + (q * q) as f32;
+    // Cells of the parabolas in the envelope, and where they start
+    let mut roots = vec![0];
+    let mut starts = vec![f32::NEG_INFINITY];
+    for q in 1..f.len() {
+        loop {
+            let p = *roots.last().expect("The first parabola is never removed");
+            let start = (parabola(q) - parabola(p)) / (2 * (q - p)) as f32;
+            if start <= *starts.last().expect("Same length as roots") {
+                roots.pop();
+                starts.pop();
+            } else {
+                roots.push(q);
+                starts.push(start);
+                break;
+            }
+        }
+    }
+    let mut parabola = 0;
+    (0..f.len())
+        .map(|q| {
+            while starts
+                .get(parabola + 1)
+                .is_some_and(|&start| start < q as f32)
+            {
+                parabola += 1;
+            }
+            let p = roots[parabola];
+            (q as f32 - p as f32).powi(2) + f[p]
         })
         .collect()
 }
 
-fn push_pixels(
-    glyph: RasterizedGlyph,
-    pixel_buffer: &mut Vec<Rgba>,
-    buffer_width: usize,
-    scale: f32,
-) -> AtlasIndex {
-    // Transform into rgba (there is a bug with opengl that treats all
-    // input textures as rgba)
-    let pixels = match glyph.buffer {
-        BitmapBuffer::Rgb(v) => v
-            .chunks_exact(3)
-            .map(|chunk| {
-                let mut res = [0xff; 4];
-                for (res_el, &chunk_el) in res.iter_mut().zip(chunk) {
-                    *res_el = chunk_el;
+/// Pixels of a rasterised glyph, and how they should be stored. Grayscale
+/// antialiasing comes as RGB with the same value in each channel.
+fn pixels(buffer: BitmapBuffer) -> (Format, Vec<u8>) {
+    match buffer {
+        BitmapBuffer::Rgb(v) if v.chunks_exact(3).all(|p| p[0] == p[1] && p[1] == p[2]) => {
+            (Format::Gray, v.into_iter().step_by(3).collect())
+        }
+        BitmapBuffer::Rgb(v) => {
+            let rgba = v.chunks_exact(3).flat_map(|p| [p[0], p[1], p[2], 0xff]);
+            (Format::Rgba, rgba.collect())
+        }
+        BitmapBuffer::Rgba(v) => (Format::Rgba, v),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Map without glyphs, for glyphs made up by the tests
+    fn empty_map(distance_field: bool) -> GlyphMap {
+        GlyphMap {
+            id: 0,
+            pages: Default::default(),
+            glyphs: HashMap::new(),
+            missing: HashSet::new(),
+            distance_field,
+            font_key: FontKey::next(),
+            last_used: 0,
+            camera_scale: 1.,
+            line_height: 1.,
+            ascender: 1.,
+            descender: 0.,
+        }
+    }
+
+    /// Gray glyph of `width` by `height` pixels
+    fn glyph(character: char, width: usize, height: usize) -> RasterizedGlyph {
+        RasterizedGlyph {
+            character,
+            width: width as i32,
+            height: height as i32,
+            top: height as i32,
+            left: 0,
+            advance: (width as i32, 0),
+            buffer: BitmapBuffer::Rgb(vec![0xff; width * height * 3]),
+        }
+    }
+
+    #[test]
+    fn skyline_packs_lowest_first() {
+        let mut skyline = Skyline::new();
+        assert_eq!(skyline.place(100, 10), Some((0, 0)));
+        assert_eq!(skyline.place(100, 20), Some((100, 0)));
+        assert_eq!(skyline.place(PAGE_SIZE - 200, 5), Some((200, 0)));
+        // Resting on the highest segment under it, as low as it can
+        assert_eq!(skyline.place(150, 1), Some((200, 5)));
+        // Neighbours at the same height become one segment
+        assert_eq!(skyline.place(PAGE_SIZE - 350, 1), Some((350, 5)));
+        assert_eq!(skyline.segments.len(), 3);
+        assert_eq!(skyline.place(PAGE_SIZE + 1, 1), None);
+        assert_eq!(skyline.place(1, PAGE_SIZE), None);
+
+        assert_eq!(skyline.place(PAGE_SIZE, PAGE_SIZE - 20), Some((0, 20)));
+        assert_eq!(skyline.segments.len(), 1);
+        assert_eq!(skyline.place(1, 1), None);
+    }
+
+    #[test]
+    fn pages_are_added_then_evicted() {
+        let mut map = empty_map(false);
+        let big = PAGE_SIZE - 1;
+        for (c, page) in ['a', 'b', 'c', 'd'].into_iter().zip(0..) {
+            assert!(map.insert(c, glyph(c, big, big), 1));
+            assert_eq!(map.glyphs[&c].page, page);
+        }
+        // Every page was drawn from in this frame, so one more is added
+        assert!(map.insert('e', glyph('e', big, big), 1));
+        assert_eq!(map.glyphs[&'e'].page, 4);
+        assert_eq!(map.pages[Format::Gray as usize].len(), 5);
+
+        // The page drawn from the longest ago makes room
+        for page in &mut map.pages[Format::Gray as usize][1..] {
+            page.last_used = 2;
+        }
+        assert!(map.insert('f', glyph('f', big, big), 3));
+        assert_eq!(map.glyphs[&'f'].page, 0);
+        assert!(!map.glyphs.contains_key(&'a'));
+        assert_eq!(map.pages[Format::Gray as usize].len(), 5);
+        assert!(map.pages[Format::Rgba as usize].is_empty());
+
+        assert!(!map.insert('g', glyph('g', PAGE_SIZE, 1), 3));
+        assert!(map.missing.contains(&'g'));
+    }
+
+    #[test]
+    fn zero_width_glyphs() {
+        for distance_field in [false, true] {
+            let mut map = empty_map(distance_field);
+            assert!(map.insert(' ', glyph(' ', 0, 0), 0));
+            assert!(map.insert('\u{200b}', glyph('\u{200b}', 0, 12), 0));
+            assert!(map.insert('x', glyph('x', 2, 2), 0));
+            let x = map.glyphs[&'x'];
+            assert_eq!(map.pages[x.format as usize].len(), 1);
+            let (_, advance, _) = map.get_glyph_data(' ', 0., 0., 1.);
+            assert_eq!(advance, 0.);
+        }
+    }
+
+    #[test]
+    fn uploads_send_what_changed() {
+        let mut map = empty_map(false);
+        let mut layers = [0; 2];
+        let uploads = |map: &GlyphMap, layers: &mut [usize; 2], everything| {
+            let (allocated, uploads) = map.uploads(layers, everything);
+            let uploads: Vec<_> = uploads
+                .iter()
+                .map(|upload| (upload.format, upload.layer, upload.rect))
+                .collect();
+            (allocated, uploads)
+        };
+        assert_eq!(uploads(&map, &mut layers, false), (vec![], vec![]));
+
+        // A new page allocates the texture array, sending the rows in use
+        map.insert('a', glyph('a', 10, 20), 0);
+        assert_eq!(
+            uploads(&map, &mut layers, false),
+            (
+                vec![Format::Gray],
+                vec![(Format::Gray, 0, [0, 0, PAGE_SIZE, 21])]
+            )
+        );
+        assert_eq!(layers, [MAX_PAGES, 0]);
+
+        // Then only what was written, with its padding
+        map.insert('b', glyph('b', 5, 5), 0);
+        map.insert('c', glyph('c', 3, 2), 0);
+        assert_eq!(
+            uploads(&map, &mut layers, false),
+            (vec![], vec![(Format::Gray, 0, [11, 0, 21, 6])])
+        );
+        assert_eq!(uploads(&map, &mut layers, false), (vec![], vec![]));
+        assert_eq!(
+            uploads(&map, &mut layers, true),
+            (vec![], vec![(Format::Gray, 0, [0, 0, PAGE_SIZE, 21])])
+        );
+
+        let colour = RasterizedGlyph {
+            buffer: BitmapBuffer::Rgba(vec![0xff; 3 * 3 * 4]),
+            ..glyph('d', 3, 3)
+        };
+        map.insert('d', colour, 0);
+        let (allocated, sent) = uploads(&map, &mut layers, false);
+        assert_eq!(allocated, [Format::Rgba]);
+        assert_eq!(sent, [(Format::Rgba, 0, [0, 0, PAGE_SIZE, 4])]);
+        assert_eq!(layers, [MAX_PAGES, MAX_PAGES]);
+
+        // More pages than layers send every page again, but not the rows
+        // no glyph reached
+        for _ in 0..MAX_PAGES {
+            map.pages[Format::Gray as usize].push(Page::new(Format::Gray, 0));
+        }
+        let (allocated, sent) = uploads(&map, &mut layers, false);
+        assert_eq!(allocated, [Format::Gray]);
+        assert_eq!(sent, [(Format::Gray, 0, [0, 0, PAGE_SIZE, 21])]);
+        assert_eq!(layers, [MAX_PAGES + 1, MAX_PAGES]);
+        let upload = map.uploads(&mut layers, true).1[1];
+        assert_eq!(upload.bytes(), PAGE_SIZE * 4 * 4);
+    }
+
+    #[test]
+    fn squared_distances_match_brute_force() {
+        const FAR: f32 = 1e20;
+        let brute_force = |f: &[f32]| -> Vec<f32> {
+            (0..f.len())
+                .map(|q| {
+                    (0..f.len())
+                        .map(|p| (q as f32 - p as f32).powi(2) + f[p])
+                        .fold(f32::INFINITY, f32::min)
+                })
+                .collect()
+        };
+        let cases: [&[f32]; 6] = [
+            &[0.],
+            &[FAR, FAR, 0.],
+            &[0., FAR, FAR, FAR, 0.],
+            &[FAR, 0., FAR, FAR, FAR, FAR, FAR, 0., 0., FAR],
+            &[4., 1., 9., 0., 16., 2.],
+            &[FAR; 3],
+        ];
+        for f in cases {
+            assert_eq!(squared_distances_1d(f), brute_force(f), "{f:?}");
+        }
+
+        // In two dimensions, to the closest cell that is the target
+        let width = 7;
+        let cells: Vec<bool> = (0..width * 5).map(|idx| idx % 11 == 3).collect();
+        for target in [true, false] {
+            let distances = squared_distances(&cells, target, width);
+            for (idx, &distance) in distances.iter().enumerate() {
+                let closest = (0..cells.len())
+                    .filter(|&other| cells[other] == target)
+                    .map(|other| {
+                        let dx = (idx % width).abs_diff(other % width);
+                        let dy = (idx / width).abs_diff(other / width);
+                        (dx * dx + dy * dy) as f32
+                    })
+                    .fold(f32::INFINITY, f32::min);
+                assert_eq!(distance, closest);
+            }
+        }
+    }
+
+    #[test]
+    fn distance_fields() {
+        // A 6 by 6 square of coverage, one pixel in from the edges
+        let (width, height) = (8, 8);
+        let pixels: Vec<u8> = (0..width * height)
+            .map(|idx| {
+                let (x, y) = (idx % width, idx / width);
+                if (1..7).contains(&x) && (1..7).contains(&y) {
+                    0xff
+                } else {
+                    0
                 }
-                Rgba(res)
             })
-            .collect(),
-        BitmapBuffer::Rgba(v) => v
-            .chunks_exact(4)
-            .map(|slice| Rgba(slice.try_into().expect("We used a chunk size of 4")))
-            .collect(),
-    };
-    let new_pixels: Vec<_> = expand_width(pixels, glyph.width as usize, buffer_width);
-    let y_index = pixel_buffer.len() / buffer_width;
-    pixel_buffer.extend(new_pixels);
-    pixel_buffer.extend(repeat(Rgba([0; 4])).take(buffer_width));
-    let (ax, ay) = glyph.advance;
-    AtlasIndex {
-        y_index,
-        top: glyph.top as f32 * scale,
-        left: glyph.left as f32 * scale,
-        width: glyph.width as f32 * scale,
-        height: glyph.height as f32 * scale,
-        ax: ax as f32 * scale,
-        ay: ay as f32 * scale,
+            .collect();
+        let field = distance_field(&pixels, Format::Gray, width, height);
+        let field_width = width + 2 * SDF_SPREAD;
+        assert_eq!(field.len(), field_width * (height + 2 * SDF_SPREAD));
+        let at = |x: usize, y: usize| field[(y + SDF_SPREAD) * field_width + x + SDF_SPREAD];
+        // Pixel centres half a pixel in and out of the edge, at 0.5 ± 0.5 / 12
+        assert_eq!(at(1, 4), 138);
+        assert_eq!(at(0, 4), 117);
+        assert!(at(3, 3) > at(2, 3) && at(2, 3) > at(1, 3));
+        assert_eq!(field[0], 0);
+        // Symmetric, like the square
+        assert_eq!(at(1, 4), at(6, 4));
+        assert_eq!(at(4, 1), at(4, 6));
+
+        // Colour channels other than red are ignored
+        let rgba: Vec<u8> = pixels.iter().flat_map(|&p| [p, 0, 0, 0]).collect();
+        assert_eq!(distance_field(&rgba, Format::Rgba, width, height), field);
+    }
+
+    #[test]
+    fn stats_count_map_lookups() {
+        let mut atlas = GlyphAtlas::new();
+        // Selecting a scale looks nothing up
+        atlas.select_scale(1., 1);
+        atlas.select_scale(1., 1);
+        let stats = atlas.stats();
+        assert_eq!((stats.hits, stats.misses), (0, 0));
+
+        atlas.add_characters("ab".chars());
+        atlas.add_characters("cd".chars());
+        let _ = atlas.bitmap();
+        let stats = atlas.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 1));
+
+        atlas.select_scale(2., 1);
+        atlas.add_characters("ab".chars());
+        atlas.select_scale(1., 1);
+        atlas.add_characters("ab".chars());
+        let stats = atlas.stats();
+        assert_eq!((stats.hits, stats.misses), (3, 2));
     }
 }
@@ -0,0 +1,448 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use crate::{atlas::Font, ex, theme};
+
+/// Text typed in command mode, starting with `:`, `/` or `?`, and its cursor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandLine {
+    text: String,
+    /// Byte offset of the cursor in `text`
+    cursor: usize,
+    /// History entry shown by Up/Down, and the text typed before walking the
+    /// history, which only entries starting with it are shown for
+    history_pos: Option<(usize, String)>,
+    completion: Option<Completion>,
+}
+
+/// Candidates Tab cycles through
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Completion {
+    candidates: Vec<String>,
+    idx: usize,
+    /// Byte offset of the completed word in the text
+    start: usize,
+}
+
+impl CommandLine {
+    pub fn new(text: impl Into<String>) -> Self {
+        let text = text.into();
+        Self {
+            cursor: text.len(),
+            text,
+            history_pos: None,
+            completion: None,
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Start of the editable text, after the `:`, `/` or `?`
+    fn start(&self) -> usize {
+        self.text.chars().next().map_or(0, char::len_utf8)
+    }
+
+    /// Marks the end of Tab and Up/Down sequences
+    fn edited(&mut self) {
+        self.history_pos = None;
+        self.completion = None;
+    }
+
+    pub fn insert(&mut self, s: &str) {
+        self.text.insert_str(self.cursor, s);
+        self.cursor += s.len();
+        self.edited();
+    }
+
+    /// Deletes the char before the cursor. Returns `false` if there is
+    /// nothing left to delete, which closes the command line.
+    pub fn backspace(&mut self) -> bool {
+        if self.text.len() <= self.start() {
+            return false;
+        }
+        if let Some(c) = self.text[self.start()..self.cursor].chars().next_back() {
+            self.cursor -= c.len_utf8();
+            self.text.remove(self.cursor);
+            self.edited();
+        }
+        true
+    }
+
+    /// Deletes the word before the cursor, along with the blanks after it
+    pub fn delete_word(&mut self) {
+        let before = &self.text[self.start()..self.cursor];
+        let trimmed = before.trim_end();
+        let word_start = match trimmed.chars().next_back() {
+            Some(c) if c.is_alphanumeric() || c == '_' => {
+                trimmed.trim_end_matches(|c: char| c.is_alphanumeric() || c == '_')
+            }
+            Some(c) => trimmed.strip_suffix(c).unwrap_or(trimmed),
+            None => trimmed,
+        };
+        let start = self.start() + word_start.len();
+        self.text.replace_range(start..self.cursor, "");
+        self.cursor = start;
+        self.edited();
+    }
+
+    /// Deletes everything before the cursor
+    pub fn delete_to_start(&mut self) {
+        self.text.replace_range(self.start()..self.cursor, "");
+        self.cursor = self.start();
+        self.edited();
+    }
+
+    pub fn move_left(&mut self) {
+        if let Some(c) = self.text[self.start()..self.cursor].chars().next_back() {
+            self.cursor -= c.len_utf8();
+        }
+        self.edited();
+    }
+
+    pub fn move_right(&mut self) {
+        if let Some(c) = self.text[self.cursor..].chars().next() {
+            self.cursor += c.len_utf8();
+        }
+        self.edited();
+    }
+
+    pub fn move_home(&mut self) {
+        self.cursor = self.start();
+        self.edited();
+    }
+
+    pub fn move_end(&mut self) {
+        self.cursor = self.text.len();
+        self.edited();
+    }
+
+    /// Shows the previous (older) history entry starting with the text typed
+    /// so far, or the next one if `newer`
+    pub fn walk_history(&mut self, history: &History, newer: bool) {
+        let (pos, typed) = self
+            .history_pos
+            .take()
+            .unwrap_or_else(|| (history.entries.len(), self.text.clone()));
+        let matches = |idx: &usize| history.entries[*idx].starts_with(&typed);
+        let found = if newer {
+            (pos + 1..history.entries.len()).find(matches)
+        } else {
+            (0..pos).rev().find(matches)
+        };
+        match found {
+            Some(idx) => {
+                self.text = history.entries[idx].clone();
+                self.history_pos = Some((idx, typed));
+            }
+            // Past the newest entry, back to what was typed
+            None if newer => self.text = typed,
+            None => self.history_pos = Some((pos, typed)),
+        }
+        self.cursor = self.text.len();
+        self.completion = None;
+    }
+
+    /// Completes the word before the cursor, or goes to the next candidate
+    /// (the previous one if `reverse`) when Tab was the last key
+    pub fn complete(&mut self, reverse: bool) {
+        let completion = match self.completion.take() {
+            Some(mut completion) => {
+                let len = completion.candidates.len();
+                completion.idx = if reverse {
+                    (completion.idx + len - 1) % len
+                } else {
+                    (completion.idx + 1) % len
+                };
+                completion
+            }
+            None => {
+                let (start, candidates) = candidates(&self.text[..self.cursor]);
+                if candidates.is_empty() {
+                    return;
+                }
+                let idx = if reverse { candidates.len() - 1 } else { 0 };
+                Completion {
+                    candidates,
+                    idx,
+                    start,
+                }
+            }
+        };
+        let candidate = &completion.candidates[completion.idx];
+        self.text
+            .replace_range(completion.start..self.cursor, candidate);
+        self.cursor = completion.start + candidate.len();
+        self.history_pos = None;
+        self.completion = Some(completion);
+    }
+}
+
+/// Where the word before the cursor starts, and what it can be completed to
+fn candidates(before_cursor: &str) -> (usize, Vec<String>) {
+    let Some(command) = before_cursor.strip_prefix(':') else {
+        return (0, Vec::new());
+    };
+    // Skip the range, which is made of these chars, marks, and patterns that
+    // aren't worth the trouble
+    let name_start = command
+        .find(|c: char| !(c.is_ascii_digit() || " .,$%'<>+-".contains(c)))
+        .unwrap_or(command.len());
+    let rest = &command[name_start..];
+    let name_len = rest
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(rest.len());
+    let (name, arg) = rest.split_at(name_len);
+    let offset = 1 + name_start;
+    if arg.is_empty() {
+        let names = ex::COMMANDS.iter().filter(|cmd| cmd.starts_with(name));
+        return (offset, names.map(|cmd| cmd.to_string()).collect());
+    }
+
+    let arg = arg.strip_prefix('!').unwrap_or(arg);
+    let word = arg.trim_start();
+    let offset = before_cursor.len() - word.len();
+    let candidates = match name {
+        "e" | "edit" | "w" | "write" | "wq" | "x" | "xit" | "saveas" => path_candidates(word),
+        "colo" | "colorscheme" => theme::names()
+            .into_iter()
+            .filter(|name| name.starts_with(word))
+            .collect(),
+        "font" => Font::query()
+            .map(|font| font.to_string())
+            .filter(|font| font.to_lowercase().starts_with(&word.to_lowercase()))
+            .collect(),
+        _ => Vec::new(),
+    };
+    (offset, candidates)
+}
+
+/// Paths starting with `prefix`, directories ending with `/`
+fn path_candidates(prefix: &str) -> Vec<String> {
+    let (dir, file) = match prefix.rfind('/') {
+        Some(idx) => prefix.split_at(idx + 1),
+        None => ("", prefix),
+    };
+    let entries = match fs::read_dir(if dir.is_empty() { "." } else { dir }) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut candidates: Vec<_> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            // Hidden files only show up when asked for
+            if !name.starts_with(file) || (name.starts_with('.') && !file.starts_with('.')) {
+                return None;
+            }
+            let slash = if entry.path().is_dir() { "/" } else { "" };
+            Some(format!("{dir}{name}{slash}"))
+        })
+        .collect();
+    candidates.sort();
+    candidates
+}
+
+/// Command lines run so far, oldest first, kept across sessions
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct History {
+    entries: Rc<Vec<String>>,
+    /// Where the history is saved, `None` for one that isn't
+    path: Option<Rc<Path>>,
+}
+
+impl History {
+    /// Reads the history saved by previous sessions
+    pub fn load() -> Self {
+        let path = history_path();
+        let entries = match path.as_deref().map(fs::read_to_string) {
+            Some(Ok(text)) => text.lines().map(str::to_owned).collect(),
+            Some(Err(e)) if e.kind() != io::ErrorKind::NotFound => {
+                eprintln!("Couldn't read the command history: {e}");
+                Vec::new()
+            }
+            _ => Vec::new(),
+        };
+        Self {
+            entries: Rc::new(entries),
+            path: path.map(Rc::from),
+        }
+    }
+
+    /// Adds `entry` as the newest entry, keeping at most `size` of them, and
+    /// saves the history
+    pub fn push(&mut self, entry: &str, size: usize) {
+        let entries = Rc::make_mut(&mut self.entries);
+        entries.retain(|old| old != entry);
+        entries.push(entry.to_owned());
+        let excess = entries.len().saturating_sub(size);
+        entries.drain(..excess);
+
+        if let Some(path) = &self.path {
+            if let Err(e) = self.save(path) {
+                eprintln!("Couldn't save the command history: {e}");
+            }
+        }
+    }
+
+    fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut text = self.entries.join("\n");
+        text.push('\n');
+        fs::write(path, text)
+    }
+}
+
+/// `$XDG_STATE_HOME/saphedit/history`, `XDG_STATE_HOME` defaulting to
+/// `~/.local/state`
+fn history_path() -> Option<PathBuf> {
+    let state_dir = match env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".local/state"),
+    };
+    Some(state_dir.join("saphedit/history"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn editing() {
+        let mut line = CommandLine::new(":s/foo bar");
+        line.delete_word();
+        assert_eq!(line.text(), ":s/foo ");
+        line.delete_word();
+        assert_eq!(line.text(), ":s/");
+        line.delete_word();
+        assert_eq!(line.text(), ":s");
+        line.insert("/éb");
+        line.move_left();
+        line.insert("x");
+        assert_eq!((line.text(), line.cursor()), (":s/éxb", 6));
+        // The `:` stays out of reach
+        line.move_home();
+        line.move_left();
+        line.insert("%");
+        assert_eq!((line.text(), line.cursor()), (":%s/éxb", 2));
+        line.move_right();
+        line.delete_to_start();
+        assert_eq!((line.text(), line.cursor()), (":/éxb", 1));
+        assert!(line.backspace());
+        assert_eq!(line.text(), ":/éxb");
+
+        line.move_end();
+        for _ in 0..4 {
+            assert!(line.backspace());
+        }
+        assert_eq!((line.text(), line.cursor()), (":", 1));
+        assert!(!line.backspace());
+    }
+
+    #[test]
+    fn history() {
+        let mut history = History::default();
+        for entry in [":s/a/b", ":w", ":set", ":w"] {
+            history.push(entry, 3);
+        }
+        assert_eq!(*history.entries, [":s/a/b", ":set", ":w"]);
+        history.push(":q", 3);
+        assert_eq!(*history.entries, [":set", ":w", ":q"]);
+
+        let mut line = CommandLine::new(":");
+        line.walk_history(&history, false);
+        assert_eq!(line.text(), ":q");
+        line.walk_history(&history, false);
+        line.walk_history(&history, false);
+        line.walk_history(&history, false);
+        assert_eq!(line.text(), ":set");
+        line.walk_history(&history, true);
+        assert_eq!((line.text(), line.cursor()), (":w", 2));
+        line.walk_history(&history, true);
+        line.walk_history(&history, true);
+        assert_eq!(line.text(), ":");
+
+        // Only entries starting with what was typed, until it is edited
+        let mut line = CommandLine::new(":s");
+        line.walk_history(&history, false);
+        assert_eq!(line.text(), ":set");
+        line.walk_history(&history, true);
+        assert_eq!(line.text(), ":s");
+        line.walk_history(&history, false);
+        line.insert(" x");
+        line.walk_history(&history, false);
+        assert_eq!(line.text(), ":set x");
+    }
+
+    #[test]
+    fn completion() {
+        let mut line = CommandLine::new(":no");
+        line.complete(false);
+        assert_eq!(line.text(), ":nohlsearch");
+        let mut line = CommandLine::new(":'<,'>d");
+        line.complete(false);
+        assert_eq!(line.text(), ":'<,'>delete");
+
+        let mut line = CommandLine::new(":w");
+        line.complete(false);
+        assert_eq!(line.text(), ":wq");
+        line.complete(false);
+        assert_eq!(line.text(), ":write");
+        line.complete(false);
+        assert_eq!(line.text(), ":wq");
+        line.complete(true);
+        assert_eq!(line.text(), ":write");
+        // Typing ends the cycle, the next Tab completes the new word
+        line.insert(" ");
+        line.move_left();
+        line.complete(false);
+        assert_eq!(line.text(), ":write ");
+
+        let mut line = CommandLine::new(":zz");
+        line.complete(false);
+        assert_eq!((line.text(), line.cursor()), (":zz", 3));
+        assert_eq!(candidates("/w"), (0, Vec::new()));
+        assert_eq!(candidates(":d x"), (3, Vec::new()));
+    }
+
+    #[test]
+    fn path_completion() {
+        let dir = env::temp_dir().join(format!("saphedit-cmdline-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        for file in ["file.txt", "fine.rs", ".hidden"] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        let dir = dir.to_str().unwrap();
+
+        let (start, found) = candidates(&format!(":e {dir}/fi"));
+        assert_eq!(start, 3);
+        assert_eq!(found, [format!("{dir}/file.txt"), format!("{dir}/fine.rs")]);
+        let (_, found) = candidates(&format!(":w! {dir}/"));
+        assert_eq!(
+            found,
+            [
+                format!("{dir}/file.txt"),
+                format!("{dir}/fine.rs"),
+                format!("{dir}/sub/")
+            ]
+        );
+        let (_, found) = candidates(&format!(":saveas {dir}/."));
+        assert_eq!(found, [format!("{dir}/.hidden")]);
+        assert_eq!(
+            candidates(&format!(":e {dir}/nothing/")).1,
+            Vec::<String>::new()
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,31 +1,267 @@
-use std::time::Duration;
-
-use sdl2::keyboard::Keycode;
-
-use crate::{KeyBind, KeyMod};
-
-/// Width of the insertion mode cursor
-pub const INSERT_CURSOR_WIDTH: f32 = 0.25;
-/// Width of the normal mode cursor
-pub const NORMAL_CURSOR_WIDTH: f32 = 1.;
-/// Maximum text size/scale
-pub const MAX_SCALE: f32 = 64.;
-/// How long the cursor should blink
-pub const BLINK_TIME: Duration = Duration::from_millis(500);
-/// Margin (in letter size) to draw around both sides
-pub const MARGIN: f32 = 2.;
-/// How long the scaling animation should take
-pub const SCALE_ANIM_TIME: Duration = Duration::from_millis(100);
-/// How long the scrolling animation should take should take
-pub const SCROLL_ANIM_TIME: Duration = Duration::from_millis(100);
-/// Offset from the bottom of the central line to the centre of the screen
-pub const CENTER_OFFSET: f32 = -0.5;
-
-/// Insertion mode: print available fonts to console (tmp)
-pub(crate) const INSERT_PRINT_FONTS: KeyBind = KeyBind::ctrl(Keycode::F);
-/// Insertion mode: cycle font
-pub(crate) const INSERT_CYCLE_FONTS: KeyBind = KeyBind::new(Keycode::F, KeyMod::CtrlShift);
-/// Insertion mode: copy whole buffer to system clipboard
-pub(crate) const INSERT_COPY: KeyBind = KeyBind::ctrl(Keycode::C);
-/// Insertion mode: paste whole buffer from system clipboard
-pub(crate) const INSERT_PASTE: KeyBind = KeyBind::ctrl(Keycode::V);
+use std::{
+    collections::BTreeMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use inotify::{Inotify, WatchMask};
+use sdl2::keyboard::{Keycode, Mod};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer};
+
+use crate::{atlas::GlyphMode, input::KeyBind, keymap::MapMode};
+
+/// Settings read from `config.toml`. Every key is optional, the defaults
+/// being the values below.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Font the editor starts with, the bundled one if `None`
+    pub font: Option<String>,
+    /// How glyphs are rasterised: `"bitmap"` again for each zoom level, or
+    /// `"sdf"` once, as distance fields that stay sharp when zooming
+    pub glyphs: GlyphMode,
+    /// Width of the insertion mode cursor
+    pub insert_cursor_width: f32,
+    /// Width of the normal mode cursor
+    pub normal_cursor_width: f32,
+    /// Width of the cursor while an operator waits for its motion
+    pub operator_pending_cursor_width: f32,
+    /// Maximum text size/scale
+    pub max_scale: f32,
+    /// How long the cursor should blink
+    #[serde(rename = "blink_time_ms", deserialize_with = "millis")]
+    pub blink_time: Duration,
+    /// Size of the window when it opens, and of screenshots
+    pub window_size: (u32, u32),
+    /// Margin (in letter size) to draw around both sides
+    pub margin: f32,
+    /// How long the scaling animation should take
+    #[serde(rename = "scale_anim_time_ms", deserialize_with = "millis")]
+    pub scale_anim_time: Duration,
+    /// How long the scrolling animation should take
+    #[serde(rename = "scroll_anim_time_ms", deserialize_with = "millis")]
+    pub scroll_anim_time: Duration,
+    /// Offset from the bottom of the central line to the centre of the screen
+    pub center_offset: f32,
+    /// How many lines PageUp/PageDown move the cursor by
+    pub page_lines: usize,
+    /// How many spaces `>` and `<` indent by
+    pub shift_width: usize,
+    /// Theme the editor starts with, as `:colorscheme` takes it
+    pub colorscheme: String,
+    /// Whether line numbers are shown in front of the lines
+    pub number: bool,
+    /// How many command lines are kept in the history
+    pub history_size: usize,
+    pub keys: Keys,
+    /// Key `<leader>` stands for in mappings
+    pub leader: String,
+    /// How long typed keys wait for the rest of a mapping
+    #[serde(rename = "timeout_ms", deserialize_with = "millis")]
+    pub timeout: Duration,
+    /// Mappings whose keys can be mapped in turn, like `:map`
+    pub map: ModeMaps,
+    /// Mappings whose keys are used as they are, like `:noremap`
+    pub noremap: ModeMaps,
+}
+
+/// Keys with a modifier that don't come from Vim, written like in scripts,
+/// as in `redo = "<C-r>"` or `copy = "<D-c>"`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Keys {
+    /// Normal mode: redo
+    pub redo: KeyBind,
+    /// Normal mode: start or leave visual block mode
+    pub visual_block: KeyBind,
+    /// Command mode: delete the word before the cursor
+    pub command_delete_word: KeyBind,
+    /// Command mode: delete everything before the cursor
+    pub command_delete_line: KeyBind,
+    /// Insertion mode: print available fonts to console (tmp)
+    pub print_fonts: KeyBind,
+    /// Insertion mode: cycle font
+    pub cycle_fonts: KeyBind,
+    /// Insertion, normal and visual modes: copy the selection, or the
+    /// cursor's line, to system clipboard
+    pub copy: KeyBind,
+    /// Insertion mode: paste the system clipboard, `"+`, at the cursor
+    pub paste: KeyBind,
+}
+
+/// Mappings of each mode, from keys to keys in the notation of scripts, as
+/// in `jk = "<Esc>"` under `[map.insert]`
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModeMaps {
+    pub normal: BTreeMap<String, String>,
+    pub visual: BTreeMap<String, String>,
+    pub operator_pending: BTreeMap<String, String>,
+    pub insert: BTreeMap<String, String>,
+    pub command: BTreeMap<String, String>,
+}
+
+impl ModeMaps {
+    /// Every mapping, with its mode
+    pub fn iter(&self) -> impl Iterator<Item = (MapMode, &str, &str)> {
+        [
+            (MapMode::Normal, &self.normal),
+            (MapMode::Visual, &self.visual),
+            (MapMode::OperatorPending, &self.operator_pending),
+            (MapMode::Insert, &self.insert),
+            (MapMode::Command, &self.command),
+        ]
+        .into_iter()
+        .flat_map(|(mode, maps)| {
+            maps.iter()
+                .map(move |(lhs, rhs)| (mode, lhs.as_str(), rhs.as_str()))
+        })
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            font: None,
+            glyphs: GlyphMode::default(),
+            insert_cursor_width: 0.25,
+            normal_cursor_width: 1.,
+            operator_pending_cursor_width: 0.5,
+            max_scale: 64.,
+            blink_time: Duration::from_millis(500),
+            window_size: (800, 600),
+            margin: 2.,
+            scale_anim_time: Duration::from_millis(100),
+            scroll_anim_time: Duration::from_millis(100),
+            center_offset: -0.5,
+            page_lines: 20,
+            shift_width: 4,
+            colorscheme: "default".into(),
+            number: false,
+            history_size: 100,
+            keys: Keys::default(),
+            leader: "\\".into(),
+            timeout: Duration::from_millis(1000),
+            map: ModeMaps::default(),
+            noremap: ModeMaps::default(),
+        }
+    }
+}
+
+impl Default for Keys {
+    fn default() -> Self {
+        Self {
+            redo: KeyBind::ctrl(Keycode::R),
+            visual_block: KeyBind::ctrl(Keycode::V),
+            command_delete_word: KeyBind::ctrl(Keycode::W),
+            command_delete_line: KeyBind::ctrl(Keycode::U),
+            print_fonts: KeyBind::ctrl(Keycode::F),
+            cycle_fonts: KeyBind::new(Keycode::F, Mod::LCTRLMOD.union(Mod::LSHIFTMOD)),
+            copy: KeyBind::ctrl(Keycode::C),
+            paste: KeyBind::ctrl(Keycode::V),
+        }
+    }
+}
+
+impl Config {
+    /// Smallest scale the text is zoomed out to, and so the smallest
+    /// `max_scale`
+    pub const MIN_SCALE: f32 = 8.;
+
+    /// Reads the config file at `path`, a missing one being empty
+    pub fn read(path: &Path) -> Result<Self, String> {
+        match fs::read_to_string(path) {
+            Ok(text) => parse_toml(path, &text).and_then(|config: Self| config.check(path)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(format!("Couldn't read {}: {e}", path.display())),
+        }
+    }
+
+    /// Rejects the values that parse but can't be used
+    fn check(self, path: &Path) -> Result<Self, String> {
+        if self.max_scale.is_nan() || self.max_scale < Self::MIN_SCALE {
+            return Err(format!(
+                "{}: max_scale must be at least {}",
+                path.display(),
+                Self::MIN_SCALE
+            ));
+        }
+        Ok(self)
+    }
+}
+
+/// Parses `text`, read from `path`, with errors pointing at the line
+pub fn parse_toml<T: DeserializeOwned>(path: &Path, text: &str) -> Result<T, String> {
+    toml::from_str(text).map_err(|e| {
+        // The full error draws the line, which doesn't fit in the footer
+        let line = e
+            .span()
+            .map(|span| text[..span.start].matches('\n').count() + 1);
+        match line {
+            Some(line) => format!("{}:{line}: {}", path.display(), e.message()),
+            None => format!("{}: {}", path.display(), e.message()),
+        }
+    })
+}
+
+/// `$XDG_CONFIG_HOME/saphedit`, `XDG_CONFIG_HOME` defaulting to `~/.config`
+pub fn config_dir() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("saphedit"))
+}
+
+/// `config.toml` in `config_dir`
+pub fn config_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("config.toml"))
+}
+
+/// Durations are written in milliseconds
+fn millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_millis)
+}
+
+/// Tells when the config file was written, to reload it
+pub struct ConfigWatcher {
+    inotify: Inotify,
+    path: PathBuf,
+}
+
+impl ConfigWatcher {
+    /// Watches the directory of the file rather than the file, as editors
+    /// often write a new file and rename it over the old one
+    pub fn new(path: PathBuf) -> io::Result<Self> {
+        let dir = path.parent().unwrap_or(Path::new("."));
+        fs::create_dir_all(dir)?;
+        let inotify = Inotify::init()?;
+        inotify.watches().add(
+            dir,
+            WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::DELETE,
+        )?;
+        Ok(Self { inotify, path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the file changed since the last call. Doesn't block.
+    pub fn changed(&mut self) -> io::Result<bool> {
+        let mut buffer = [0; 1024];
+        let mut changed = false;
+        loop {
+            let events = match self.inotify.read_events(&mut buffer) {
+                Ok(events) => events,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(changed),
+                Err(e) => return Err(e),
+            };
+            let name = self.path.file_name();
+            changed |= events
+                .into_iter()
+                .any(|event| event.name.is_some() && event.name == name);
+        }
+    }
+}
@@ -0,0 +1,556 @@
+use std::ops::Range;
+
+use crate::{motion, rope::Rope, search::Search, LogicState};
+
+/// Line a range address starts from
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Base {
+    /// Line number, starting at 1
+    Line(usize),
+    /// `.`
+    Current,
+    /// `$`
+    Last,
+    /// `'x`
+    Mark(char),
+    /// `/pat/` or `?pat?`
+    Pattern { pattern: String, backward: bool },
+}
+
+/// Line address in a command range, such as `.+2` or `/foo/-1`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Address {
+    base: Base,
+    offset: isize,
+}
+
+impl Address {
+    fn new(base: Base) -> Self {
+        Self { base, offset: 0 }
+    }
+
+    /// Row the address points to
+    fn resolve(&self, state: &LogicState) -> Result<usize, String> {
+        let buffer = &state.text_buffer;
+        let row = match &self.base {
+            Base::Line(n) => n.saturating_sub(1),
+            Base::Current => state.cursor_row,
+            Base::Last => buffer.len_lines() - 1,
+            Base::Mark(mark) => match (mark, state.visual_marks) {
+                ('<', Some((start, _))) => start.0,
+                ('>', Some((_, end))) => end.0,
+                _ => return Err("E20: Mark not set".into()),
+            },
+            Base::Pattern { pattern, backward } => {
+                let search = pattern_search(state, pattern, *backward)?;
+                let line = buffer.line_range(state.cursor_row);
+                let from = if *backward { line.start } else { line.end };
+                match search.find(&state.flat_text.get(buffer), from, *backward, 1) {
+                    Some(byte) => buffer.byte_to_line(byte),
+                    None => return Err(format!("E486: Pattern not found: {}", search.pattern())),
+                }
+            }
+        };
+        row.checked_add_signed(self.offset)
+            .filter(|&row| row < buffer.len_lines())
+            .ok_or_else(|| "E16: Invalid range".into())
+    }
+}
+
+/// Full names of the commands, as completed by Tab
+pub const COMMANDS: &[&str] = &[
+    "cmap",
+    "colorscheme",
+    "cnoremap",
+    "cunmap",
+    "delete",
+    "edit",
+    "font",
+    "global",
+    "imap",
+    "inoremap",
+    "iunmap",
+    "map",
+    "nmap",
+    "nnoremap",
+    "nohlsearch",
+    "noremap",
+    "nunmap",
+    "omap",
+    "onoremap",
+    "ounmap",
+    "quit",
+    "redo",
+    "saveas",
+    "substitute",
+    "undo",
+    "unmap",
+    "vglobal",
+    "vmap",
+    "vnoremap",
+    "vunmap",
+    "wq",
+    "write",
+    "xit",
+    "xmap",
+    "xnoremap",
+    "xunmap",
+    "yank",
+];
+
+/// Parsed command line: `[range]name[!] arg`
+#[derive(Debug)]
+pub struct ExCommand<'a> {
+    pub range: Option<(Address, Address)>,
+    pub name: &'a str,
+    pub bang: bool,
+    pub arg: &'a str,
+}
+
+impl ExCommand<'_> {
+    /// Rows the command acts on, or `None` if no range was given
+    pub fn rows(&self, state: &LogicState) -> Result<Option<Range<usize>>, String> {
+        let Some((start, end)) = &self.range else {
+            return Ok(None);
+        };
+        let (start, end) = (start.resolve(state)?, end.resolve(state)?);
+        // Backward ranges are swapped without asking
+        Ok(Some(start.min(end)..start.max(end) + 1))
+    }
+}
+
+/// Parses a command line, without its `:`
+pub fn parse(line: &str) -> Result<ExCommand<'_>, String> {
+    let mut rest = line.trim_start();
+    let range = if let Some(after) = rest.strip_prefix('%') {
+        rest = after;
+        Some((Address::new(Base::Line(1)), Address::new(Base::Last)))
+    } else {
+        let start = parse_address(&mut rest)?;
+        if let Some(after) = rest.strip_prefix(',') {
+            rest = after;
+            let end = parse_address(&mut rest)?;
+            let current = || Address::new(Base::Current);
+            Some((start.unwrap_or_else(current), end.unwrap_or_else(current)))
+        } else {
+            start.map(|address| (address.clone(), address))
+        }
+    };
+
+    let rest = rest.trim_start();
+    let name_end = rest
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(rest.len());
+    let (name, rest) = rest.split_at(name_end);
+    let (bang, rest) = match rest.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, rest),
+    };
+    Ok(ExCommand {
+        range,
+        name,
+        bang,
+        arg: rest.trim_start(),
+    })
+}
+
+/// Parses an address at the start of `s` and moves `s` past it
+fn parse_address(s: &mut &str) -> Result<Option<Address>, String> {
+    let mut chars = s.chars();
+    let base = match chars.next() {
+        Some('.') => {
+            *s = chars.as_str();
+            Some(Base::Current)
+        }
+        Some('$') => {
+            *s = chars.as_str();
+            Some(Base::Last)
+        }
+        Some('\'') => {
+            let mark = chars.next().ok_or("E20: Mark not set")?;
+            *s = chars.as_str();
+            Some(Base::Mark(mark))
+        }
+        Some(c) if c.is_ascii_digit() => Some(Base::Line(parse_number(s)?)),
+        Some(delim @ ('/' | '?')) => {
+            let (pattern, rest) = split_delimited(chars.as_str(), delim);
+            *s = rest.unwrap_or("");
+            Some(Base::Pattern {
+                pattern,
+                backward: delim == '?',
+            })
+        }
+        _ => None,
+    };
+
+    // `+N` and `-N`, without a base they are relative to the current line
+    let mut offset = None;
+    while let Some(sign @ ('+' | '-')) = s.chars().next() {
+        *s = &s[1..];
+        let n = if s.starts_with(|c: char| c.is_ascii_digit()) {
+            parse_number(s)? as isize
+        } else {
+            1
+        };
+        let n = if sign == '-' { -n } else { n };
+        offset = Some(offset.unwrap_or(0) + n);
+    }
+
+    Ok(match (base, offset) {
+        (None, None) => None,
+        (base, offset) => Some(Address {
+            base: base.unwrap_or(Base::Current),
+            offset: offset.unwrap_or(0),
+        }),
+    })
+}
+
+/// Parses the digits at the start of `s` and moves `s` past them
+fn parse_number(s: &mut &str) -> Result<usize, String> {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (digits, rest) = s.split_at(end);
+    *s = rest;
+    digits.parse().map_err(|_| "E16: Invalid range".into())
+}
+
+/// Splits `s` at the first `delim` not escaped with a backslash, unescaping
+/// the escaped ones. The rest is `None` if there is no closing `delim`.
+fn split_delimited(s: &str, delim: char) -> (String, Option<&str>) {
+    let mut field = String::new();
+    let mut chars = s.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some((_, c)) if c == delim => field.push(c),
+                Some((_, c)) => {
+                    field.push('\\');
+                    field.push(c);
+                }
+                None => field.push('\\'),
+            },
+            c if c == delim => return (field, Some(&s[idx + c.len_utf8()..])),
+            c => field.push(c),
+        }
+    }
+    (field, None)
+}
+
+/// Search for `pattern`, the last search if it is empty
+pub fn pattern_search(state: &LogicState, pattern: &str, backward: bool) -> Result<Search, String> {
+    let pattern = match (pattern, &state.last_search) {
+        ("", Some(last)) => last.pattern(),
+        ("", None) => return Err("E35: No previous regular expression".into()),
+        (pattern, _) => pattern,
+    };
+    Search::new(pattern, backward).map_err(|_| format!("E383: Invalid search string: {pattern}"))
+}
+
+/// Splits `/pat/cmd`, as given to `:g`, into the search and the command
+pub fn parse_global<'a>(state: &LogicState, arg: &'a str) -> Result<(Search, &'a str), String> {
+    let delim = arg_delimiter(arg)?;
+    let (pattern, command) = split_delimited(&arg[delim.len_utf8()..], delim);
+    let search = pattern_search(state, &pattern, false)?;
+    Ok((search, command.unwrap_or("")))
+}
+
+/// Rows in `rows` with a match of `search`, or without one if `invert`
+pub fn global_rows(buffer: &Rope, rows: Range<usize>, search: &Search, invert: bool) -> Vec<usize> {
+    rows.filter(|&row| search.is_match(&buffer.line(row)) != invert)
+        .collect()
+}
+
+/// Where a row matched by `:g` is once a command turned the rows of `edited`
+/// into `added` more or fewer. `None` if the command deleted it: the rows at
+/// the end of `edited` are the ones that went.
+pub fn shift_row(row: usize, edited: &Range<usize>, added: isize) -> Option<usize> {
+    if row < edited.start {
+        Some(row)
+    } else if row >= edited.end {
+        row.checked_add_signed(added)
+    } else {
+        let left = edited.len().saturating_add_signed(added);
+        (row - edited.start < left).then_some(row)
+    }
+}
+
+fn arg_delimiter(arg: &str) -> Result<char, String> {
+    match arg.chars().next() {
+        None => Err("E35: No previous regular expression".into()),
+        Some(c) if c.is_alphanumeric() || matches!(c, '\\' | '"' | '|') => {
+            Err("E146: Regular expressions can't be delimited by letters".into())
+        }
+        Some(c) => Ok(c),
+    }
+}
+
+/// A `:s` in progress. Matches are looked for line by line, from the first
+/// row of the range to the last one, which moves as replacements add or
+/// remove lines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Substitution {
+    search: Search,
+    /// Replacement in the syntax of `regex`
+    replacement: String,
+    /// `g` flag: replace every match on a line, not only the first one
+    global: bool,
+    /// `c` flag: ask before each replacement
+    pub confirm: bool,
+    row: usize,
+    /// Byte in the line matches are looked for from
+    offset: usize,
+    end_row: usize,
+    count: usize,
+    lines: usize,
+    last_row: Option<usize>,
+    /// Whether `offset` is right after a non-empty match, where an empty match
+    /// doesn't count
+    after_match: bool,
+}
+
+impl Substitution {
+    /// Parses the `/pat/rep/flags` argument of `:s`, to act on `rows`
+    pub fn parse(state: &LogicState, arg: &str, rows: Range<usize>) -> Result<Self, String> {
+        let delim = arg_delimiter(arg)?;
+        let (pattern, rest) = split_delimited(&arg[delim.len_utf8()..], delim);
+        let (replacement, flags) = match rest {
+            Some(rest) => split_delimited(rest, delim),
+            None => (String::new(), None),
+        };
+
+        let (mut global, mut confirm, mut ignore_case) = (false, false, false);
+        let flags = flags.unwrap_or("").trim_end();
+        for c in flags.chars() {
+            match c {
+                'g' => global = true,
+                'c' => confirm = true,
+                'i' => ignore_case = true,
+                'I' => ignore_case = false,
+                _ => return Err(format!("E488: Trailing characters: {flags}")),
+            }
+        }
+
+        let mut search = pattern_search(state, &pattern, false)?;
+        if ignore_case {
+            let pattern = format!("(?i){}", search.pattern());
+            search = Search::new(&pattern, false).expect("Flags keep the pattern valid");
+        }
+        Ok(Self {
+            search,
+            replacement: replacement_syntax(&replacement),
+            global,
+            confirm,
+            row: rows.start,
+            offset: 0,
+            end_row: rows.end,
+            count: 0,
+            lines: 0,
+            last_row: None,
+            after_match: false,
+        })
+    }
+
+    /// Search the substitution replaces the matches of
+    pub fn search(&self) -> &Search {
+        &self.search
+    }
+
+    /// Next match to replace: its row and byte range in the line
+    pub fn next_match(&self, buffer: &Rope) -> Option<(usize, Range<usize>)> {
+        let end_row = self.end_row.min(buffer.len_lines());
+        (self.row..end_row).find_map(|row| {
+            let line = buffer.line(row);
+            let mut from = if row == self.row { self.offset } else { 0 };
+            if from > line.len() {
+                return None;
+            }
+            while !line.is_char_boundary(from) {
+                from += 1;
+            }
+            let mut range = self.search.find_in_line(&line, from)?;
+            if range.is_empty() && range.start == from && row == self.row && self.after_match {
+                let next = from + line[from..].chars().next().map_or(1, char::len_utf8);
+                if next > line.len() {
+                    return None;
+                }
+                range = self.search.find_in_line(&line, next)?;
+            }
+            Some((row, range))
+        })
+    }
+
+    /// Replaces the match at `range` on `row`
+    pub fn replace(&mut self, state: &mut LogicState, row: usize, range: Range<usize>) {
+        let buffer = &mut state.text_buffer;
+        let line = buffer.line(row);
+        let new = self.search.expand(&line, range.start, &self.replacement);
+        let start = buffer.line_to_byte(row) + range.start;
+        if !range.is_empty() {
+            buffer.delete(start..start + range.len());
+        }
+        if !new.is_empty() {
+            buffer.insert(start, &new);
+        }
+        state.modified = true;
+
+        self.count += 1;
+        if self.last_row != Some(row) {
+            self.lines += 1;
+        }
+        // Lines added by the replacement are part of the range
+        let added = new.matches('\n').count();
+        self.end_row += added;
+        let end = match new.rfind('\n') {
+            Some(idx) => new.len() - idx - 1,
+            None => range.start + new.len(),
+        };
+        self.last_row = Some(row + added);
+        self.advance(row + added, end, range.is_empty());
+    }
+
+    /// Leaves the match at `range` on `row` as it is
+    pub fn skip(&mut self, row: usize, range: Range<usize>) {
+        self.advance(row, range.end, range.is_empty());
+    }
+
+    /// Leaves the remaining matches as they are
+    pub fn stop(&mut self) {
+        self.row = self.end_row;
+    }
+
+    fn advance(&mut self, row: usize, offset: usize, empty_match: bool) {
+        if self.global {
+            self.row = row;
+            // Don't find the same empty match again
+            self.offset = offset + usize::from(empty_match);
+            self.after_match = !empty_match;
+        } else {
+            self.row = row + 1;
+            self.offset = 0;
+        }
+    }
+
+    /// Ends the substitution: puts the cursor on the last replaced line and
+    /// tells how many replacements were made
+    pub fn finish(&self, state: &mut LogicState) {
+        let Some(row) = self.last_row else {
+            state.message = None;
+            return;
+        };
+        let target = motion::first_non_blank(&state.text_buffer, row);
+        state.set_cursor_byte(target);
+        let plural = |n| if n == 1 { "" } else { "s" };
+        state.message = Some(format!(
+            "{} substitution{} on {} line{}",
+            self.count,
+            plural(self.count),
+            self.lines,
+            plural(self.lines),
+        ));
+    }
+}
+
+/// Turns a Vim replacement string (`\1`, `&`, `\r`...) into the syntax of
+/// `regex` (`${1}`, `${0}`...)
+fn replacement_syntax(vim: &str) -> String {
+    let mut out = String::new();
+    let mut chars = vim.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => out.push_str("${0}"),
+            '$' => out.push_str("$$"),
+            '\\' => match chars.next() {
+                Some(d @ '0'..='9') => out.push_str(&format!("${{{d}}}")),
+                Some('$') => out.push_str("$$"),
+                Some('n' | 'r') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some(c) => out.push(c),
+                None => out.push('\\'),
+            },
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `line` on `text`, with the cursor on `row`. Returns the text and
+    /// the message it left.
+    fn ex(text: &str, row: usize, line: &str) -> (String, Option<String>) {
+        let mut state = LogicState::new(Rope::from(text));
+        state.cursor_row = row;
+        if let Err(e) = crate::run_ex(&mut state, line, false) {
+            state.message = Some(e);
+        }
+        (state.text_buffer.to_string(), state.message)
+    }
+
+    #[test]
+    fn substitute_flags() {
+        assert_eq!(ex("a a\na a", 0, "s/a/b/").0, "b a\na a");
+        assert_eq!(ex("a a\na a", 1, "s/a/b/g").0, "a a\nb b");
+        assert_eq!(ex("a A\nA a", 0, "%s/a/b/gi").0, "b b\nb b");
+        assert_eq!(ex("Foo", 0, "s/foo/x/i").0, "x");
+        assert_eq!(
+            ex("foo bar", 0, r"s/(\w+) (\w+)/\2 \1 & $x/").0,
+            "bar foo foo bar $x"
+        );
+        assert_eq!(ex("a,b,c\nd,e", 0, r"%s/,/\r/g").0, "a\nb\nc\nd\ne");
+        assert_eq!(ex("1\n2\n3\n4", 0, "2,3s/$/!/").0, "1\n2!\n3!\n4");
+        // Empty matches are replaced too, once per position
+        assert_eq!(ex("xay", 0, "s/a*/-/g").0, "-x-y-");
+        assert_eq!(
+            ex("aa\na", 0, "%s/a/b/g").1.as_deref(),
+            Some("3 substitutions on 2 lines")
+        );
+        assert_eq!(
+            ex("a", 0, "s/a/b/z").1.as_deref(),
+            Some("E488: Trailing characters: z")
+        );
+        assert_eq!(
+            ex("a", 0, "s/x/y/").1.as_deref(),
+            Some("E486: Pattern not found: x")
+        );
+        assert_eq!(
+            ex("a", 0, "g/a/s/a/b/c").1.as_deref(),
+            Some("Can't confirm substitutions run by :g")
+        );
+    }
+
+    #[test]
+    fn global_with_relative_ranges() {
+        assert_eq!(ex("1\nfoo\n2\nfoo", 0, "g/foo/d").0, "1\n2");
+        assert_eq!(ex("1\nfoo\n2\nfoo", 0, "v/foo/d").0, "foo\nfoo");
+        // Matched rows deleted by an earlier command are skipped, and the
+        // others still run on the line they matched
+        assert_eq!(ex("x1\nx2\nx3\ny\nx4\nz", 0, "g/x/+1d").0, "x1\nx3\nx4");
+        assert_eq!(ex("b\na\nc\na", 0, "g/a/-1d").0, "a\na");
+        assert_eq!(ex("a\nb\na\nb\nc", 0, "g/a/.,+1d").0, "c");
+        assert_eq!(ex("a\nb\nc", 0, r"g/[ab]/s/$/\r-/").0, "a\n-\nb\n-\nc");
+        assert_eq!(
+            ex("a\nb", 0, "g/b/+1d").1.as_deref(),
+            Some("E16: Invalid range")
+        );
+        assert_eq!(
+            ex("a", 0, "g/a/g/a/d").1.as_deref(),
+            Some("E147: Cannot do :global recursive")
+        );
+    }
+
+    #[test]
+    fn shifted_rows() {
+        // Rows 2 and 3 turned into one
+        assert_eq!(shift_row(1, &(2..4), -1), Some(1));
+        assert_eq!(shift_row(2, &(2..4), -1), Some(2));
+        assert_eq!(shift_row(3, &(2..4), -1), None);
+        assert_eq!(shift_row(4, &(2..4), -1), Some(3));
+        // Deleted
+        assert_eq!(shift_row(2, &(2..4), -2), None);
+        assert_eq!(shift_row(5, &(2..4), -2), Some(3));
+        // Split
+        assert_eq!(shift_row(3, &(2..4), 2), Some(3));
+        assert_eq!(shift_row(4, &(2..4), 2), Some(6));
+    }
+}
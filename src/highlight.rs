@@ -0,0 +1,963 @@
+use std::{iter, mem, ops::Range, path::Path, ptr};
+
+use crate::{rope::Rope, syntax::SyntaxCache};
+
+/// Words of each language, separated by spaces
+const RUST_KEYWORDS: &str = "as async await break const continue crate dyn else enum extern fn \
+    for if impl in let loop match mod move mut pub ref return self Self static struct super \
+    trait type unsafe use where while yield";
+const RUST_TYPES: &str = "bool char str u8 u16 u32 u64 u128 usize i8 i16 i32 i64 i128 isize \
+    f32 f64";
+const GLSL_KEYWORDS: &str = "attribute break case centroid const continue default discard do \
+    else flat for highp if in inout invariant layout lowp mediump noperspective out precision \
+    return smooth struct switch uniform varying while";
+const GLSL_TYPES: &str = "void bool int uint float double";
+
+/// Languages there are tokenizers or grammars for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    Rust,
+    Toml,
+    Markdown,
+    Glsl,
+}
+
+impl Language {
+    /// Language of the file at `path`, from its extension
+    pub fn of(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "rs" => Some(Language::Rust),
+            "toml" => Some(Language::Toml),
+            "md" | "markdown" => Some(Language::Markdown),
+            "glsl" | "vert" | "frag" | "geom" | "comp" => Some(Language::Glsl),
+            _ => None,
+        }
+    }
+}
+
+/// What a span of text is. Each kind has a colour in themes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Token {
+    Comment,
+    Keyword,
+    Type,
+    Function,
+    /// Macros, attributes and preprocessor directives
+    Macro,
+    String,
+    Number,
+    Constant,
+    /// Keys of TOML tables
+    Property,
+    /// Markdown headings and TOML table headers
+    Heading,
+    Emphasis,
+    Link,
+}
+
+/// Span of a line, in bytes from its start
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub range: Range<usize>,
+    pub token: Token,
+}
+
+/// What a line starts inside of, left open by the ones before
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum LineState {
+    #[default]
+    Normal,
+    /// Block comment, nested `depth` times in Rust
+    Comment(u32),
+    String(Quote),
+    /// Fenced Markdown code block, closed by at least `len` `fence` chars
+    CodeBlock {
+        fence: char,
+        len: usize,
+    },
+}
+
+/// How a string ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Quote {
+    char: char,
+    /// 1, or 3 for TOML multi-line strings
+    count: usize,
+    /// `#` after the quote, for Rust raw strings
+    hashes: usize,
+    escapes: bool,
+}
+
+impl Quote {
+    const fn plain(char: char) -> Self {
+        Self {
+            char,
+            count: 1,
+            hashes: 0,
+            escapes: true,
+        }
+    }
+}
+
+/// Spans of every line, for the text and language they were last updated
+/// with. Languages with a grammar are highlighted from their syntax tree,
+/// only on the rows it says changed. The others are tokenized, from the first
+/// changed line until one starts in the same state as before.
+#[derive(Default)]
+pub struct SyntaxHighlight {
+    language: Option<Language>,
+    text: Rope,
+    lines: Vec<Line>,
+}
+
+/// Spans of a line, and the state it starts in
+#[derive(Default)]
+struct Line {
+    state: LineState,
+    spans: Vec<Span>,
+}
+
+impl SyntaxHighlight {
+    /// Highlights `text`, from the tree `syntax` has of it for languages
+    /// with a grammar
+    pub fn update(&mut self, language: Option<Language>, text: &Rope, syntax: &SyntaxCache) {
+        if self.language == language && self.text.ptr_eq(text) {
+            return;
+        }
+        let Some(lang) = language else {
+            self.lines.clear();
+            self.language = None;
+            self.text = text.clone();
+            return;
+        };
+        let (old_count, new_count) = (self.lines.len(), text.len_lines());
+        let (prefix, suffix) = if self.language == language {
+            changed_lines(&self.text, text)
+        } else {
+            (0, 0)
+        };
+        let old_text = mem::replace(&mut self.text, text.clone());
+        self.language = language;
+
+        let lines = &mut self.lines;
+        let highlighted = syntax.with_changes(lang, &old_text, text, |tree, changed| {
+            // Parsed for another text since this one, which can't tell which
+            // rows changed
+            let (prefix, suffix, changed) = match changed {
+                Some(changed) => (prefix, suffix, changed),
+                None => (0, 0, Vec::new()),
+            };
+            // Lines around the change keep their spans, until the tree says
+            // otherwise
+            let old_tail = lines.split_off(old_count - suffix);
+            lines.truncate(prefix);
+            lines.resize_with(new_count - suffix, Line::default);
+            lines.extend(old_tail);
+            for rows in iter::once(prefix..new_count - suffix).chain(changed) {
+                for (row, spans) in rows.clone().zip(tree.highlights(rows)) {
+                    lines[row].spans = spans;
+                }
+            }
+        });
+        if highlighted.is_some() {
+            return;
+        }
+
+        let mut old_rest = self.lines.split_off(prefix.min(old_count));
+        let mut state = old_rest
+            .first()
+            .map_or(LineState::Normal, |line| line.state);
+        for row in prefix..new_count {
+            // Lines after the change are the same, and so are their spans
+            // once they start in the same state
+            if row >= new_count - suffix {
+                let old_idx = row + old_count - new_count - prefix;
+                if old_rest[old_idx].state == state {
+                    self.lines.extend(old_rest.drain(old_idx..));
+                    break;
+                }
+            }
+            let (spans, end) = tokenize(lang, state, &text.line(row));
+            self.lines.push(Line { state, spans });
+            state = end;
+        }
+    }
+
+    /// Spans of line `row`, in order
+    pub fn spans(&self, row: usize) -> &[Span] {
+        self.lines.get(row).map_or(&[], |line| &line.spans)
+    }
+}
+
+/// How many lines at the start, and at the end, both texts have in common
+fn changed_lines(old: &Rope, new: &Rope) -> (usize, usize) {
+    let max_bytes = old.len_bytes().min(new.len_bytes());
+    let (prefix, prefix_lines) = common_bytes(old.chunks(), new.chunks(), max_bytes, false);
+    let max_suffix = max_bytes - prefix;
+    let (_, suffix_lines) = common_bytes(
+        old.chunks_in_rev(0..old.len_bytes()),
+        new.chunks_in_rev(0..new.len_bytes()),
+        max_suffix,
+        true,
+    );
+    let max_lines = old.len_lines().min(new.len_lines());
+    (prefix_lines, suffix_lines.min(max_lines - prefix_lines))
+}
+
+/// How many bytes at the start, and at the end, both texts have in common,
+/// without overlapping
+pub fn common_ends(old: &Rope, new: &Rope) -> (usize, usize) {
+    let max_bytes = old.len_bytes().min(new.len_bytes());
+    let (prefix, _) = common_bytes(old.chunks(), new.chunks(), max_bytes, false);
+    let (suffix, _) = common_bytes(
+        old.chunks_in_rev(0..old.len_bytes()),
+        new.chunks_in_rev(0..new.len_bytes()),
+        max_bytes - prefix,
+        true,
+    );
+    (prefix, suffix)
+}
+
+/// How many bytes two sequences of chunks start with in common, up to `max`,
+/// and how many `\n` there are in them. Chunks that are the same leaf of
+/// both ropes aren't compared byte by byte. With `reversed`, the chunks come
+/// from the end, and are compared from their end.
+fn common_bytes<'a>(
+    mut a: impl Iterator<Item = &'a str>,
+    mut b: impl Iterator<Item = &'a str>,
+    max: usize,
+    reversed: bool,
+) -> (usize, usize) {
+    let (mut len, mut newlines) = (0, 0);
+    let (mut chunk_a, mut chunk_b): (&[u8], &[u8]) = (&[], &[]);
+    while len < max {
+        if chunk_a.is_empty() {
+            let Some(chunk) = a.next() else { break };
+            chunk_a = chunk.as_bytes();
+            continue;
+        }
+        if chunk_b.is_empty() {
+            let Some(chunk) = b.next() else { break };
+            chunk_b = chunk.as_bytes();
+            continue;
+        }
+        let n = chunk_a.len().min(chunk_b.len()).min(max - len);
+        let (part_a, part_b) = if reversed {
+            (&chunk_a[chunk_a.len() - n..], &chunk_b[chunk_b.len() - n..])
+        } else {
+            (&chunk_a[..n], &chunk_b[..n])
+        };
+        let common = if ptr::eq(part_a, part_b) {
+            n
+        } else if reversed {
+            let pairs = part_a.iter().rev().zip(part_b.iter().rev());
+            pairs.take_while(|(x, y)| x == y).count()
+        } else {
+            let pairs = part_a.iter().zip(part_b);
+            pairs.take_while(|(x, y)| x == y).count()
+        };
+        let common_part = if reversed {
+            &part_a[n - common..]
+        } else {
+            &part_a[..common]
+        };
+        newlines += common_part.iter().filter(|&&byte| byte == b'\n').count();
+        len += common;
+        if common < n {
+            break;
+        }
+        if reversed {
+            chunk_a = &chunk_a[..chunk_a.len() - n];
+            chunk_b = &chunk_b[..chunk_b.len() - n];
+        } else {
+            chunk_a = &chunk_a[n..];
+            chunk_b = &chunk_b[n..];
+        }
+    }
+    (len, newlines)
+}
+
+/// Spans of `line`, which starts in `state`, and the state the next one
+/// starts in
+fn tokenize(language: Language, state: LineState, line: &str) -> (Vec<Span>, LineState) {
+    let mut scanner = Scanner {
+        line,
+        pos: 0,
+        spans: Vec::new(),
+    };
+    let state = match language {
+        Language::Rust | Language::Glsl => c_like(&mut scanner, language, state),
+        Language::Toml => toml(&mut scanner, state),
+        Language::Markdown => markdown(&mut scanner, state),
+    };
+    (scanner.spans, state)
+}
+
+/// Walks through a line, recording spans
+struct Scanner<'a> {
+    line: &'a str,
+    pos: usize,
+    spans: Vec<Span>,
+}
+
+impl<'a> Scanner<'a> {
+    fn rest(&self) -> &'a str {
+        &self.line[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn bump(&mut self) {
+        self.pos += self.peek().map_or(0, char::len_utf8);
+    }
+
+    fn eat(&mut self, prefix: &str) -> bool {
+        let found = self.rest().starts_with(prefix);
+        if found {
+            self.pos += prefix.len();
+        }
+        found
+    }
+
+    fn eat_while(&mut self, f: impl Fn(char) -> bool) {
+        while self.peek().is_some_and(&f) {
+            self.bump();
+        }
+    }
+
+    fn skip_line(&mut self) {
+        self.pos = self.line.len();
+    }
+
+    /// Marks what was scanned since `start` as `token`
+    fn push(&mut self, start: usize, token: Token) {
+        if start == self.pos {
+            return;
+        }
+        match self.spans.last_mut() {
+            Some(last) if last.range.end == start && last.token == token => {
+                last.range.end = self.pos;
+            }
+            _ => self.spans.push(Span {
+                range: start..self.pos,
+                token,
+            }),
+        }
+    }
+
+    /// Scans a string started at `start` up to its closing quote, if it's
+    /// on this line
+    fn string(&mut self, start: usize, quote: Quote) -> bool {
+        let closing = quote.char.to_string().repeat(quote.count) + &"#".repeat(quote.hashes);
+        let closed = loop {
+            if quote.escapes && self.eat("\\") {
+                self.bump();
+            } else if self.eat(&closing) {
+                break true;
+            } else if self.peek().is_none() {
+                break false;
+            } else {
+                self.bump();
+            }
+        };
+        self.push(start, Token::String);
+        closed
+    }
+
+    /// Scans a block comment started at `start`, `depth` levels deep
+    fn block_comment(&mut self, start: usize, mut depth: u32, nests: bool) -> LineState {
+        while depth > 0 && self.peek().is_some() {
+            if self.eat("*/") {
+                depth -= 1;
+            } else if nests && self.eat("/*") {
+                depth += 1;
+            } else {
+                self.bump();
+            }
+        }
+        self.push(start, Token::Comment);
+        if depth == 0 {
+            LineState::Normal
+        } else {
+            LineState::Comment(depth)
+        }
+    }
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Rust and GLSL, whose comments, numbers and identifiers look alike
+fn c_like(s: &mut Scanner, language: Language, state: LineState) -> LineState {
+    let rust = language == Language::Rust;
+    let state = match state {
+        LineState::Comment(depth) => s.block_comment(0, depth, rust),
+        LineState::String(quote) if !s.string(0, quote) => state,
+        _ => LineState::Normal,
+    };
+    if state != LineState::Normal {
+        return state;
+    }
+    while let Some(c) = s.peek() {
+        let start = s.pos;
+        if s.eat("//") {
+            s.skip_line();
+            s.push(start, Token::Comment);
+        } else if s.eat("/*") {
+            let state = s.block_comment(start, 1, rust);
+            if state != LineState::Normal {
+                return state;
+            }
+        } else if let Some(quote) = rust.then(|| rust_string_start(s)).flatten() {
+            // Rust strings can go on for several lines
+            if !s.string(start, quote) {
+                return LineState::String(quote);
+            }
+        } else if rust && c == '\'' {
+            rust_char_or_lifetime(s);
+        } else if rust && (s.rest().starts_with("#[") || s.rest().starts_with("#![")) {
+            // Attributes, up to their closing bracket
+            let mut depth = 0;
+            while let Some(c) = s.peek() {
+                s.bump();
+                match c {
+                    '[' => depth += 1,
+                    ']' if depth == 1 => break,
+                    ']' => depth -= 1,
+                    _ => (),
+                }
+            }
+            s.push(start, Token::Macro);
+        } else if !rust && c == '#' && s.line[..start].trim().is_empty() {
+            // Preprocessor directives, up to a comment
+            while s.peek().is_some() && !s.rest().starts_with("//") {
+                s.bump();
+            }
+            s.push(start, Token::Macro);
+        } else if c.is_ascii_digit() {
+            s.eat_while(is_ident);
+            let fraction = s.rest().strip_prefix('.');
+            if fraction.is_some_and(|f| f.starts_with(|c: char| c.is_ascii_digit())) {
+                s.bump();
+                s.eat_while(is_ident);
+            }
+            s.push(start, Token::Number);
+        } else if is_ident(c) {
+            s.eat_while(is_ident);
+            let word = &s.line[start..s.pos];
+            let token = if rust && s.rest().starts_with('!') && !s.rest().starts_with("!=") {
+                s.bump();
+                Some(Token::Macro)
+            } else {
+                classify(language, word, s.rest())
+            };
+            if let Some(token) = token {
+                s.push(start, token);
+            }
+        } else {
+            s.bump();
+        }
+    }
+    LineState::Normal
+}
+
+/// Token of an identifier of Rust or GLSL, followed by `rest`
+fn classify(language: Language, word: &str, rest: &str) -> Option<Token> {
+    let (keywords, types) = match language {
+        Language::Rust => (RUST_KEYWORDS, RUST_TYPES),
+        _ => (GLSL_KEYWORDS, GLSL_TYPES),
+    };
+    let glsl_type = |word: &str| {
+        let vector = word.trim_start_matches(['i', 'u', 'b', 'd']);
+        let matrix = word.trim_start_matches('d').strip_prefix("mat");
+        ["vec2", "vec3", "vec4"].contains(&vector)
+            || matrix.is_some_and(|size| {
+                !size.is_empty() && size.chars().all(|c| matches!(c, '2'..='4' | 'x'))
+            })
+            || word.trim_start_matches(['i', 'u']).starts_with("sampler")
+    };
+    let upper = word.chars().any(|c| c.is_uppercase());
+    let lower = word.chars().any(|c| c.is_lowercase());
+    let listed = |words: &str| words.split_whitespace().any(|listed| listed == word);
+    if listed(keywords) {
+        Some(Token::Keyword)
+    } else if listed(types) || (language == Language::Glsl && glsl_type(word)) {
+        Some(Token::Type)
+    } else if ["true", "false"].contains(&word)
+        || (upper && !lower && word.len() > 1)
+        || word.starts_with("gl_")
+    {
+        Some(Token::Constant)
+    } else if word.starts_with(char::is_uppercase) {
+        Some(Token::Type)
+    } else if rest.trim_start().starts_with('(') {
+        Some(Token::Function)
+    } else {
+        None
+    }
+}
+
+/// Scans the prefix and opening quote of a Rust string, like `b"` or `r#"`
+fn rust_string_start(s: &mut Scanner) -> Option<Quote> {
+    let rest = s.rest();
+    let unprefixed = rest.strip_prefix(['b', 'c']).unwrap_or(rest);
+    let (quote, len) = if let Some(raw) = unprefixed.strip_prefix('r') {
+        let hashes = raw.len() - raw.trim_start_matches('#').len();
+        if !raw[hashes..].starts_with('"') {
+            return None;
+        }
+        let quote = Quote {
+            hashes,
+            escapes: false,
+            ..Quote::plain('"')
+        };
+        (quote, rest.len() - raw.len() + hashes + 1)
+    } else if unprefixed.starts_with('"') {
+        (Quote::plain('"'), rest.len() - unprefixed.len() + 1)
+    } else {
+        return None;
+    };
+    s.pos += len;
+    Some(quote)
+}
+
+/// Scans `'a'` as a char, and `'a` as a lifetime
+fn rust_char_or_lifetime(s: &mut Scanner) {
+    let start = s.pos;
+    let mut chars = s.rest().chars().skip(1);
+    let is_char = matches!(
+        (chars.next(), chars.next()),
+        (Some('\\'), _) | (Some(_), Some('\''))
+    );
+    s.bump();
+    if is_char {
+        s.eat("\\");
+        s.bump();
+        s.eat_while(|c| c != '\'');
+        s.bump();
+        s.push(start, Token::String);
+    } else {
+        s.eat_while(is_ident);
+        s.push(start, Token::Type);
+    }
+}
+
+fn toml(s: &mut Scanner, state: LineState) -> LineState {
+    if let LineState::String(quote) = state {
+        if !s.string(0, quote) {
+            return state;
+        }
+    } else {
+        s.eat_while(char::is_whitespace);
+        if s.peek() == Some('[') {
+            let start = s.pos;
+            s.eat_while(|c| c != ']');
+            s.eat_while(|c| c == ']');
+            s.push(start, Token::Heading);
+        }
+    }
+    while let Some(c) = s.peek() {
+        let start = s.pos;
+        if c == '#' {
+            s.skip_line();
+            s.push(start, Token::Comment);
+        } else if c == '"' || c == '\'' {
+            let triple = s.rest().starts_with(&c.to_string().repeat(3));
+            let quote = Quote {
+                count: if triple { 3 } else { 1 },
+                escapes: c == '"',
+                ..Quote::plain(c)
+            };
+            s.pos += quote.count;
+            // Only multi-line strings carry on to the next line
+            if !s.string(start, quote) && triple {
+                return LineState::String(quote);
+            }
+        } else if c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '+') {
+            // Bare keys, numbers and dates
+            s.eat_while(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '+' | ':' | '.'));
+            let word = &s.line[start..s.pos];
+            let after = s.rest().trim_start();
+            let token = if after.starts_with('=') {
+                Some(Token::Property)
+            } else if word == "true" || word == "false" {
+                Some(Token::Constant)
+            } else if word.starts_with(|c: char| c.is_ascii_digit() || c == '+' || c == '-')
+                || ["inf", "nan"].contains(&word)
+            {
+                Some(Token::Number)
+            } else {
+                None
+            };
+            if let Some(token) = token {
+                s.push(start, token);
+            }
+        } else {
+            s.bump();
+        }
+    }
+    LineState::Normal
+}
+
+/// Fence of a Markdown code block, three or more backticks or tildes
+fn code_fence(line: &str) -> Option<(char, usize)> {
+    let fence = line.chars().next().filter(|&c| c == '`' || c == '~')?;
+    let len = line.len() - line.trim_start_matches(fence).len();
+    (len >= 3).then_some((fence, len))
+}
+
+fn markdown(s: &mut Scanner, state: LineState) -> LineState {
+    let trimmed = s.line.trim_start();
+    let indent = s.line.len() - trimmed.len();
+    if let LineState::CodeBlock { fence, len } = state {
+        s.skip_line();
+        s.push(0, Token::String);
+        let closes = code_fence(trimmed).is_some_and(|(c, n)| {
+            c == fence && n >= len && trimmed.trim_end_matches(c).trim().is_empty()
+        });
+        return if closes { LineState::Normal } else { state };
+    }
+    if let Some((fence, len)) = code_fence(trimmed) {
+        s.skip_line();
+        s.push(0, Token::String);
+        return LineState::CodeBlock { fence, len };
+    }
+    let hashes = trimmed.len() - trimmed.trim_start_matches('#').len();
+    let heading = (1..=6).contains(&hashes)
+        && (trimmed[hashes..].is_empty() || trimmed[hashes..].starts_with(' '));
+    let compact: String = trimmed.chars().filter(|c| !c.is_whitespace()).collect();
+    let thematic_break = compact.len() >= 3
+        && ["-", "*", "_"]
+            .iter()
+            .any(|c| compact.trim_start_matches(c).is_empty());
+    let line_token = if heading {
+        Some(Token::Heading)
+    } else if trimmed.starts_with('>') {
+        Some(Token::Comment)
+    } else if thematic_break {
+        Some(Token::Keyword)
+    } else {
+        None
+    };
+    if let Some(token) = line_token {
+        s.skip_line();
+        s.push(0, token);
+        return LineState::Normal;
+    }
+
+    // List markers
+    s.pos = indent;
+    let numbered = trimmed.trim_start_matches(|c: char| c.is_ascii_digit());
+    let marker = if trimmed.starts_with(['-', '*', '+']) {
+        1
+    } else if numbered.len() < trimmed.len() && numbered.starts_with(['.', ')']) {
+        trimmed.len() - numbered.len() + 1
+    } else {
+        0
+    };
+    if marker > 0 && trimmed[marker..].starts_with(' ') {
+        s.pos += marker;
+        s.push(indent, Token::Keyword);
+    }
+
+    while let Some(c) = s.peek() {
+        let start = s.pos;
+        let rest = s.rest();
+        match c {
+            '\\' => {
+                s.bump();
+                s.bump();
+            }
+            '`' | '*' | '_' => {
+                let run = rest.len() - rest.trim_start_matches(c).len();
+                let delimiter = &rest[..run];
+                s.pos += run;
+                let intraword = c == '_' && s.line[..start].ends_with(is_ident);
+                let after = s.rest();
+                let end = after.find(delimiter).filter(|&end| end > 0);
+                match end {
+                    Some(end) if c == '`' || !(intraword || after.starts_with(' ')) => {
+                        s.pos += end + run;
+                        let token = if c == '`' {
+                            Token::String
+                        } else {
+                            Token::Emphasis
+                        };
+                        s.push(start, token);
+                    }
+                    _ => (),
+                }
+            }
+            '[' | '!' => {
+                let open = if rest.starts_with("![") { 2 } else { 1 };
+                let link = rest[open..].find(']').and_then(|close| {
+                    let target = &rest[open + close + 1..];
+                    let paren = target.strip_prefix('(')?.find(')')?;
+                    Some(open + close + paren + 3)
+                });
+                match link {
+                    Some(len) if c == '[' || open == 2 => {
+                        s.pos += len;
+                        s.push(start, Token::Link);
+                    }
+                    _ => s.bump(),
+                }
+            }
+            '<' if rest[1..].starts_with("http") || rest[1..].starts_with("mailto:") => {
+                match rest.find('>') {
+                    Some(end) => {
+                        s.pos += end + 1;
+                        s.push(start, Token::Link);
+                    }
+                    None => s.bump(),
+                }
+            }
+            _ => s.bump(),
+        }
+    }
+    LineState::Normal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Text and token of each span of `lines`, tokenized one after the
+    /// other as lines of a file
+    fn tokens<'a>(language: Language, lines: &[&'a str]) -> Vec<Vec<(&'a str, Token)>> {
+        let mut state = LineState::Normal;
+        lines
+            .iter()
+            .map(|line| {
+                let (spans, end) = tokenize(language, state, line);
+                state = end;
+                spans
+                    .into_iter()
+                    .map(|span| (&line[span.range], span.token))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rust() {
+        use Token::*;
+        assert_eq!(
+            tokens(
+                Language::Rust,
+                &["#[derive(Debug)] pub fn f(x: &'a str) -> u8 {"]
+            ),
+            [vec![
+                ("#[derive(Debug)]", Macro),
+                ("pub", Keyword),
+                ("fn", Keyword),
+                ("f", Function),
+                ("'a", Type),
+                ("str", Type),
+                ("u8", Type),
+            ]]
+        );
+        assert_eq!(
+            tokens(
+                Language::Rust,
+                &[
+                    "let c = '\\'' + 1.5e3 != MAX; // é",
+                    "println!(r#\"a\"# \"b\\\"\")"
+                ]
+            ),
+            [
+                vec![
+                    ("let", Keyword),
+                    ("'\\''", String),
+                    ("1.5e3", Number),
+                    ("MAX", Constant),
+                    ("// é", Comment),
+                ],
+                vec![
+                    ("println!", Macro),
+                    ("r#\"a\"#", String),
+                    ("\"b\\\"\"", String),
+                ],
+            ]
+        );
+        // Nested block comments and strings go on for several lines
+        assert_eq!(
+            tokens(
+                Language::Rust,
+                &["/* a /* b */", "c */ x", "Some(\"one", "two\") y"]
+            ),
+            [
+                vec![("/* a /* b */", Comment)],
+                vec![("c */", Comment)],
+                vec![("Some", Type), ("\"one", String)],
+                vec![("two\"", String)],
+            ]
+        );
+    }
+
+    #[test]
+    fn glsl() {
+        use Token::*;
+        assert_eq!(
+            tokens(
+                Language::Glsl,
+                &[
+                    "  #version 330 // core",
+                    "uniform mat4x3 m; in vec2 uv; out uvec4 gl_Out;",
+                    "/* a /* b */ float f = texture(s, uv).r;",
+                ]
+            ),
+            [
+                vec![("#version 330 ", Macro), ("// core", Comment)],
+                vec![
+                    ("uniform", Keyword),
+                    ("mat4x3", Type),
+                    ("in", Keyword),
+                    ("vec2", Type),
+                    ("out", Keyword),
+                    ("uvec4", Type),
+                    ("gl_Out", Constant),
+                ],
+                vec![
+                    ("/* a /* b */", Comment),
+                    ("float", Type),
+                    ("texture", Function),
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn toml() {
+        use Token::*;
+        assert_eq!(
+            tokens(
+                Language::Toml,
+                &[
+                    "[[bin]] # binaries",
+                    "name = 'sedit' # \"quoted\"",
+                    "a.b = [true, -1_000, 1979-05-27T07:32:00Z, nan]",
+                    "doc = \"\"\"one",
+                    "two\"\"\" x = 1",
+                ]
+            ),
+            [
+                vec![("[[bin]]", Heading), ("# binaries", Comment)],
+                vec![
+                    ("name", Property),
+                    ("'sedit'", String),
+                    ("# \"quoted\"", Comment)
+                ],
+                vec![
+                    ("a.b", Property),
+                    ("true", Constant),
+                    ("-1_000", Number),
+                    ("1979-05-27T07:32:00Z", Number),
+                    ("nan", Number),
+                ],
+                vec![("doc", Property), ("\"\"\"one", String)],
+                vec![("two\"\"\"", String), ("x", Property), ("1", Number)],
+            ]
+        );
+    }
+
+    #[test]
+    fn markdown() {
+        use Token::*;
+        assert_eq!(
+            tokens(
+                Language::Markdown,
+                &[
+                    "## Title",
+                    "1. *a* snake_case_word `co*de` [link](url) <https://x>",
+                    "  - item \\*not\\*",
+                    "> quote",
+                    "* * *",
+                    "````rust",
+                    "```",
+                    "````",
+                    "__b__",
+                ]
+            ),
+            [
+                vec![("## Title", Heading)],
+                vec![
+                    ("1.", Keyword),
+                    ("*a*", Emphasis),
+                    ("`co*de`", String),
+                    ("[link](url)", Link),
+                    ("<https://x>", Link),
+                ],
+                vec![("-", Keyword)],
+                vec![("> quote", Comment)],
+                vec![("* * *", Keyword)],
+                vec![("````rust", String)],
+                vec![("```", String)],
+                vec![("````", String)],
+                vec![("__b__", Emphasis)],
+            ]
+        );
+    }
+
+    #[test]
+    fn common_ends_of_edits() {
+        let ends = |old: &str, new: &str| common_ends(&Rope::from(old), &Rope::from(new));
+        assert_eq!(ends("abcXdef", "abcYYdef"), (3, 3));
+        assert_eq!(ends("abc", "abc"), (3, 0));
+        assert_eq!(ends("", "abc"), (0, 0));
+        // The ends don't overlap, even when both could take the same bytes
+        assert_eq!(ends("aa", "aaa"), (2, 0));
+        assert_eq!(ends("abab", "ab"), (2, 0));
+
+        let lines = |old: &str, new: &str| changed_lines(&Rope::from(old), &Rope::from(new));
+        assert_eq!(lines("a\nb\nc", "a\nx\nc"), (1, 1));
+        assert_eq!(lines("a\nb\nc", "a\nb\nc"), (2, 0));
+        assert_eq!(lines("a\nb", "a\nb\nc"), (1, 0));
+        assert_eq!(lines("x\na\nb", "a\nb"), (0, 1));
+        // The ends don't overlap, so the added blank line leaves `b` out
+        assert_eq!(lines("a\n\nb", "a\n\n\nb"), (2, 0));
+    }
+
+    #[test]
+    fn common_ends_of_long_texts() {
+        let old = Rope::from("lorem ipsum\n".repeat(5000).as_str());
+        let mut new = old.clone();
+        new.insert(30_000, "é\nx");
+        new.delete(40_003..40_013);
+        assert_eq!(common_ends(&old, &new), (30_000, old.len_bytes() - 40_009));
+        assert_eq!(common_ends(&new, &old), (30_000, old.len_bytes() - 40_009));
+        assert_eq!(changed_lines(&old, &new), (2500, 1666));
+        assert_eq!(common_ends(&old, &old.clone()), (old.len_bytes(), 0));
+    }
+
+    #[test]
+    fn update_retokenizes_changed_lines() {
+        let syntax = SyntaxCache::default();
+        let mut highlight = SyntaxHighlight::default();
+        let text = Rope::from("a = 1\nb = \"\"\"\nc = 3\n\"\"\"\nd = 2");
+        highlight.update(Some(Language::Toml), &text, &syntax);
+        assert_eq!(highlight.spans(2)[0].token, Token::String);
+        assert_eq!(highlight.spans(4)[0].token, Token::Property);
+
+        // Closing the string early changes the lines after it
+        let mut edited = text.clone();
+        edited.insert(13, "\"\"\"");
+        highlight.update(Some(Language::Toml), &edited, &syntax);
+        assert_eq!(highlight.spans(1).len(), 2);
+        assert_eq!(highlight.spans(2)[0].token, Token::Property);
+        assert_eq!(highlight.spans(3)[0].token, Token::String);
+        assert_eq!(
+            highlight.spans(4),
+            [Span {
+                range: 0..5,
+                token: Token::String
+            }]
+        );
+
+        highlight.update(None, &edited, &syntax);
+        assert_eq!(highlight.spans(0), []);
+    }
+}
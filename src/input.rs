@@ -0,0 +1,473 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::File,
+    io::{self, LineWriter, Write},
+    path::Path,
+    time::Instant,
+};
+
+use sdl2::{
+    clipboard::ClipboardUtil,
+    event::Event,
+    keyboard::{Keycode, Mod},
+};
+use serde::Deserialize;
+
+/// Input the editor reacts to, whether it comes from the window or a script
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    /// Text typed, after the keyboard layout was applied
+    Text(String),
+    /// A key pressed, with the modifiers held at the time. Keys that type
+    /// text also come as `Text`.
+    Key(Keycode, Mod),
+    /// The window is being closed
+    Quit,
+    /// Keys typed so far waited too long for the rest of a mapping
+    Timeout,
+}
+
+impl Input {
+    pub fn from_event(event: Event) -> Option<Self> {
+        match event {
+            Event::Quit { .. } => Some(Input::Quit),
+            Event::TextInput { text, .. } => Some(Input::Text(text)),
+            Event::KeyDown {
+                keycode: Some(key),
+                keymod,
+                ..
+            } => Some(Input::Key(key, keymod)),
+            _ => None,
+        }
+    }
+
+    /// Whether the key also comes as `Text`, which is what mappings look at
+    pub fn types_text(&self) -> bool {
+        match self {
+            Input::Key(key, keymod) => {
+                let code = *key as i32;
+                !normalize_mod(*keymod).intersects(COMMAND_MODS)
+                    && u8::try_from(code).is_ok_and(|c| c == b' ' || c.is_ascii_graphic())
+            }
+            _ => false,
+        }
+    }
+
+    /// The same input with modifiers as scripts write them, so that it can
+    /// be compared with parsed ones
+    pub fn normalized(self) -> Self {
+        match self {
+            Input::Key(key, keymod) => Input::Key(key, normalize_mod(keymod)),
+            input => input,
+        }
+    }
+}
+
+/// Shows inputs as in recordings: `text` and the text, in the notation of
+/// scripts, `key` and the key in `<>`, `quit` or `timeout`
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Input::Text(text) => write!(f, "text {}", ScriptText(text)),
+            Input::Key(key, keymod) => {
+                f.write_str("key <")?;
+                for (modifier, prefix) in MODIFIERS {
+                    if keymod.intersects(modifier) {
+                        f.write_str(prefix)?;
+                    }
+                }
+                let code = *key as i32;
+                match KEY_NAMES.iter().find(|(_, named)| named == key) {
+                    Some((name, _)) => f.write_str(name)?,
+                    None => match char::from_u32(code as u32).filter(char::is_ascii_alphanumeric) {
+                        Some(c) => write!(f, "{c}")?,
+                        None => write!(f, "Key-{code}")?,
+                    },
+                }
+                f.write_str(">")
+            }
+            Input::Quit => f.write_str("quit"),
+            Input::Timeout => f.write_str("timeout"),
+        }
+    }
+}
+
+/// Shows text in the notation of scripts, on a single line
+struct ScriptText<'a>(&'a str);
+
+impl fmt::Display for ScriptText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '<' => f.write_str("<lt>")?,
+                // Would be invisible at the end of lines
+                ' ' => f.write_str("<Space>")?,
+                c if c.is_control() => write!(f, "<Char-{}>", c as u32)?,
+                c => write!(f, "{c}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A key pressed with modifiers, the bindings set in `[keys]` of the config
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct KeyBind {
+    key: Keycode,
+    /// As `normalize_mod` gives them
+    modifiers: Mod,
+}
+
+/// Modifiers that make keys not type text, as `normalize_mod` gives them
+const COMMAND_MODS: Mod = Mod::LCTRLMOD.union(Mod::LALTMOD).union(Mod::LGUIMOD);
+
+/// Either Shift key
+pub const SHIFT_MOD: Mod = Mod::LSHIFTMOD.union(Mod::RSHIFTMOD);
+
+impl KeyBind {
+    pub const fn new(key: Keycode, modifiers: Mod) -> Self {
+        Self { key, modifiers }
+    }
+    pub const fn ctrl(key: Keycode) -> Self {
+        Self::new(key, Mod::LCTRLMOD)
+    }
+}
+
+/// Parses keys written like in scripts, `<C-r>` or `<A-S-f>`
+impl TryFrom<String> for KeyBind {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, String> {
+        let key = name
+            .strip_prefix('<')
+            .and_then(|name| name.strip_suffix('>'))
+            .and_then(|name| parse_key(name, false));
+        let Some(Input::Key(key, modifiers)) = key else {
+            return Err(format!("invalid key `{name}`, expected one like `<C-r>`"));
+        };
+        // Others would get in the way of typing
+        if !modifiers.intersects(COMMAND_MODS) {
+            return Err(format!("`{name}` needs Ctrl, Alt or Super"));
+        }
+        Ok(Self::new(key, modifiers))
+    }
+}
+
+impl PartialEq<KeyBind> for Input {
+    fn eq(&self, keybind: &KeyBind) -> bool {
+        match self {
+            Input::Key(key, keymod) => {
+                key == &keybind.key && normalize_mod(*keymod) == keybind.modifiers
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Keeps the modifiers that can be written in `<>`, as the left ones, like
+/// `parse_key` gives them
+fn normalize_mod(keymod: Mod) -> Mod {
+    let left = Mod::LCTRLMOD | Mod::LSHIFTMOD | Mod::LALTMOD | Mod::LGUIMOD;
+    MODIFIERS
+        .iter()
+        .filter(|(both, _)| keymod.intersects(*both))
+        .fold(Mod::NOMOD, |normalized, (both, _)| {
+            normalized | (*both & left)
+        })
+}
+
+/// Modifiers as written in `<>`, either side of the keyboard
+const MODIFIERS: [(Mod, &str); 4] = [
+    (Mod::LCTRLMOD.union(Mod::RCTRLMOD), "C-"),
+    (Mod::LSHIFTMOD.union(Mod::RSHIFTMOD), "S-"),
+    (Mod::LALTMOD.union(Mod::RALTMOD), "A-"),
+    (Mod::LGUIMOD.union(Mod::RGUIMOD), "D-"),
+];
+
+/// Names of the keys in `<>`, as Vim writes them
+const KEY_NAMES: &[(&str, Keycode)] = &[
+    ("Esc", Keycode::Escape),
+    ("CR", Keycode::Return),
+    ("Enter", Keycode::Return),
+    ("Return", Keycode::Return),
+    ("BS", Keycode::Backspace),
+    ("Tab", Keycode::Tab),
+    ("Del", Keycode::Delete),
+    ("Up", Keycode::Up),
+    ("Down", Keycode::Down),
+    ("Left", Keycode::Left),
+    ("Right", Keycode::Right),
+    ("Home", Keycode::Home),
+    ("End", Keycode::End),
+    ("PageUp", Keycode::PageUp),
+    ("PageDown", Keycode::PageDown),
+];
+
+/// Names in `<>` that stand for typed text, for chars that are awkward in
+/// scripts
+const TEXT_NAMES: &[(&str, &str)] = &[("lt", "<"), ("Space", " "), ("Bar", "|"), ("Bslash", "\\")];
+
+/// First line of the files written by `--record`
+const RECORDING_HEADER: &str = "saphedit recording 1";
+
+/// What `--replay` feeds to the editor
+#[derive(Default)]
+pub struct Replay {
+    pub inputs: Vec<Input>,
+    /// Gives back what the clipboard held when the recorded session read it
+    pub clipboard: LocalClipboard,
+}
+
+/// Parses the keys given to `--replay`, either a script or a recording
+pub fn parse_keys(text: &str) -> Result<Replay, String> {
+    match text.strip_prefix(RECORDING_HEADER) {
+        Some(lines) => parse_recording(lines),
+        None => Ok(Replay {
+            inputs: parse_script(text),
+            ..Replay::default()
+        }),
+    }
+}
+
+/// A line of a recording
+enum Recorded {
+    Input(Input),
+    /// The editor read the clipboard, which held this
+    Clipboard(String),
+}
+
+/// Parses the lines after the header of a recording. Each has the time in
+/// milliseconds since the recording started, which is only there for
+/// people to read, and an input as `Input` displays it, or `clipboard` and
+/// the text the clipboard held when it was read.
+fn parse_recording(lines: &str) -> Result<Replay, String> {
+    let parse_line = |line: &str| {
+        let (_time, input) = line.split_once(' ')?;
+        let (kind, arg) = input.split_once(' ').unwrap_or((input, ""));
+        let input = match kind {
+            "text" => Input::Text(parse_text(arg)?),
+            "key" => {
+                let name = arg.strip_prefix('<')?.strip_suffix('>')?;
+                parse_key(name, true).filter(|input| matches!(input, Input::Key(..)))?
+            }
+            "quit" => Input::Quit,
+            "timeout" => Input::Timeout,
+            "clipboard" => return Some(Recorded::Clipboard(parse_text(arg)?)),
+            _ => return None,
+        };
+        Some(Recorded::Input(input))
+    };
+
+    let mut replay = Replay::default();
+    // The first line is what's left of the header
+    for (idx, line) in lines
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+    {
+        match parse_line(line).ok_or(format!("Line {}: invalid input", idx + 1))? {
+            Recorded::Input(input) => replay.inputs.push(input),
+            Recorded::Clipboard(text) => replay.clipboard.recorded.push_back(text),
+        }
+    }
+    Ok(replay)
+}
+
+/// Parses text in the notation of scripts, which can't have keys
+fn parse_text(script: &str) -> Option<String> {
+    let mut text = String::new();
+    for input in parse_script(script) {
+        let Input::Text(typed) = input else {
+            return None;
+        };
+        text.push_str(&typed);
+    }
+    Some(text)
+}
+
+/// Parses a keystroke script in Vim's notation, like `ihello<Esc>:w<CR>`:
+/// chars are typed as text, line breaks press Return, and keys are named in
+/// `<>`, with `C-`, `S-`, `A-` and `D-` for Ctrl, Shift, Alt and Super.
+/// `<Char-N>` types the char with code N, and `<Key-N>` presses the key SDL
+/// numbers N. Anything in `<>` that isn't a key is typed as it is, like in
+/// Vim.
+pub fn parse_script(script: &str) -> Vec<Input> {
+    let mut inputs = Vec::new();
+    let mut rest = script;
+    while let Some(c) = rest.chars().next() {
+        let key = rest
+            .strip_prefix('<')
+            .and_then(|after| after.split_once('>'))
+            .and_then(|(name, after)| Some((parse_key(name, false)?, after)));
+        if let Some((input, after)) = key {
+            inputs.push(input);
+            rest = after;
+            continue;
+        }
+        inputs.push(match c {
+            '\n' => Input::Key(Keycode::Return, Mod::NOMOD),
+            c => Input::Text(c.into()),
+        });
+        rest = &rest[c.len_utf8()..];
+    }
+    inputs
+}
+
+/// Parses what's between `<` and `>`. Single chars are only keys with a
+/// modifier, or if `plain_chars`.
+fn parse_key(name: &str, plain_chars: bool) -> Option<Input> {
+    let mut modifiers = Mod::NOMOD;
+    let mut name = name;
+    // The last char is the key even if it's a `-`, as in `<C-->`
+    while let Some((modifier, key)) = name.split_at_checked(2).filter(|(_, key)| !key.is_empty()) {
+        modifiers |= match modifier.to_ascii_uppercase().as_str() {
+            "C-" => Mod::LCTRLMOD,
+            "S-" => Mod::LSHIFTMOD,
+            "A-" | "M-" => Mod::LALTMOD,
+            "D-" => Mod::LGUIMOD,
+            _ => break,
+        };
+        name = key;
+    }
+
+    if let Some(text) = find_name(TEXT_NAMES, name) {
+        if modifiers.is_empty() {
+            return Some(Input::Text(text.into()));
+        }
+        return Some(Input::Key(char_key(text.chars().next()?)?, modifiers));
+    }
+    if let Some(key) = find_name(KEY_NAMES, name) {
+        return Some(Input::Key(key, modifiers));
+    }
+    if let Some(code) = name.strip_prefix("Char-") {
+        let c = char::from_u32(code.parse().ok()?)?;
+        return modifiers.is_empty().then(|| Input::Text(c.into()));
+    }
+    if let Some(code) = name.strip_prefix("Key-") {
+        return Some(Input::Key(
+            Keycode::from_i32(code.parse().ok()?)?,
+            modifiers,
+        ));
+    }
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if plain_chars || !modifiers.is_empty() => {
+            Some(Input::Key(char_key(c)?, modifiers))
+        }
+        _ => None,
+    }
+}
+
+/// Names are case insensitive, as in Vim
+fn find_name<T: Copy>(names: &[(&str, T)], name: &str) -> Option<T> {
+    names
+        .iter()
+        .find(|(key_name, _)| key_name.eq_ignore_ascii_case(name))
+        .map(|&(_, value)| value)
+}
+
+/// Key typing `c` on a US layout. SDL numbers those keys with their
+/// lowercase ASCII char.
+fn char_key(c: char) -> Option<Keycode> {
+    match c {
+        ' ' => Some(Keycode::Space),
+        c if c.is_ascii_graphic() => Keycode::from_i32(c.to_ascii_lowercase() as i32),
+        _ => None,
+    }
+}
+
+/// Writes every input to a file as it comes, for `--replay`
+pub struct Recorder {
+    // Written line by line, so that the inputs leading to a crash are kept
+    out: LineWriter<File>,
+    start: Instant,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut out = LineWriter::new(File::create(path)?);
+        writeln!(out, "{RECORDING_HEADER}")?;
+        Ok(Self {
+            out,
+            start: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, input: &Input) -> io::Result<()> {
+        writeln!(self.out, "{} {input}", self.start.elapsed().as_millis())
+    }
+
+    /// Writes what the clipboard held when the editor read it
+    pub fn record_clipboard(&mut self, text: &str) -> io::Result<()> {
+        let time = self.start.elapsed().as_millis();
+        writeln!(self.out, "{time} clipboard {}", ScriptText(text))
+    }
+}
+
+/// Where `"+`, Ctrl-C and Ctrl-V copy to and paste from
+pub trait Clipboard {
+    fn text(&mut self) -> Result<String, String>;
+    fn set_text(&mut self, text: &str) -> Result<(), String>;
+}
+
+impl Clipboard for ClipboardUtil {
+    fn text(&mut self) -> Result<String, String> {
+        self.clipboard_text()
+    }
+
+    fn set_text(&mut self, text: &str) -> Result<(), String> {
+        self.set_clipboard_text(text)
+    }
+}
+
+/// Clipboard that only lives as long as the editor, for runs without a
+/// window
+#[derive(Default)]
+pub struct LocalClipboard {
+    text: String,
+    /// What the system clipboard held each time a recorded session read it,
+    /// read again in the same order
+    recorded: VecDeque<String>,
+}
+
+impl Clipboard for LocalClipboard {
+    fn text(&mut self) -> Result<String, String> {
+        if let Some(text) = self.recorded.pop_front() {
+            self.text = text;
+        }
+        Ok(self.text.clone())
+    }
+
+    fn set_text(&mut self, text: &str) -> Result<(), String> {
+        self.text = text.into();
+        Ok(())
+    }
+}
+
+/// Clipboard that writes what is read from it to `recorder`, so that replays
+/// paste the same
+pub struct RecordedClipboard<'a, C> {
+    pub clipboard: &'a mut C,
+    pub recorder: &'a mut Option<Recorder>,
+}
+
+impl<C: Clipboard> Clipboard for RecordedClipboard<'_, C> {
+    fn text(&mut self) -> Result<String, String> {
+        let text = self.clipboard.text()?;
+        if let Some(Err(e)) = self
+            .recorder
+            .as_mut()
+            .map(|recorder| recorder.record_clipboard(&text))
+        {
+            eprintln!("Stopped recording: {e}");
+            *self.recorder = None;
+        }
+        Ok(text)
+    }
+
+    fn set_text(&mut self, text: &str) -> Result<(), String> {
+        self.clipboard.set_text(text)
+    }
+}
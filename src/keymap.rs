@@ -0,0 +1,445 @@
+use std::rc::Rc;
+
+use crate::{
+    input::{self, Input},
+    EditorMode,
+};
+
+/// How many times mappings can expand to other mappings, before giving up
+/// on a recursive one
+pub const MAX_DEPTH: usize = 1000;
+
+/// Modes mappings apply in, as `:nmap`, `:vmap`, `:omap`, `:imap` and `:cmap`
+/// name them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapMode {
+    Normal,
+    Visual,
+    OperatorPending,
+    Insert,
+    Command,
+}
+
+impl MapMode {
+    /// Modes of `:map`
+    const MAP: &'static [MapMode] = &[MapMode::Normal, MapMode::Visual, MapMode::OperatorPending];
+    /// Modes of `:map!`
+    const MAP_BANG: &'static [MapMode] = &[MapMode::Insert, MapMode::Command];
+
+    /// Mode whose mappings apply in `mode`, if any do
+    pub fn of(mode: &EditorMode) -> Option<Self> {
+        match mode {
+            EditorMode::Normal => Some(MapMode::Normal),
+            EditorMode::Visual { .. } => Some(MapMode::Visual),
+            EditorMode::OperatorPending { .. } => Some(MapMode::OperatorPending),
+            EditorMode::Insert => Some(MapMode::Insert),
+            EditorMode::Command(_) => Some(MapMode::Command),
+            EditorMode::Confirm(_) => None,
+        }
+    }
+
+    /// Letter mappings are listed with, and that prefixes the commands
+    fn letter(self) -> char {
+        match self {
+            MapMode::Normal => 'n',
+            MapMode::Visual => 'v',
+            MapMode::OperatorPending => 'o',
+            MapMode::Insert => 'i',
+            MapMode::Command => 'c',
+        }
+    }
+
+    fn idx(self) -> usize {
+        self as usize
+    }
+}
+
+/// What a `:map` family command does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapCommand {
+    /// `:map` and `:noremap`, adding or listing mappings
+    Map { remap: bool },
+    /// `:unmap`
+    Unmap,
+}
+
+/// Recognises `:map`, `:noremap` and `:unmap`, alone or with a mode letter
+/// in front, like `:nnoremap`. `!` makes the ones without a letter apply to
+/// insert and command mode.
+pub fn parse_command(name: &str, bang: bool) -> Option<(MapCommand, &'static [MapMode])> {
+    let command = |name| match name {
+        "map" => Some(MapCommand::Map { remap: true }),
+        "noremap" => Some(MapCommand::Map { remap: false }),
+        "unmap" => Some(MapCommand::Unmap),
+        _ => None,
+    };
+    if let Some(command) = command(name) {
+        let modes = if bang {
+            MapMode::MAP_BANG
+        } else {
+            MapMode::MAP
+        };
+        return Some((command, modes));
+    }
+    let (letter, name) = name.split_at_checked(1)?;
+    let modes: &[_] = match letter {
+        "n" => &[MapMode::Normal],
+        "v" | "x" => &[MapMode::Visual],
+        "o" => &[MapMode::OperatorPending],
+        "i" => &[MapMode::Insert],
+        "c" => &[MapMode::Command],
+        _ => return None,
+    };
+    Some((command(name)?, modes))
+}
+
+/// Keys typed as `lhs` are replaced with `rhs`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Mapping {
+    lhs: Vec<Input>,
+    rhs: Vec<Input>,
+    /// Whether the keys of `rhs` can be mapped in turn
+    remap: bool,
+    /// `lhs` and `rhs` as they were written, for listings
+    lhs_text: String,
+    rhs_text: String,
+}
+
+/// What typed keys are mapped to
+pub enum Lookup<'a> {
+    /// The keys start a mapping, and more are needed to tell which
+    Pending,
+    /// The first `len` keys are mapped to `rhs`
+    Mapped {
+        len: usize,
+        rhs: &'a [Input],
+        remap: bool,
+    },
+    /// The first key isn't mapped
+    Unmapped,
+}
+
+/// Mappings of every mode, in one list per mode
+type ModeMappings = Rc<[Vec<Mapping>; 5]>;
+
+/// Mappings of every mode. Those of the config are kept apart from those
+/// made with `:map`, as they are made again when it's reloaded.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Keymaps {
+    config: ModeMappings,
+    /// Made with `:map` and the like, which win over the config's
+    commands: ModeMappings,
+}
+
+impl Keymaps {
+    pub fn is_empty(&self) -> bool {
+        self.config
+            .iter()
+            .chain(self.commands.iter())
+            .all(Vec::is_empty)
+    }
+
+    /// Maps `lhs` to `rhs` in `modes`, both in the notation of scripts, with
+    /// `<leader>` standing for `leader`. `<Nop>` maps to nothing.
+    pub fn add(
+        &mut self,
+        modes: &[MapMode],
+        lhs: &str,
+        rhs: &str,
+        remap: bool,
+        leader: &str,
+    ) -> Result<(), String> {
+        add_mapping(&mut self.commands, modes, lhs, rhs, remap, leader)
+    }
+
+    /// Same as `add`, for a mapping of the config
+    pub fn add_from_config(
+        &mut self,
+        modes: &[MapMode],
+        lhs: &str,
+        rhs: &str,
+        remap: bool,
+        leader: &str,
+    ) -> Result<(), String> {
+        add_mapping(&mut self.config, modes, lhs, rhs, remap, leader)
+    }
+
+    /// Removes the mappings of the config, before it's read again
+    pub fn clear_config(&mut self) {
+        self.config = ModeMappings::default();
+    }
+
+    /// Mappings in effect in `mode`
+    fn mappings(&self, mode: MapMode) -> impl Iterator<Item = &Mapping> {
+        let commands = &self.commands[mode.idx()];
+        let config = self.config[mode.idx()]
+            .iter()
+            .filter(|mapping| commands.iter().all(|command| command.lhs != mapping.lhs));
+        config.chain(commands)
+    }
+
+    /// Removes the mappings of `lhs` in `modes`
+    pub fn remove(&mut self, modes: &[MapMode], lhs: &str, leader: &str) -> Result<(), String> {
+        let lhs = parse_keys(&expand_leader(lhs, leader));
+        let found = modes
+            .iter()
+            .any(|&mode| self.mappings(mode).any(|old| old.lhs == lhs));
+        if !found {
+            return Err("E31: No such mapping".into());
+        }
+        // Mappings of the config come back when it's reloaded
+        for all in [&mut self.config, &mut self.commands] {
+            let all = Rc::make_mut(all);
+            for mode in modes {
+                all[mode.idx()].retain(|old| old.lhs != lhs);
+            }
+        }
+        Ok(())
+    }
+
+    /// Lines listing the mappings of `modes` whose keys start with `lhs`,
+    /// like `:map` shows them: the mode, the keys, and what they're mapped
+    /// to, after a `*` if it isn't remapped
+    pub fn list(&self, modes: &[MapMode], lhs: &str, leader: &str) -> Vec<String> {
+        let lhs = parse_keys(&expand_leader(lhs, leader));
+        let mut lines = Vec::new();
+        for mode in modes {
+            for mapping in self.mappings(*mode) {
+                if !mapping.lhs.starts_with(&lhs) {
+                    continue;
+                }
+                let star = if mapping.remap { ' ' } else { '*' };
+                lines.push(format!(
+                    "{}  {:<12} {star} {}",
+                    mode.letter(),
+                    mapping.lhs_text,
+                    mapping.rhs_text
+                ));
+            }
+        }
+        lines
+    }
+
+    /// What `keys`, typed in `mode`, are mapped to. Unless `complete`, keys
+    /// that could start a longer mapping wait for the next ones.
+    pub fn lookup(&self, mode: MapMode, keys: &[Input], complete: bool) -> Lookup<'_> {
+        let longer =
+            |mapping: &Mapping| mapping.lhs.len() > keys.len() && mapping.lhs.starts_with(keys);
+        if !complete && self.mappings(mode).any(longer) {
+            return Lookup::Pending;
+        }
+        self.mappings(mode)
+            .filter(|mapping| keys.starts_with(&mapping.lhs))
+            .max_by_key(|mapping| mapping.lhs.len())
+            .map_or(Lookup::Unmapped, |mapping| Lookup::Mapped {
+                len: mapping.lhs.len(),
+                rhs: &mapping.rhs,
+                remap: mapping.remap,
+            })
+    }
+}
+
+/// Maps `lhs` to `rhs` in the `modes` of `all`, as `Keymaps::add` does
+fn add_mapping(
+    all: &mut ModeMappings,
+    modes: &[MapMode],
+    lhs: &str,
+    rhs: &str,
+    remap: bool,
+    leader: &str,
+) -> Result<(), String> {
+    let lhs_text = expand_leader(lhs, leader);
+    let rhs_text = expand_leader(rhs, leader);
+    let lhs = parse_keys(&lhs_text);
+    if lhs.is_empty() {
+        return Err("E474: Invalid argument".into());
+    }
+    let rhs = if rhs_text.eq_ignore_ascii_case("<Nop>") {
+        Vec::new()
+    } else {
+        parse_keys(&rhs_text)
+    };
+    let mapping = Mapping {
+        lhs,
+        rhs,
+        remap,
+        lhs_text,
+        rhs_text,
+    };
+    let all = Rc::make_mut(all);
+    for mode in modes {
+        let mappings = &mut all[mode.idx()];
+        mappings.retain(|old| old.lhs != mapping.lhs);
+        mappings.push(mapping.clone());
+    }
+    Ok(())
+}
+
+/// Keys of a script, one char of text each, as they get looked up
+pub fn parse_keys(script: &str) -> Vec<Input> {
+    input::parse_script(script)
+        .into_iter()
+        .flat_map(split_text)
+        .collect()
+}
+
+/// Splits text into its chars, mappings being made of single keys
+pub fn split_text(input: Input) -> Vec<Input> {
+    match input {
+        Input::Text(text) => text.chars().map(|c| Input::Text(c.into())).collect(),
+        input => vec![input.normalized()],
+    }
+}
+
+/// Replaces `<leader>`, in any case, with `leader`
+fn expand_leader(keys: &str, leader: &str) -> String {
+    let mut expanded = String::new();
+    let mut rest = keys;
+    while let Some(idx) = rest.to_ascii_lowercase().find("<leader>") {
+        expanded.push_str(&rest[..idx]);
+        expanded.push_str(leader);
+        rest = &rest[idx + "<leader>".len()..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keymaps with the config's mappings and then the commands', in normal
+    /// mode and without remapping, `,` being the leader
+    fn normal_maps(config: &[(&str, &str)], commands: &[(&str, &str)]) -> Keymaps {
+        let mut keymaps = Keymaps::default();
+        for (lhs, rhs) in config {
+            keymaps
+                .add_from_config(&[MapMode::Normal], lhs, rhs, false, ",")
+                .unwrap();
+        }
+        for (lhs, rhs) in commands {
+            keymaps
+                .add(&[MapMode::Normal], lhs, rhs, false, ",")
+                .unwrap();
+        }
+        keymaps
+    }
+
+    /// How many keys of `keys` are mapped in normal mode and to what,
+    /// `None` if the first one isn't mapped
+    fn mapped(keymaps: &Keymaps, keys: &str, complete: bool) -> Option<(usize, Vec<Input>)> {
+        match keymaps.lookup(MapMode::Normal, &parse_keys(keys), complete) {
+            Lookup::Pending => panic!("{keys} waits for more keys"),
+            Lookup::Mapped { len, rhs, .. } => Some((len, rhs.to_vec())),
+            Lookup::Unmapped => None,
+        }
+    }
+
+    fn is_pending(keymaps: &Keymaps, keys: &str) -> bool {
+        let keys = parse_keys(keys);
+        matches!(
+            keymaps.lookup(MapMode::Normal, &keys, false),
+            Lookup::Pending
+        )
+    }
+
+    #[test]
+    fn lookup() {
+        let keymaps = normal_maps(
+            &[("j", "gj"), ("<leader>w", ":w<CR>"), ("jk", "<Esc>")],
+            &[],
+        );
+        assert!(is_pending(&keymaps, "j"));
+        assert!(is_pending(&keymaps, ","));
+        assert_eq!(mapped(&keymaps, "j", true), Some((1, parse_keys("gj"))));
+        assert_eq!(
+            mapped(&keymaps, "jk", false),
+            Some((2, parse_keys("<Esc>")))
+        );
+        // The longest mapping the keys start with wins, the rest is typed
+        assert_eq!(mapped(&keymaps, "jx", false), Some((1, parse_keys("gj"))));
+        assert_eq!(
+            mapped(&keymaps, ",wq", false),
+            Some((2, parse_keys(":w<CR>")))
+        );
+        assert_eq!(mapped(&keymaps, ",", true), None);
+        assert_eq!(mapped(&keymaps, "x", false), None);
+        assert!(matches!(
+            keymaps.lookup(MapMode::Insert, &parse_keys("j"), false),
+            Lookup::Unmapped
+        ));
+
+        let keymaps = normal_maps(&[], &[("Q", "<nop>")]);
+        assert_eq!(mapped(&keymaps, "Q", false), Some((1, Vec::new())));
+    }
+
+    #[test]
+    fn commands_win_over_config() {
+        let mut keymaps = normal_maps(&[("x", "a"), ("y", "b")], &[("x", "c")]);
+        assert_eq!(mapped(&keymaps, "x", false), Some((1, parse_keys("c"))));
+        assert_eq!(
+            keymaps.list(&[MapMode::Normal], "", ","),
+            ["n  y            * b", "n  x            * c"]
+        );
+
+        // Reloading the config keeps the commands' mappings
+        keymaps.clear_config();
+        assert!(!keymaps.is_empty());
+        assert_eq!(mapped(&keymaps, "y", false), None);
+        keymaps
+            .add_from_config(&[MapMode::Normal], "x", "d", true, ",")
+            .unwrap();
+        assert_eq!(mapped(&keymaps, "x", false), Some((1, parse_keys("c"))));
+
+        // Unmapping removes both, until the config is read again
+        keymaps.remove(MapMode::MAP, "x", ",").unwrap();
+        assert!(keymaps.is_empty());
+        assert_eq!(
+            keymaps.remove(MapMode::MAP, "x", ","),
+            Err("E31: No such mapping".into())
+        );
+    }
+
+    #[test]
+    fn list() {
+        let mut keymaps = normal_maps(&[], &[("<Leader>a", "A"), ("<C-x>", "x")]);
+        keymaps
+            .add(MapMode::MAP_BANG, "jk", "<Esc>", true, ",")
+            .unwrap();
+        assert_eq!(
+            keymaps.list(MapMode::MAP, ",", ","),
+            ["n  ,a           * A"]
+        );
+        assert_eq!(
+            keymaps.list(MapMode::MAP_BANG, "", ","),
+            ["i  jk             <Esc>", "c  jk             <Esc>"]
+        );
+        assert_eq!(
+            keymaps.add(&[MapMode::Normal], "", "x", true, ","),
+            Err("E474: Invalid argument".into())
+        );
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(
+            parse_command("noremap", false),
+            Some((MapCommand::Map { remap: false }, MapMode::MAP))
+        );
+        assert_eq!(
+            parse_command("map", true),
+            Some((MapCommand::Map { remap: true }, MapMode::MAP_BANG))
+        );
+        assert_eq!(
+            parse_command("xunmap", false),
+            Some((MapCommand::Unmap, &[MapMode::Visual][..]))
+        );
+        assert_eq!(
+            parse_command("inoremap", false),
+            Some((MapCommand::Map { remap: false }, &[MapMode::Insert][..]))
+        );
+        assert_eq!(parse_command("nmapx", false), None);
+        assert_eq!(parse_command("umap", false), None);
+        assert_eq!(parse_command("", false), None);
+    }
+}
@@ -1,16 +1,16 @@
 extern crate sdl2;
 
 use atlas::GlyphAtlas;
-use gl::types::GLfloat;
-use sdl2::clipboard::ClipboardUtil;
-use sdl2::event::Event;
-use sdl2::keyboard::{Keycode, Mod};
-use sdl2::EventPump;
-use shader::Shader;
+use sdl2::keyboard::Keycode;
 
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
 use std::mem::replace;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::time::{Duration, Instant};
-use std::{iter, ptr};
+use std::{env, iter, process};
 
 /*  To Do
    To do eventually
@@ -19,12 +19,38 @@ use std::{iter, ptr};
 */
 
 mod atlas;
+mod cmdline;
 mod config;
+mod ex;
+mod highlight;
+mod input;
+mod keymap;
+mod motion;
+mod operator;
+mod register;
+mod render;
 mod rope;
+mod search;
 mod shader;
-use config::*;
-
+mod syntax;
+mod theme;
+mod undo;
 use crate::atlas::Font;
+use crate::cmdline::{CommandLine, History};
+use crate::config::{Config, ConfigWatcher};
+use crate::ex::Substitution;
+use crate::highlight::{Language, SyntaxHighlight};
+use crate::input::{Clipboard, Input, RecordedClipboard, Recorder, Replay, SHIFT_MOD};
+use crate::keymap::{Keymaps, Lookup, MapCommand, MapMode};
+use crate::motion::{FindKind, Motion, Parsed};
+use crate::operator::{Operator, Selection, TextObject};
+use crate::register::Registers;
+use crate::render::{CpuRenderer, GlRenderer, Renderer};
+use crate::rope::Rope;
+use crate::search::{FlatText, Search, SearchHighlight};
+use crate::syntax::{SyntaxCache, SyntaxTree};
+use crate::theme::Theme;
+use crate::undo::UndoTree;
 
 macro_rules! log_err {
     ($e:expr) => {
@@ -41,14 +67,115 @@ fn round_to_scale(value: f32, scale: f32) -> f32 {
 }
 
 pub fn main() {
+    let mut logic_state = LogicState::new(Rope::new());
+
+    let mut screenshot = None;
+    let mut replay_script = None;
+    let mut record = None;
+    let mut config_arg = None;
+    let mut paths = Vec::new();
+    let mut args = env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        let option = match arg.to_str() {
+            Some(option @ ("--screenshot" | "--replay" | "--record" | "--config")) => option,
+            _ => {
+                paths.push(PathBuf::from(arg));
+                continue;
+            }
+        };
+        let Some(value) = args.next().map(PathBuf::from) else {
+            eprintln!("{option} needs a file");
+            process::exit(1);
+        };
+        match option {
+            "--screenshot" => screenshot = Some(value),
+            "--replay" => replay_script = Some(value),
+            "--config" => config_arg = Some(value),
+            _ => record = Some(value),
+        }
+    }
+    let mut paths = paths.into_iter();
+    if let Some(path) = paths.next() {
+        edit_file(&mut logic_state, path);
+    }
+    for ignored in paths {
+        eprintln!("Only one file can be edited at a time, ignoring {ignored:?}");
+    }
+
+    let headless = screenshot.is_some() || replay_script.is_some();
+    // A config given with `--config` is used with or without a window, so
+    // that sessions recorded with it replay the same
+    let config_path = config_arg.or_else(|| config::config_path().filter(|_| !headless));
+    match config_path.as_deref().map(Config::read) {
+        Some(Ok(config)) => apply_config(&mut logic_state, config),
+        // Falls back to the defaults
+        Some(Err(e)) => {
+            eprintln!("{e}");
+            logic_state.message = Some(e);
+        }
+        None => {}
+    }
+
+    if let Some(script) = replay_script {
+        let script = match fs::read_to_string(&script) {
+            Ok(script) => script,
+            Err(e) => {
+                eprintln!("Couldn't read {}: {e}", script.display());
+                process::exit(1);
+            }
+        };
+        if let Err(e) = replay(&mut logic_state, &script) {
+            eprintln!("Couldn't replay the keys: {e}");
+            process::exit(1);
+        }
+        if let Some(message) = &logic_state.message {
+            eprintln!("{message}");
+        }
+        log_err!(dump_state(&logic_state, io::stdout().lock()));
+    }
+
+    let mut atlas = GlyphAtlas::new();
+    atlas.set_mode(logic_state.config.glyphs);
+    if let Some(font) = logic_state.font {
+        atlas.change_font(font);
+    }
+    if let Some(out) = screenshot {
+        if let Err(e) = write_screenshot(&out, &mut atlas, &logic_state) {
+            eprintln!("Couldn't write {}: {e}", out.display());
+            process::exit(1);
+        }
+    }
+    if headless {
+        if record.is_some() {
+            eprintln!("Only sessions with a window are recorded, ignoring --record");
+        }
+        return;
+    }
+    let mut recorder = record.map(|path| match Recorder::create(&path) {
+        Ok(recorder) => recorder,
+        Err(e) => {
+            eprintln!("Couldn't record to {}: {e}", path.display());
+            process::exit(1);
+        }
+    });
+    logic_state.command_history = History::load();
+    let mut config_watcher = config_path.and_then(|path| match ConfigWatcher::new(path) {
+        Ok(watcher) => Some(watcher),
+        Err(e) => {
+            eprintln!("Couldn't watch the config file: {e}");
+            None
+        }
+    });
+
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     video_subsystem.text_input().start();
 
-    let clipboard = video_subsystem.clipboard();
+    let mut clipboard = video_subsystem.clipboard();
 
+    let (width, height) = logic_state.config.window_size;
     let mut window = video_subsystem
-        .window("", 800, 600)
+        .window("", width, height)
         .opengl()
         .position_centered()
         .resizable()
@@ -62,119 +189,154 @@ pub fn main() {
 
     let mut event_pump = sdl_context.event_pump().unwrap();
 
-    // setup blending
-    unsafe {
-        gl::Enable(gl::BLEND);
-        gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
-    };
-
-    let vbo = unsafe {
-        let mut vbo = 0;
-        gl::GenBuffers(1, &mut vbo);
-        check_err();
-        vbo
-    };
-
-    let text_shader = Shader::text_shader(vbo);
-    let shape_shader = Shader::shape_shader(vbo);
+    // Safety: the context was just made current, and is dropped last
+    let mut renderer = unsafe { GlRenderer::new() };
 
     let mut screen_size = window.drawable_size();
-    // Buffer for the text edited on screen. Lines are \n terminated
-    let mut atlas = GlyphAtlas::new(&text_shader);
     let mut last_recorded_frame = 0;
 
-    let mut logic_state = LogicState {
-        exit: false,
-        text_buffer: String::new(),
-        cursor_col: 0,
-        font: None,
-        cursor_row: 0,
-        line_count: 0,
-        mode: EditorMode::Normal,
-    };
-
     let mut gfx_state = GraphicsState {
-        camera_scale: MAX_SCALE,
+        camera_scale: logic_state.config.max_scale,
         cursor_visible: false,
-        center_y: CENTER_OFFSET,
+        center_y: logic_state.config.center_offset,
     };
 
-    let mut scale_animation = TimeInterpolator::new(gfx_state.camera_scale, SCALE_ANIM_TIME);
-    let mut scroll_animation = TimeInterpolator::new(gfx_state.center_y, SCROLL_ANIM_TIME);
+    let mut scale_animation =
+        TimeInterpolator::new(gfx_state.camera_scale, logic_state.config.scale_anim_time);
+    let mut scroll_animation =
+        TimeInterpolator::new(gfx_state.center_y, logic_state.config.scroll_anim_time);
+    let mut search_highlight = SearchHighlight::default();
+    let mut syntax_highlight = SyntaxHighlight::default();
 
     let run_timer = Instant::now();
+    let mut last_input = Instant::now();
     let mut frame_timer = Instant::now();
+    let mut fps = 0.;
+    window
+        .set_title(&window_title(&logic_state, fps, &atlas))
+        .expect("String has no null bytes");
     'running: for frame_counter in 0.. {
         // fps tracking
         if frame_timer.elapsed().as_secs_f32() >= 0.5 {
             let elapsed_frames = frame_counter - last_recorded_frame;
-            let fps = elapsed_frames as f32 / frame_timer.elapsed().as_secs_f32();
+            fps = elapsed_frames as f32 / frame_timer.elapsed().as_secs_f32();
             window
-                .set_title(&format!("Saphedit, fps={fps:.0}"))
+                .set_title(&window_title(&logic_state, fps, &atlas))
                 .expect("String has no null bytes");
             last_recorded_frame = frame_counter;
             frame_timer = Instant::now();
         }
 
-        let new_state = match &logic_state.mode {
-            EditorMode::Insert => handle_events_insert(&mut event_pump, &logic_state, &clipboard),
-            EditorMode::Normal => handle_events_normal(&mut event_pump, &logic_state),
-            EditorMode::Command(cmd) => handle_command_input(&mut event_pump, &logic_state, cmd),
-        };
+        let mut new_state = logic_state.clone();
+        // Timeouts go first, as the keys that came since haven't been waited for
+        let timed_out =
+            !new_state.typed_keys.is_empty() && last_input.elapsed() >= new_state.config.timeout;
+        let events = event_pump.poll_iter().filter_map(Input::from_event);
+        for input in timed_out
+            .then_some(Input::Timeout)
+            .into_iter()
+            .chain(events)
+        {
+            if input != Input::Timeout {
+                last_input = Instant::now();
+            }
+            if let Some(Err(e)) = recorder.as_mut().map(|recorder| recorder.record(&input)) {
+                eprintln!("Stopped recording: {e}");
+                recorder = None;
+            }
+            let mut clipboard = RecordedClipboard {
+                clipboard: &mut clipboard,
+                recorder: &mut recorder,
+            };
+            handle_input(&mut new_state, input, &mut clipboard);
+        }
+
+        if let Some(watcher) = &mut config_watcher {
+            match watcher.changed() {
+                Ok(false) => {}
+                Ok(true) => match Config::read(watcher.path()) {
+                    Ok(config) => {
+                        new_state.message = Some("Config reloaded".into());
+                        apply_config(&mut new_state, config);
+                    }
+                    // The previous values stay in effect
+                    Err(e) => new_state.message = Some(e),
+                },
+                Err(e) => {
+                    eprintln!("Stopped watching the config file: {e}");
+                    config_watcher = None;
+                }
+            }
+        }
 
         if new_state.exit {
             break 'running;
         }
 
         if new_state.font != logic_state.font {
-            if let Some(ft) = new_state.font {
-                atlas.change_font(ft)
-            }
+            atlas.change_font(new_state.font.unwrap_or(Font::DEFAULT));
         }
+        atlas.set_mode(new_state.config.glyphs);
 
         // Make sure to invalidate `new_state` as soon as possible to avoid
         // accidentally using the wrong state
         let logic_state_updated = logic_state != new_state;
         let row_moved = logic_state.cursor_row != new_state.cursor_row;
+        let config_changed = logic_state.config != new_state.config;
+        let title_changed = logic_state.modified != new_state.modified
+            || logic_state.file_path != new_state.file_path;
         logic_state = new_state;
 
+        if logic_state_updated {
+            search_highlight.update(
+                logic_state.highlighted_search(),
+                &logic_state.text_buffer,
+                &logic_state.flat_text,
+            );
+        }
+        // Also done before anything changed, for the file opened at startup
+        syntax_highlight.update(
+            logic_state.language(),
+            &logic_state.text_buffer,
+            &logic_state.syntax,
+        );
+
+        if title_changed {
+            window
+                .set_title(&window_title(&logic_state, fps, &atlas))
+                .expect("String has no null bytes");
+        }
+
         // Update screen size
         let new_screen_size = window.drawable_size();
         let resize = new_screen_size != screen_size;
         screen_size = new_screen_size;
 
+        // Animation timings come from the config
+        if config_changed {
+            scale_animation.duration = logic_state.config.scale_anim_time;
+            scroll_animation.duration = logic_state.config.scroll_anim_time;
+        }
+
         // Update text size / update scale
         if logic_state_updated || resize {
-            let (text_w, text_h) = logic_state
-                .text_buffer
-                .split('\n')
-                .map(|line| atlas.measure_dims(line.chars()))
-                .reduce(|(w1, h1), (w2, h2)| (w1.max(w2), h1.max(h2)))
-                .unwrap_or((1., 1.));
-            let scale_x = new_screen_size.0 as f32 / (text_w + 2. * MARGIN);
-            let scale_y = new_screen_size.1 as f32 / text_h;
-            // TODO: do a better estimate of the size; the issue here is that
-            // the theoretical scale depends on the text size, which can change
-            // from one scale to another
-            let new_scale_raw = scale_x.min(scale_y).clamp(8., MAX_SCALE);
-            let step = GlyphAtlas::SCALE_STEP;
-            let new_scale_rounded = (new_scale_raw / step).floor() * step;
-            scale_animation.reset(new_scale_rounded);
+            scale_animation.reset(fitting_scale(&logic_state, &atlas, new_screen_size));
         }
 
         let camera_scale = scale_animation.interpolated_value();
 
         atlas.select_scale(camera_scale, 1);
+        // So that the animation doesn't stall once it gets there
+        atlas.prepare_scale(scale_animation.end_value, logic_state.text_buffer.chars());
 
         // Cursor update
-        let time_period = (run_timer.elapsed().as_secs_f32() / BLINK_TIME.as_secs_f32()) as u32;
+        let time_period = (run_timer.elapsed().as_secs_f32()
+            / logic_state.config.blink_time.as_secs_f32()) as u32;
         let cursor_visible = time_period % 2 == 0;
 
         // Scroll update
-        if row_moved {
-            let y_center_new_target =
-                logic_state.cursor_row as f32 * atlas.line_height() + CENTER_OFFSET;
-            scroll_animation.reset(y_center_new_target);
+        if row_moved || config_changed {
+            scroll_animation.reset(cursor_center_y(&logic_state, &mut atlas));
         }
 
         let center_y_raw = scroll_animation.interpolated_value();
@@ -197,319 +359,1338 @@ pub fn main() {
             continue;
         }
 
-        unsafe {
-            let (width, height) = screen_size;
-            gl::Viewport(0, 0, width as i32, height as i32);
-            gl::ClearColor(0.2, 0.3, 0.3, 1.);
-            gl::Clear(gl::COLOR_BUFFER_BIT);
-
-            text_shader.r#use();
-
-            let color_black: [GLfloat; 4] = [0., 0., 0., 1.];
-            text_shader.uniform4vf("color", color_black);
-            text_shader.uniform1f("scale", camera_scale);
-            text_shader.uniform2i("screenSize", [width as i32, height as i32]);
-            text_shader.uniform1f("yCenter", gfx_state.center_y);
-
-            // Rendering logic put into separate functions to alleviate nesting
-            let x_start = round_to_scale(MARGIN, camera_scale);
-            let cursor_coords = render_text(&logic_state, &mut atlas, x_start, 0., &text_shader);
-
-            shape_shader.r#use();
-            shape_shader.uniform1f("scale", camera_scale);
-            shape_shader.uniform2i("screenSize", [width as i32, height as i32]);
-            shape_shader.uniform1f("yCenter", gfx_state.center_y);
-            render_cursor(
-                &shape_shader,
-                cursor_coords,
-                atlas.ascender(),
-                atlas.descender(),
-                cursor_visible,
-                &logic_state,
-            );
+        render_frame(
+            &mut renderer,
+            &mut atlas,
+            screen_size,
+            &gfx_state,
+            &logic_state,
+            search_highlight.matches(),
+            &syntax_highlight,
+        );
+        window.gl_swap_window();
+    }
+}
 
-            render_footer(
-                &shape_shader,
-                &text_shader,
-                &mut atlas,
-                (width, height),
-                &logic_state,
-            );
+/// Scale at which the whole text fits on a screen of `screen_size`, within
+/// the limits
+fn fitting_scale(state: &LogicState, atlas: &GlyphAtlas, screen_size: (u32, u32)) -> f32 {
+    let (text_w, text_h) = state
+        .text_buffer
+        .lines()
+        .enumerate()
+        .map(|(row, line)| {
+            let label = line_number_label(state, row).unwrap_or_default();
+            atlas.measure_dims(label.chars().chain(line.chars()))
+        })
+        .reduce(|(w1, h1), (w2, h2)| (w1.max(w2), h1.max(h2)))
+        .unwrap_or((1., 1.));
+    let scale_x = screen_size.0 as f32 / (text_w + 2. * state.config.margin);
+    let scale_y = screen_size.1 as f32 / text_h;
+    // TODO: do a better estimate of the size; the issue here is that
+    // the theoretical scale depends on the text size, which can change
+    // from one scale to another
+    let new_scale_raw = scale_x
+        .min(scale_y)
+        .clamp(Config::MIN_SCALE, state.config.max_scale);
+    let step = GlyphAtlas::SCALE_STEP;
+    (new_scale_raw / step).floor() * step
+}
+
+/// Line number shown in front of line `row`, right-aligned and followed by
+/// a space like in Vim, if the `number` setting is on
+fn line_number_label(state: &LogicState, row: usize) -> Option<String> {
+    let width = state.text_buffer.len_lines().to_string().len().max(3);
+    state.config.number.then(|| format!("{:>width$} ", row + 1))
+}
+
+/// Y coordinate to show in the middle of the screen, to centre the cursor's line
+fn cursor_center_y(state: &LogicState, atlas: &mut GlyphAtlas) -> f32 {
+    state.cursor_row as f32 * atlas.line_height() + state.config.center_offset
+}
+
+/// Draws the text, the cursor and the footer
+fn render_frame(
+    renderer: &mut dyn Renderer,
+    atlas: &mut GlyphAtlas,
+    screen_size: (u32, u32),
+    gfx_state: &GraphicsState,
+    state: &LogicState,
+    search_matches: &[Range<usize>],
+    syntax: &SyntaxHighlight,
+) {
+    atlas.start_frame();
+    renderer.clear(screen_size, state.theme.background.0);
+    renderer.set_camera(gfx_state.camera_scale, gfx_state.center_y);
+
+    // Rendering logic put into separate functions to alleviate nesting
+    let x_start = round_to_scale(state.config.margin, gfx_state.camera_scale);
+    let cursor_coords = render_text(state, atlas, x_start, 0., search_matches, syntax, renderer);
+
+    render_cursor(
+        renderer,
+        cursor_coords,
+        atlas.ascender(),
+        atlas.descender(),
+        gfx_state.cursor_visible,
+        state,
+    );
+
+    render_footer(renderer, atlas, screen_size, state, search_matches.len());
+}
+
+/// Renders the first frame shown for `state` on the CPU, and writes it as a
+/// PPM image to `out`
+fn write_screenshot(out: &Path, atlas: &mut GlyphAtlas, state: &LogicState) -> io::Result<()> {
+    let camera_scale = fitting_scale(state, atlas, state.config.window_size);
+    atlas.select_scale(camera_scale, 1);
+    let gfx_state = GraphicsState {
+        camera_scale,
+        cursor_visible: true,
+        center_y: round_to_scale(cursor_center_y(state, atlas), camera_scale),
+    };
+    let mut search_highlight = SearchHighlight::default();
+    search_highlight.update(
+        state.highlighted_search(),
+        &state.text_buffer,
+        &state.flat_text,
+    );
+    let mut syntax_highlight = SyntaxHighlight::default();
+    syntax_highlight.update(state.language(), &state.text_buffer, &state.syntax);
+
+    let mut renderer = CpuRenderer::new();
+    render_frame(
+        &mut renderer,
+        atlas,
+        state.config.window_size,
+        &gfx_state,
+        state,
+        search_highlight.matches(),
+        &syntax_highlight,
+    );
+    renderer.write_ppm(BufWriter::new(File::create(out)?))
+}
+
+/// Updates the editor for one input, replacing the keys that are mapped.
+/// Only touches `state` and `clipboard`, plus the files that commands read
+/// and write.
+fn handle_input(state: &mut LogicState, input: Input, clipboard: &mut dyn Clipboard) {
+    match input {
+        Input::Timeout => resolve_typed_keys(state, clipboard, true),
+        // Those come as text too, which is what gets mapped
+        input if input.types_text() => dispatch_input(state, input, clipboard),
+        input if state.keymaps.is_empty() && state.typed_keys.is_empty() => {
+            dispatch_input(state, input, clipboard)
         }
-        window.gl_swap_window();
+        input => {
+            state.typed_keys.extend(keymap::split_text(input));
+            resolve_typed_keys(state, clipboard, false);
+        }
+    }
+}
+
+/// Handles the keys of `typed_keys` that aren't waiting for more of a
+/// mapping, or all of them if `timed_out`
+fn resolve_typed_keys(state: &mut LogicState, clipboard: &mut dyn Clipboard, timed_out: bool) {
+    let mut depth = 0;
+    while !state.typed_keys.is_empty() {
+        let lookup = match MapMode::of(&state.mode) {
+            Some(mode) => state.keymaps.lookup(mode, &state.typed_keys, timed_out),
+            None => Lookup::Unmapped,
+        };
+        match lookup {
+            Lookup::Pending => return,
+            Lookup::Mapped { len, rhs, remap } => {
+                depth += 1;
+                if depth > keymap::MAX_DEPTH {
+                    state.typed_keys.clear();
+                    state.message = Some("E223: Recursive mapping".into());
+                    return;
+                }
+                let rhs = rhs.to_vec();
+                let rest = state.typed_keys.split_off(len);
+                if remap {
+                    state.typed_keys = rhs;
+                    state.typed_keys.extend(rest);
+                } else {
+                    state.typed_keys = rest;
+                    for input in rhs {
+                        dispatch_input(state, input, clipboard);
+                    }
+                }
+            }
+            Lookup::Unmapped => {
+                let input = state.typed_keys.remove(0);
+                dispatch_input(state, input, clipboard);
+            }
+        }
+        if state.exit {
+            return;
+        }
+    }
+}
+
+/// Handles input in the current mode
+fn dispatch_input(state: &mut LogicState, input: Input, clipboard: &mut dyn Clipboard) {
+    match (&state.mode, input) {
+        (_, Input::Quit) => state.exit = true,
+        (EditorMode::Insert, input) => handle_insert_input(state, input, clipboard),
+        (
+            EditorMode::Normal | EditorMode::OperatorPending { .. } | EditorMode::Visual { .. },
+            input,
+        ) => handle_normal_input(state, input, clipboard),
+        (EditorMode::Command(_), input) => handle_command_input(state, input),
+        (EditorMode::Confirm(_), input) => handle_confirm_input(state, input),
     }
+    if let Some(text) = state.registers.take_clipboard() {
+        log_err!(clipboard.set_text(&text));
+    }
+}
 
-    // Cleanup
-    unsafe {
-        gl::DeleteBuffers(1, &vbo);
+/// Puts the settings of `config` in effect. Fonts are looked up here, the
+/// atlas switching to them at the next frame, and so are themes. Mappings of
+/// the config replace the ones it made before, those of `:map` are kept.
+fn apply_config(state: &mut LogicState, config: Config) {
+    if config.font != state.config.font {
+        match &config.font {
+            None => state.font = None,
+            Some(name) => match Font::find(name) {
+                Some(font) => state.font = Some(font),
+                None => state.message = Some(format!("Unknown font: {name}")),
+            },
+        }
+    }
+    // Unless `:colorscheme` switched to another one, the theme is read again
+    let switched = state.theme.name != state.config.colorscheme;
+    if !switched || config.colorscheme != state.config.colorscheme {
+        match Theme::load(&config.colorscheme) {
+            Ok(theme) => state.theme = Rc::new(theme),
+            Err(e) => state.message = Some(e),
+        }
+    }
+    let mappings = config.map.iter().map(|mapping| (mapping, true));
+    let noremappings = config.noremap.iter().map(|mapping| (mapping, false));
+    state.keymaps.clear_config();
+    for ((mode, lhs, rhs), remap) in mappings.chain(noremappings) {
+        if let Err(e) = state
+            .keymaps
+            .add_from_config(&[mode], lhs, rhs, remap, &config.leader)
+        {
+            state.message = Some(format!("Mapping {lhs}: {e}"));
+        }
+    }
+    state.config = Rc::new(config);
+}
+
+/// Feeds the keys of a script or recording to the editor, until it exits or
+/// runs out of keys
+fn replay(state: &mut LogicState, keys: &str) -> Result<(), String> {
+    let Replay {
+        inputs,
+        mut clipboard,
+    } = input::parse_keys(keys)?;
+    for input in inputs {
+        handle_input(state, input, &mut clipboard);
+        if state.exit {
+            return Ok(());
+        }
+    }
+    // Keys left waiting for a mapping are handled, as after a pause
+    if !state.typed_keys.is_empty() {
+        handle_input(state, Input::Timeout, &mut clipboard);
     }
+    Ok(())
 }
 
-fn handle_command_input<'a>(
-    event_pump: &mut EventPump,
-    old_state: &LogicState<'a>,
-    command: &str,
-) -> LogicState<'a> {
-    use Event::*;
+/// Writes the cursor position (1-based, like the ruler in Vim), the mode,
+/// then the buffer
+fn dump_state(state: &LogicState, mut out: impl Write) -> io::Result<()> {
+    writeln!(
+        out,
+        "cursor {}:{}",
+        state.cursor_row + 1,
+        state.cursor_col + 1
+    )?;
+    writeln!(out, "mode {}", state.mode.name())?;
+    write!(out, "{}", state.text_buffer)
+}
+
+fn handle_command_input(state: &mut LogicState, input: Input) {
     use Keycode::*;
-    let mut state = old_state.clone();
-    for event in event_pump.poll_iter() {
-        match event {
-            Quit { .. } => state.exit = true,
-            // Cancel command
-            KeyDown {
-                keycode: Some(Escape),
-                ..
-            } => {
-                state.mode = EditorMode::Normal;
-            }
-            // Append to the command
-            TextInput { mut text, .. } => {
-                // prepend the old command.
-                text.insert_str(0, command);
-                let mode = EditorMode::Command(text);
-                state.mode = mode;
-            }
-            KeyDown {
-                keycode: Some(Return),
-                ..
-            } => {
-                handle_command(&mut state);
+    let EditorMode::Command(command) = &mut state.mode else {
+        return;
+    };
+    match input {
+        // Cancel command
+        Input::Key(Escape, _) => state.mode = EditorMode::Normal,
+        other if other == state.config.keys.command_delete_word => command.delete_word(),
+        other if other == state.config.keys.command_delete_line => command.delete_to_start(),
+        Input::Text(text) => command.insert(&text),
+        Input::Key(keycode, keymod) => match keycode {
+            // Deleting the `:` leaves command mode
+            Backspace if !command.backspace() => state.mode = EditorMode::Normal,
+            Left => command.move_left(),
+            Right => command.move_right(),
+            Home => command.move_home(),
+            End => command.move_end(),
+            Up => command.walk_history(&state.command_history, false),
+            Down => command.walk_history(&state.command_history, true),
+            Tab => command.complete(keymod.intersects(SHIFT_MOD)),
+            Return | KpEnter => {
+                state
+                    .command_history
+                    .push(command.text(), state.config.history_size);
+                handle_command(state);
+                // `:s///c` goes on asking for confirmation
+                if let EditorMode::Command(_) = state.mode {
+                    state.mode = EditorMode::Normal;
+                }
+                state.commit_undo();
             }
             _ => (),
-        }
+        },
+        Input::Quit | Input::Timeout => (),
     }
-    state
 }
 
 fn handle_command(state: &mut LogicState) {
     let command = if let EditorMode::Command(cmd) = &state.mode {
-        cmd
+        cmd.text().to_owned()
     } else {
         return;
     };
 
-    if command == ":q" {
-        state.exit = true;
+    if let Some((pattern, backward)) = search_pattern(&command) {
+        search_command(state, pattern, backward);
+        return;
+    }
+    let command = command.strip_prefix(':').unwrap_or(&command);
+    if !command.trim().is_empty() {
+        state.registers.set_read_only(':', command.trim());
+    }
+    if let Err(e) = run_ex(state, command, false) {
+        state.message = Some(e);
     }
 }
 
-fn handle_events_normal<'a>(event_pump: &mut EventPump, old_state: &LogicState<'a>) -> LogicState<'a> {
-    use Event::*;
-    use Keycode::*;
-    let mut state = old_state.clone();
-    for event in event_pump.poll_iter() {
-        match event {
-            Quit { .. } => state.exit = true,
-            KeyDown {
-                keycode: Some(I), ..
-            } => {
-                state.mode = EditorMode::Insert;
-            }
-            KeyDown {
-                keycode: Some(Colon),
-                ..
-            } => state.mode = EditorMode::Command(String::new()),
-            TextInput { text, .. } if command_prefix(&text) => {
-                state.mode = EditorMode::Command(text)
+/// Runs a command line, without its `:`. `in_global` is set for the commands
+/// run by `:g`.
+fn run_ex(state: &mut LogicState, line: &str, in_global: bool) -> Result<(), String> {
+    let command = ex::parse(line)?;
+    let range = command.rows(state)?;
+    let rows = range
+        .clone()
+        .unwrap_or(state.cursor_row..state.cursor_row + 1);
+    let arg = command.arg.trim_end();
+    let path = (!arg.is_empty()).then(|| Path::new(arg));
+
+    let takes_range = matches!(
+        command.name,
+        "" | "s" | "substitute" | "g" | "global" | "v" | "vglobal" | "d" | "delete" | "y" | "yank"
+    );
+    if range.is_some() && !takes_range {
+        return Err("E481: No range allowed".into());
+    }
+
+    match (command.name, command.bang) {
+        // `:N` goes to line N
+        ("", _) => {
+            if range.is_some() {
+                let target = motion::first_non_blank(&state.text_buffer, rows.end - 1);
+                state.set_cursor_byte(target);
             }
-            _ => (),
         }
+        ("q" | "quit", false) if state.modified => {
+            return Err("No write since last change (add ! to override)".into());
+        }
+        ("q" | "quit", _) => state.exit = true,
+        ("w" | "write", _) => {
+            write_file(state, path, false);
+        }
+        ("wq" | "x" | "xit", _) => {
+            if write_file(state, path, false) {
+                state.exit = true;
+            }
+        }
+        ("saveas", _) => match path {
+            Some(path) => {
+                write_file(state, Some(path), true);
+            }
+            None => return Err("Argument required".into()),
+        },
+        ("e" | "edit", false) if state.modified => {
+            return Err("No write since last change (add ! to override)".into());
+        }
+        ("e" | "edit", _) => match path.map(Path::to_owned).or_else(|| state.file_path.clone()) {
+            Some(path) => edit_file(state, path),
+            None => return Err("No file name".into()),
+        },
+        ("font", _) => {
+            if arg.is_empty() {
+                let current = state.font.map_or("default".into(), |font| font.to_string());
+                state.message = Some(format!("Font: {current}"));
+                return Ok(());
+            }
+            match Font::find(arg) {
+                Some(font) => state.font = Some(font),
+                None => return Err(format!("Unknown font: {arg}")),
+            }
+        }
+        ("colo" | "colorscheme", _) => {
+            if arg.is_empty() {
+                state.message = Some(state.theme.name.clone());
+                return Ok(());
+            }
+            state.theme = Rc::new(Theme::load(arg)?);
+        }
+        ("u" | "undo", _) => match arg {
+            "" => state.undo(1),
+            seq => match seq.parse() {
+                Ok(seq) => state.undo_goto(Some(seq)),
+                Err(_) => return Err("Invalid change number".into()),
+            },
+        },
+        ("red" | "redo", _) => state.redo(1),
+        ("noh" | "nohlsearch", _) => state.highlight_search = false,
+        ("d" | "delete", _) => {
+            operator::apply(state, Operator::Delete, Selection::Lines(rows), None)
+        }
+        ("y" | "yank", _) => operator::apply(state, Operator::Yank, Selection::Lines(rows), None),
+        ("s" | "substitute", _) => {
+            let mut substitution = Substitution::parse(state, command.arg, rows)?;
+            if substitution.next_match(&state.text_buffer).is_none() {
+                let pattern = substitution.search().pattern();
+                return Err(format!("E486: Pattern not found: {pattern}"));
+            }
+            state.set_search(substitution.search().clone());
+            if substitution.confirm {
+                if in_global {
+                    return Err("Can't confirm substitutions run by :g".into());
+                }
+                state.mode = EditorMode::Confirm(substitution);
+                confirm_next(state);
+            } else {
+                while let Some((row, range)) = substitution.next_match(&state.text_buffer) {
+                    substitution.replace(state, row, range);
+                }
+                substitution.finish(state);
+            }
+        }
+        ("g" | "global" | "v" | "vglobal", bang) => {
+            if in_global {
+                return Err("E147: Cannot do :global recursive".into());
+            }
+            let invert = bang || command.name.starts_with('v');
+            let rows = range.unwrap_or(0..state.text_buffer.len_lines());
+            let (search, sub_command) = ex::parse_global(state, command.arg)?;
+            let matching = ex::global_rows(&state.text_buffer, rows, &search, invert);
+            if matching.is_empty() {
+                return Err(format!("E486: Pattern not found: {}", search.pattern()));
+            }
+            state.set_search(search);
+            // Commands add or remove lines, which moves the rows left to
+            // visit, or deletes them
+            let mut matching: Vec<_> = matching.into_iter().map(Some).collect();
+            for idx in 0..matching.len() {
+                let Some(row) = matching[idx] else {
+                    continue;
+                };
+                if row >= state.text_buffer.len_lines() {
+                    break;
+                }
+                let lines = state.text_buffer.len_lines();
+                state.cursor_row = row;
+                state.cursor_col = 0;
+                let edited = ex::parse(sub_command)?.rows(state)?;
+                let edited = edited.unwrap_or(row..row + 1);
+                run_ex(state, sub_command, true)?;
+                let added = state.text_buffer.len_lines() as isize - lines as isize;
+                if added != 0 {
+                    for later in &mut matching[idx + 1..] {
+                        *later = later.and_then(|row| ex::shift_row(row, &edited, added));
+                    }
+                }
+            }
+        }
+        (name, bang) => match keymap::parse_command(name, bang) {
+            Some((map_command, modes)) => run_map_command(state, map_command, modes, arg)?,
+            None => return Err(format!("Not an editor command: {line}")),
+        },
     }
-    state
+    Ok(())
 }
 
-fn command_prefix(text: &str) -> bool {
-    text.starts_with(':') || text.starts_with('/')
+/// Runs `:map`, `:noremap` or `:unmap` in `modes`. `:map` alone lists the
+/// mappings, and `:map {lhs}` the ones starting with `lhs`.
+fn run_map_command(
+    state: &mut LogicState,
+    command: MapCommand,
+    modes: &[MapMode],
+    arg: &str,
+) -> Result<(), String> {
+    let (lhs, rhs) = arg.split_once(char::is_whitespace).unwrap_or((arg, ""));
+    let rhs = rhs.trim_start();
+    let leader = &state.config.leader;
+    match command {
+        MapCommand::Map { .. } if rhs.is_empty() => {
+            let lines = state.keymaps.list(modes, lhs, leader);
+            if lines.is_empty() {
+                return Err("No mapping found".into());
+            }
+            state.message = Some(lines.join("\n"));
+        }
+        MapCommand::Map { remap } => state.keymaps.add(modes, lhs, rhs, remap, leader)?,
+        MapCommand::Unmap if lhs.is_empty() => return Err("E474: Invalid argument".into()),
+        MapCommand::Unmap => state.keymaps.remove(modes, lhs, leader)?,
+    }
+    Ok(())
 }
 
-fn handle_events_insert<'a>(
-    event_pump: &mut EventPump,
-    old_state: &LogicState<'a>,
-    clipboard: &ClipboardUtil,
-) -> LogicState<'a> {
-    use Event::*;
-    use Keycode::*;
-    let mut state = old_state.clone();
-    for event in event_pump.poll_iter() {
-        match event {
-            Quit { .. } => state.exit = true,
-            KeyDown {
-                keycode: Some(Escape),
-                ..
-            } => state.mode = EditorMode::Normal,
-            KeyDown {
-                keycode: Some(Left),
-                ..
-            } => {
-                if state.cursor_col == 0 {
-                    state.cursor_row = state.cursor_row.saturating_sub(1);
-                    let line_len = state
-                        .text_buffer
-                        .lines()
-                        .nth(state.cursor_row)
-                        .map(str::len);
-
-                    if let Some(line_len) = line_len {
-                        state.cursor_col = line_len;
-                    } else {
-                        state.cursor_col = 0;
-                        state.cursor_row = 0;
+/// Runs a `/` or `?` search. An empty pattern repeats the last one.
+fn search_command(state: &mut LogicState, pattern: &str, backward: bool) {
+    match ex::pattern_search(state, pattern, backward) {
+        Ok(search) => {
+            state.set_search(search);
+            state.apply_motion(Motion::SearchNext { reverse: false }, None);
+        }
+        Err(e) => state.message = Some(e),
+    }
+}
+
+/// Moves the cursor to the next match of a confirmed substitution, or ends it
+fn confirm_next(state: &mut LogicState) {
+    let EditorMode::Confirm(substitution) = &state.mode else {
+        return;
+    };
+    match substitution.next_match(&state.text_buffer) {
+        Some((row, range)) => {
+            let target = state.text_buffer.line_to_byte(row) + range.start;
+            state.set_cursor_byte(target);
+            state.message = Some("Replace this match? (y/n/a/q/l)".into());
+        }
+        None => {
+            let substitution = substitution.clone();
+            state.mode = EditorMode::Normal;
+            substitution.finish(state);
+            state.commit_undo();
+        }
+    }
+}
+
+fn handle_confirm_input(state: &mut LogicState, input: Input) {
+    let EditorMode::Confirm(substitution) = &state.mode else {
+        return;
+    };
+    let mut substitution = substitution.clone();
+    let answer = match input {
+        Input::Key(Keycode::Escape, _) => 'q',
+        Input::Text(text) => text.chars().next().unwrap_or_default(),
+        _ => return,
+    };
+    let Some((row, range)) = substitution.next_match(&state.text_buffer) else {
+        return;
+    };
+    match answer {
+        'y' => substitution.replace(state, row, range),
+        'n' => substitution.skip(row, range),
+        'a' => {
+            substitution.replace(state, row, range);
+            while let Some((row, range)) = substitution.next_match(&state.text_buffer) {
+                substitution.replace(state, row, range);
+            }
+        }
+        // Replace this last one
+        'l' => {
+            substitution.replace(state, row, range);
+            substitution.stop();
+        }
+        'q' => substitution.stop(),
+        _ => return,
+    }
+    state.mode = EditorMode::Confirm(substitution);
+    confirm_next(state);
+}
+
+/// Replaces the buffer with the contents of `path`. A file that doesn't exist
+/// yet starts out empty and gets created on the first write.
+fn edit_file(state: &mut LogicState, path: PathBuf) {
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            state.message = Some(format!("{} [New]", path.display()));
+            String::new()
+        }
+        Err(e) => {
+            state.message = Some(format!("Can't open {}: {e}", path.display()));
+            return;
+        }
+    };
+
+    if !text.is_empty() {
+        state.message = Some(format!(
+            "{} {}L, {}B",
+            path.display(),
+            text.lines().count(),
+            text.len()
+        ));
+    }
+    state.text_buffer = text.into();
+    state.history = UndoTree::new(state.text_buffer.clone());
+    state.cursor_row = 0;
+    state.cursor_col = 0;
+    state.desired_col = 0;
+    state.file_path = Some(path);
+    state.modified = false;
+}
+
+/// Writes the buffer to `path`, or to the current file if `path` is `None`.
+/// With `rename`, `path` becomes the current file once written. Returns
+/// whether the write succeeded; failures are reported in the footer.
+fn write_file(state: &mut LogicState, path: Option<&Path>, rename: bool) -> bool {
+    let path = match path.or(state.file_path.as_deref()) {
+        Some(path) => path.to_owned(),
+        None => {
+            state.message = Some("No file name".into());
+            return false;
+        }
+    };
+
+    let res = File::create(&path).and_then(|f| state.text_buffer.write_to(BufWriter::new(f)));
+    match res {
+        Ok(()) => {
+            state.message = Some(format!(
+                "\"{}\" {}L, {}B written",
+                path.display(),
+                state.text_buffer.len_lines(),
+                state.text_buffer.len_bytes()
+            ));
+            if rename || state.file_path.is_none() {
+                state.file_path = Some(path.clone());
+            }
+            if state.file_path.as_ref() == Some(&path) {
+                state.modified = false;
+                state.history.mark_saved();
+            }
+            true
+        }
+        Err(e) => {
+            state.message = Some(format!("Can't write {}: {e}", path.display()));
+            false
+        }
+    }
+}
+
+fn window_title(state: &LogicState, fps: f32, atlas: &GlyphAtlas) -> String {
+    let name = state
+        .file_path
+        .as_deref()
+        .map_or("[No Name]".into(), Path::to_string_lossy);
+    let modified = if state.modified { " [+]" } else { "" };
+    let title = format!("{name}{modified} - Saphedit, fps={fps:.0}");
+    if cfg!(debug_assertions) {
+        format!("{title}, glyph maps: {}", atlas.stats())
+    } else {
+        title
+    }
+}
+
+fn handle_normal_input(state: &mut LogicState, input: Input, clipboard: &mut dyn Clipboard) {
+    if let Some(marks) = state.visual_bounds() {
+        state.visual_marks = Some(marks);
+    }
+    match input {
+        Input::Key(Keycode::Escape, _) => {
+            state.pending_keys.clear();
+            state.mode = EditorMode::Normal;
+        }
+        other if other == state.config.keys.redo => {
+            state.pending_keys.clear();
+            state.redo(1);
+        }
+        other if other == state.config.keys.visual_block => {
+            state.pending_keys.clear();
+            state.toggle_visual(VisualKind::Block);
+        }
+        other if other == state.config.keys.copy => {
+            state.pending_keys.clear();
+            state.copy_to_clipboard();
+        }
+        Input::Text(text) if state.pending_keys.is_empty() && command_prefix(&text) => {
+            state.message = None;
+            // Commands typed in visual mode act on the selected lines
+            let text = match state.mode {
+                EditorMode::Visual { .. } if text == ":" => ":'<,'>".into(),
+                _ => text,
+            };
+            state.mode = EditorMode::Command(CommandLine::new(text))
+        }
+        Input::Text(text) => {
+            for c in text.chars() {
+                handle_normal_key(state, c);
+                // Only read the clipboard when a command is about to use it
+                if state.pending_keys.ends_with("\"+") {
+                    match clipboard.text() {
+                        Ok(text) => state.registers.load_clipboard(text),
+                        Err(e) => eprintln!("{}", e),
                     }
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Handles a key typed in normal mode. Keys are accumulated in
+/// `pending_keys` until they form a complete command.
+fn handle_normal_key(state: &mut LogicState, c: char) {
+    state.pending_keys.push(c);
+    let keys = state.pending_keys.clone();
+    let Some((register, count, keys)) = split_prefix(&keys) else {
+        state.pending_keys.clear();
+        return;
+    };
+    if keys.is_empty() {
+        return;
+    }
+
+    match state.mode {
+        EditorMode::Normal => match keys {
+            "i" => state.enter_insert(),
+            "v" => state.toggle_visual(VisualKind::Char),
+            "V" => state.toggle_visual(VisualKind::Line),
+            "p" => operator::put(state, false, count, register),
+            "P" => operator::put(state, true, count, register),
+            "u" => state.undo(count.unwrap_or(1)),
+            "g-" => state.undo_goto(state.history.seq().checked_sub(count.unwrap_or(1))),
+            "g+" => {
+                let seq = state.history.seq().saturating_add(count.unwrap_or(1));
+                state.undo_goto(Some(seq));
+            }
+            _ => {
+                let mut chars = keys.chars();
+                if let (Some(op), None) = (chars.next().and_then(Operator::from_key), chars.next())
+                {
+                    state.mode = EditorMode::OperatorPending {
+                        op,
+                        count,
+                        register,
+                    };
                 } else {
-                    state.cursor_col = state.cursor_col.saturating_sub(1);
+                    match Motion::parse(keys) {
+                        Parsed::Pending => return,
+                        Parsed::Invalid => (),
+                        Parsed::Done(motion) => state.apply_motion(motion, count),
+                    }
                 }
             }
-            KeyDown {
-                keycode: Some(Backspace),
-                ..
-            } => {
-                let removed_char = state.text_buffer.pop();
-                if removed_char.is_some() {
-                    state.cursor_col -= 1;
+        },
+        EditorMode::OperatorPending {
+            op,
+            count: op_count,
+            register: op_register,
+        } => {
+            // `2d3w` deletes 6 words
+            let count = multiply_counts(op_count, count);
+            let register = register.or(op_register);
+            let selection = if keys.len() == 1 && keys.starts_with(op.key()) {
+                // `dd`, `yy`, `>>`...: act on `count` lines
+                let last_row = state.text_buffer.len_lines() - 1;
+                let end = state
+                    .cursor_row
+                    .saturating_add(count.unwrap_or(1))
+                    .min(last_row + 1);
+                Some(Selection::Lines(state.cursor_row..end))
+            } else {
+                let parsed = match TextObject::parse(keys) {
+                    Parsed::Invalid => match Motion::parse(keys) {
+                        Parsed::Done(motion) => {
+                            Parsed::Done(state.resolve_motion(motion).and_then(|motion| {
+                                operator::motion_selection(state, op, motion, count)
+                            }))
+                        }
+                        Parsed::Pending => Parsed::Pending,
+                        Parsed::Invalid => Parsed::Invalid,
+                    },
+                    Parsed::Done(obj) => Parsed::Done(obj.selection(state)),
+                    Parsed::Pending => Parsed::Pending,
+                };
+                match parsed {
+                    Parsed::Pending => return,
+                    Parsed::Invalid => None,
+                    Parsed::Done(selection) => selection,
                 }
+            };
 
-                if removed_char == (Some('\n')) {
-                    state.line_count -= 1;
-                    // TODO: handle col
-                    state.cursor_row -= 1;
-                }
+            state.mode = EditorMode::Normal;
+            if let Some(selection) = selection {
+                operator::apply(state, op, selection, register);
             }
-            other if other == INSERT_COPY => {
-                log_err!(clipboard.set_clipboard_text(&state.text_buffer));
+        }
+        EditorMode::Visual { kind, anchor } => match keys {
+            "v" => state.toggle_visual(VisualKind::Char),
+            "V" => state.toggle_visual(VisualKind::Line),
+            "o" => {
+                // Go to the other end of the selection
+                let cursor = (state.cursor_row, state.cursor_col);
+                (state.cursor_row, state.cursor_col) = anchor;
+                state.desired_col = state.cursor_col;
+                state.mode = EditorMode::Visual {
+                    kind,
+                    anchor: cursor,
+                };
             }
-            other if other == INSERT_PASTE => match clipboard.clipboard_text() {
-                Ok(t) => {
-                    state.push_str(&t);
-                }
-                Err(e) => eprintln!("{}", e),
-            },
-            other if other == INSERT_PRINT_FONTS => {
-                let fl = Font::query();
-                for ft in fl {
-                    println!("{ft}");
+            _ => {
+                let mut chars = keys.chars();
+                let op = match (chars.next(), chars.next()) {
+                    (Some('x'), None) => Some(Operator::Delete),
+                    (Some(c), None) => Operator::from_key(c),
+                    _ => None,
+                };
+                if let Some(op) = op {
+                    let selection = state.visual_selection().expect("In visual mode");
+                    state.mode = EditorMode::Normal;
+                    operator::apply(state, op, selection, register);
+                } else {
+                    match TextObject::parse(keys) {
+                        Parsed::Pending => return,
+                        Parsed::Done(obj) => {
+                            if let Some(selection) = obj.selection(state) {
+                                state.select(selection);
+                            }
+                        }
+                        Parsed::Invalid => match Motion::parse(keys) {
+                            Parsed::Pending => return,
+                            Parsed::Invalid => (),
+                            Parsed::Done(motion) => state.apply_motion(motion, count),
+                        },
+                    }
                 }
             }
-            other if other == INSERT_CYCLE_FONTS => {
-                let mut fonts = Font::query().peekable();
-                if state.font.is_none() {
-                    state.font = fonts.next();
-                    continue;
-                }
+        },
+        _ => (),
+    }
+    state.pending_keys.clear();
+    // Insert sessions are a single change, committed when leaving insert mode
+    if state.mode == EditorMode::Normal {
+        state.commit_undo();
+    }
+}
+
+/// Splits the register (`"x`) and count in front of a normal mode command,
+/// which can come in either order. `None` if the register name is invalid.
+fn split_prefix(keys: &str) -> Option<(Option<char>, Option<usize>, &str)> {
+    let (count, mut keys) = split_count(keys);
+    let mut register = None;
+    if let Some(rest) = keys.strip_prefix('"') {
+        let mut chars = rest.chars();
+        match chars.next() {
+            Some(name) if Registers::is_valid(name) => register = Some(name),
+            Some(_) => return None,
+            // Waiting for the register name
+            None => return Some((None, count, "")),
+        }
+        keys = chars.as_str();
+    }
+    let (count2, keys) = split_count(keys);
+    Some((register, multiply_counts(count, count2), keys))
+}
+
+fn multiply_counts(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (None, None) => None,
+        (a, b) => Some(a.unwrap_or(1).saturating_mul(b.unwrap_or(1))),
+    }
+}
+
+/// Splits the count in front of a normal mode command. A leading `0` is a
+/// motion, not a count.
+fn split_count(keys: &str) -> (Option<usize>, &str) {
+    if keys.starts_with('0') {
+        return (None, keys);
+    }
+    let digits_end = keys
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(keys.len());
+    let (count, rest) = keys.split_at(digits_end);
+    (count.parse().ok(), rest)
+}
 
-                let first = fonts.peek().copied();
+fn command_prefix(text: &str) -> bool {
+    text.starts_with(':') || search_pattern(text).is_some()
+}
 
-                let new_font = fonts
-                    .skip_while(|ft| Some(*ft) != state.font)
-                    .skip(1)
-                    .next();
+/// Splits a `/pattern` or `?pattern` command into the pattern and whether it
+/// searches backward
+fn search_pattern(command: &str) -> Option<(&str, bool)> {
+    match command.strip_prefix('/') {
+        Some(pattern) => Some((pattern, false)),
+        None => command.strip_prefix('?').map(|pattern| (pattern, true)),
+    }
+}
 
-                state.font = new_font.or(first);
+fn handle_insert_input(state: &mut LogicState, input: Input, clipboard: &mut dyn Clipboard) {
+    use Keycode::*;
+    match input {
+        Input::Key(Escape, _) => {
+            // Same as vim, the cursor moves back onto the last inserted char
+            state.cursor_col = state.cursor_col.saturating_sub(1);
+            state.desired_col = state.cursor_col;
+            state.mode = EditorMode::Normal;
+            state.commit_undo();
+        }
+        Input::Key(Left, _) => {
+            if state.cursor_col == 0 {
+                if state.cursor_row > 0 {
+                    state.cursor_row -= 1;
+                    state.cursor_col = state.line_len(state.cursor_row);
+                }
+            } else {
+                state.cursor_col -= 1;
             }
-            KeyDown {
-                keycode: Some(Return),
-                ..
-            } => {
-                state.text_buffer.push('\n');
-                state.line_count += 1;
-                state.cursor_col = 0;
+            state.desired_col = state.cursor_col;
+        }
+        Input::Key(Right, _) => {
+            if state.cursor_col < state.line_len(state.cursor_row) {
+                state.cursor_col += 1;
+            } else if state.cursor_row + 1 < state.text_buffer.len_lines() {
                 state.cursor_row += 1;
+                state.cursor_col = 0;
             }
-            TextInput { text, .. } => {
-                state.push_str(&text);
+            state.desired_col = state.cursor_col;
+        }
+        Input::Key(Up, _) => state.move_cursor_vertical(-1),
+        Input::Key(Down, _) => state.move_cursor_vertical(1),
+        Input::Key(PageUp, _) => state.move_cursor_vertical(-(state.config.page_lines as isize)),
+        Input::Key(PageDown, _) => state.move_cursor_vertical(state.config.page_lines as isize),
+        Input::Key(Home, _) => {
+            state.cursor_col = 0;
+            state.desired_col = 0;
+        }
+        Input::Key(End, _) => {
+            state.cursor_col = state.line_len(state.cursor_row);
+            // Stick to the end of the line when moving vertically
+            state.desired_col = usize::MAX;
+        }
+        Input::Key(Backspace, _) => {
+            let end = state.cursor_byte();
+            if end > 0 {
+                let buffer = &state.text_buffer;
+                let start = buffer.char_to_byte(buffer.byte_to_char(end) - 1);
+                state.delete(start..end);
+                state.registers.record_backspace();
             }
-            _ => {}
         }
-    }
+        Input::Key(Delete, _) => {
+            let start = state.cursor_byte();
+            if start < state.text_buffer.len_bytes() {
+                let buffer = &state.text_buffer;
+                let end = buffer.char_to_byte(buffer.byte_to_char(start) + 1);
+                state.delete(start..end);
+            }
+        }
+        other if other == state.config.keys.copy => state.copy_to_clipboard(),
+        other if other == state.config.keys.paste => match clipboard.text() {
+            Ok(t) => {
+                state.insert_at_cursor(&t);
+            }
+            Err(e) => eprintln!("{}", e),
+        },
+        other if other == state.config.keys.print_fonts => {
+            let fl = Font::query();
+            for ft in fl {
+                println!("{ft}");
+            }
+        }
+        other if other == state.config.keys.cycle_fonts => {
+            let mut fonts = Font::query().peekable();
+            if state.font.is_none() {
+                state.font = fonts.next();
+                return;
+            }
+
+            let first = fonts.peek().copied();
+
+            let new_font = fonts
+                .skip_while(|ft| Some(*ft) != state.font)
+                .skip(1)
+                .next();
 
-    state
+            state.font = new_font.or(first);
+        }
+        Input::Key(Return, _) => {
+            state.insert_at_cursor("\n");
+        }
+        Input::Text(text) => {
+            state.insert_at_cursor(&text);
+        }
+        _ => {}
+    }
 }
 
 impl<'a> LogicState<'a> {
-    pub fn push_str(&mut self, s: &str) {
-        let new = s.replace('\r', "");
-        self.text_buffer.push_str(&new);
-        let col_displ = new
-            .lines()
-            .last()
-            .map(|line| line.chars().count())
-            .unwrap_or(0);
+    /// State editing `text` with the default settings, in normal mode
+    pub fn new(text: Rope) -> Self {
+        Self {
+            exit: false,
+            text_buffer: text.clone(),
+            cursor_col: 0,
+            font: None,
+            cursor_row: 0,
+            desired_col: 0,
+            pending_keys: String::new(),
+            last_find: None,
+            last_search: None,
+            highlight_search: false,
+            visual_marks: None,
+            registers: Registers::default(),
+            // `main` only loads it with a window, so that runs without one
+            // replay the same
+            command_history: History::default(),
+            // Same for the config, unless one is given with `--config`
+            config: Rc::default(),
+            keymaps: Keymaps::default(),
+            typed_keys: Vec::new(),
+            theme: Rc::default(),
+            history: UndoTree::new(text),
+            mode: EditorMode::Normal,
+            file_path: None,
+            syntax: SyntaxCache::default(),
+            flat_text: FlatText::default(),
+            modified: false,
+            message: None,
+        }
+    }
+
+    /// Length of line `row` in chars, without its `\n`
+    pub fn line_len(&self, row: usize) -> usize {
+        self.text_buffer.line_len_chars(row)
+    }
+
+    /// Byte offset of the cursor in the buffer
+    pub fn cursor_byte(&self) -> usize {
+        let line = self.text_buffer.line_range(self.cursor_row);
+        let line_start = self.text_buffer.byte_to_char(line.start);
+        let byte = self.text_buffer.char_to_byte(line_start + self.cursor_col);
+        byte.min(line.end)
+    }
+
+    /// Moves the cursor to the given byte offset in the buffer
+    pub fn set_cursor_byte(&mut self, byte: usize) {
+        let buffer = &self.text_buffer;
+        self.cursor_row = buffer.byte_to_line(byte);
+        let line_start = buffer.line_to_byte(self.cursor_row);
+        self.cursor_col = buffer.byte_to_char(byte) - buffer.byte_to_char(line_start);
+        self.desired_col = self.cursor_col;
+    }
+
+    /// Moves the cursor by `delta` rows, trying to stay on the desired column
+    pub fn move_cursor_vertical(&mut self, delta: isize) {
+        let last_row = self.text_buffer.len_lines() - 1;
+        self.cursor_row = self.cursor_row.saturating_add_signed(delta).min(last_row);
+        self.cursor_col = self.desired_col.min(self.line_len(self.cursor_row));
+    }
 
-        let row_displ = new.lines().count().saturating_sub(1);
-        if row_displ > 0 {
-            self.cursor_col = 0;
+    /// Moves the cursor according to `motion`, then keeps it on a char as
+    /// normal mode requires
+    pub fn apply_motion(&mut self, motion: Motion, count: Option<usize>) {
+        let motion = match self.resolve_motion(motion) {
+            Some(m) => m,
+            None => return,
+        };
+        let from = self.cursor_byte();
+        let target = motion.target(self, count);
+        match motion {
+            Motion::SearchNext { reverse } => self.report_search(from, target, reverse),
+            Motion::SearchWord { .. } => self.report_search(from, target, false),
+            _ => (),
         }
+        let target = match target {
+            Some(t) => t,
+            None => return,
+        };
 
-        self.cursor_col += col_displ;
-        self.cursor_row += row_displ;
+        let desired_col = self.desired_col;
+        self.set_cursor_byte(target);
+        if motion.is_vertical() {
+            self.desired_col = desired_col;
+        } else if motion == Motion::LineEnd {
+            self.desired_col = usize::MAX;
+        }
+        self.clamp_cursor();
     }
-}
 
-#[inline]
-// TODO: switch to the callback based system
-fn check_err() {
-    let errors: Vec<_> = iter::repeat(())
-        .map_while(|_| {
-            let res = unsafe { gl::GetError() };
-            if res == gl::NO_ERROR {
-                None
-            } else {
-                Some(res)
+    /// Remembers the finds and searches that `;`, `,`, `n` and `N` repeat, and
+    /// turns `;` and `,` into the find they repeat
+    pub fn resolve_motion(&mut self, motion: Motion) -> Option<Motion> {
+        match motion {
+            Motion::Find(kind, c) => self.last_find = Some((kind, c)),
+            Motion::SearchWord { backward } => {
+                let pos = self.cursor_byte();
+                let (search, _) = search::word_search(&self.text_buffer, pos, backward)?;
+                self.set_search(search);
             }
-        })
-        .collect();
+            _ => (),
+        }
+        motion.resolve(self.last_find)
+    }
 
-    assert!(errors.is_empty(), "Error(s) occurred: {:?}", errors);
-}
+    /// Makes `search` the one `n` and `N` repeat and the one highlighted
+    pub fn set_search(&mut self, search: Search) {
+        self.registers.set_read_only('/', search.pattern());
+        self.last_search = Some(search);
+        self.highlight_search = true;
+    }
 
-struct KeyBind {
-    key: Keycode,
-    modifier: KeyMod,
-}
+    /// Tells which match of the last search a search motion from `from` went
+    /// to, and whether it wrapped around
+    fn report_search(&mut self, from: usize, target: Option<usize>, reverse: bool) {
+        let search = match &self.last_search {
+            Some(search) => search,
+            None => {
+                self.message = Some("E35: No previous regular expression".into());
+                return;
+            }
+        };
+        let matches = search.matches(&self.flat_text.get(&self.text_buffer));
+        let found = target.and_then(|target| {
+            let idx = matches.iter().position(|m| m.start == target)?;
+            Some((idx, target))
+        });
+        let Some((idx, target)) = found else {
+            self.message = Some(format!("E486: Pattern not found: {}", search.pattern()));
+            return;
+        };
+        let backward = search.backward != reverse;
+        let note = match (backward, target) {
+            (false, target) if target <= from => " search hit BOTTOM, continuing at TOP",
+            (true, target) if target >= from => " search hit TOP, continuing at BOTTOM",
+            _ => "",
+        };
+        let prefix = if search.backward { '?' } else { '/' };
+        self.message = Some(format!(
+            "{prefix}{} [{}/{}]{note}",
+            search.pattern(),
+            idx + 1,
+            matches.len()
+        ));
+    }
 
-enum KeyMod {
-    Ctrl,
-    CtrlShift,
-}
+    /// Search whose matches are highlighted: the one being typed, or else the
+    /// last one
+    pub fn highlighted_search(&self) -> Option<Search> {
+        if let EditorMode::Command(cmd) = &self.mode {
+            if let Some((pattern, backward)) = search_pattern(cmd.text()) {
+                return Search::new(pattern, backward)
+                    .ok()
+                    .filter(|_| !pattern.is_empty());
+            }
+        }
+        self.last_search.clone().filter(|_| self.highlight_search)
+    }
+
+    /// Language the text is highlighted as, from the file name
+    pub fn language(&self) -> Option<Language> {
+        Language::of(self.file_path.as_deref()?)
+    }
 
-impl KeyMod {
-    pub const CTRL_MOD: Mod = Mod::LCTRLMOD.union(Mod::RCTRLMOD);
-    pub const SHIFT_MOD: Mod = Mod::LSHIFTMOD.union(Mod::RSHIFTMOD);
+    /// Runs `f` on the syntax tree of the buffer, `None` if there is no
+    /// grammar for its language
+    pub fn with_syntax<T>(&self, f: impl FnOnce(&SyntaxTree) -> Option<T>) -> Option<T> {
+        self.syntax.with(self.language()?, &self.text_buffer, f)
+    }
 
-    pub fn satisfies(&self, sdl_mod: Mod) -> bool {
-        let ctrl_pressed = Self::CTRL_MOD.intersects(sdl_mod);
-        let shift_pressed = Self::SHIFT_MOD.intersects(sdl_mod);
+    /// Enters visual mode of the given kind, switches to it from another
+    /// visual mode, or leaves it if it is the current one
+    pub fn toggle_visual(&mut self, kind: VisualKind) {
+        self.mode = match self.mode {
+            EditorMode::Visual { kind: current, .. } if current == kind => EditorMode::Normal,
+            EditorMode::Visual { anchor, .. } => EditorMode::Visual { kind, anchor },
+            _ => EditorMode::Visual {
+                kind,
+                anchor: (self.cursor_row, self.cursor_col),
+            },
+        };
+    }
 
-        match self {
-            KeyMod::Ctrl => ctrl_pressed && !shift_pressed,
-            KeyMod::CtrlShift => ctrl_pressed && shift_pressed,
+    /// Start and end (row, col) of the visual mode selection, if any
+    pub fn visual_bounds(&self) -> Option<((usize, usize), (usize, usize))> {
+        let EditorMode::Visual { anchor, .. } = self.mode else {
+            return None;
+        };
+        let cursor = (self.cursor_row, self.cursor_col);
+        Some((anchor.min(cursor), anchor.max(cursor)))
+    }
+
+    /// Yanks the visual selection, or the cursor's line, to `"+`
+    pub fn copy_to_clipboard(&mut self) {
+        let row = self.cursor_row;
+        let selection = self
+            .visual_selection()
+            .unwrap_or(Selection::Lines(row..row + 1));
+        let yank = operator::yank(&self.text_buffer, &selection);
+        self.registers.yank(Some('+'), yank);
+        if matches!(self.mode, EditorMode::Visual { .. }) {
+            self.mode = EditorMode::Normal;
         }
     }
-}
 
-impl KeyBind {
-    const fn new(key: Keycode, modifier: KeyMod) -> Self {
-        Self { key, modifier }
+    /// Text covered by the visual mode selection, if any
+    pub fn visual_selection(&self) -> Option<Selection> {
+        let EditorMode::Visual { kind, anchor } = self.mode else {
+            return None;
+        };
+        let cursor = (self.cursor_row, self.cursor_col);
+        let (start, end) = self.visual_bounds()?;
+        let selection = match kind {
+            VisualKind::Char => {
+                let buffer = &self.text_buffer;
+                let start = motion::col_to_byte(buffer, start.0, start.1);
+                let end = motion::col_to_byte(buffer, end.0, end.1);
+                // The char under the cursor is part of the selection
+                let end = end + buffer.char_at(end).map_or(0, char::len_utf8);
+                Selection::Chars(start..end)
+            }
+            VisualKind::Line => Selection::Lines(start.0..end.0 + 1),
+            VisualKind::Block => Selection::Block {
+                rows: start.0..end.0 + 1,
+                cols: anchor.1.min(cursor.1)..anchor.1.max(cursor.1) + 1,
+            },
+        };
+        Some(selection)
     }
-    const fn ctrl(key: Keycode) -> Self {
-        Self {
-            key,
-            modifier: KeyMod::Ctrl,
+
+    /// Makes the visual selection cover `selection`, the cursor on its end
+    fn select(&mut self, selection: Selection) {
+        let EditorMode::Visual { kind, .. } = self.mode else {
+            return;
+        };
+        match selection {
+            Selection::Chars(range) if !range.is_empty() => {
+                self.set_cursor_byte(range.start);
+                let anchor = (self.cursor_row, self.cursor_col);
+                let last = self.text_buffer.char_indices_before(range.end).next();
+                self.set_cursor_byte(last.map_or(range.start, |(idx, _)| idx));
+                self.mode = EditorMode::Visual { kind, anchor };
+            }
+            Selection::Lines(rows) if !rows.is_empty() => {
+                self.cursor_row = rows.end - 1;
+                self.clamp_cursor();
+                self.mode = EditorMode::Visual {
+                    kind: VisualKind::Line,
+                    anchor: (rows.start, 0),
+                };
+            }
+            _ => (),
         }
     }
-}
 
-impl PartialEq<KeyBind> for Event {
-    fn eq(&self, keybind: &KeyBind) -> bool {
-        match self {
-            Event::KeyDown {
-                keycode: Some(key),
-                keymod,
-                ..
-            } => key == &keybind.key && keybind.modifier.satisfies(*keymod),
-            _ => false,
+    /// Keeps the cursor on the last char of the line, as in normal mode
+    pub fn clamp_cursor(&mut self) {
+        let max_col = self.line_len(self.cursor_row).saturating_sub(1);
+        self.cursor_col = self.cursor_col.min(max_col);
+    }
+
+    /// Ends the current group of edits, making it a single undo step
+    pub fn commit_undo(&mut self) {
+        self.history.commit(&self.text_buffer);
+    }
+
+    pub fn undo(&mut self, count: usize) {
+        for _ in 0..count {
+            match self.history.undo() {
+                Some(text) => self.restore(text),
+                None => {
+                    self.message = Some("Already at oldest change".into());
+                    break;
+                }
+            }
         }
     }
+
+    pub fn redo(&mut self, count: usize) {
+        for _ in 0..count {
+            match self.history.redo() {
+                Some(text) => self.restore(text),
+                None => {
+                    self.message = Some("Already at newest change".into());
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Jumps to the given change number, across branches. `None` is before
+    /// the oldest change.
+    pub fn undo_goto(&mut self, seq: Option<usize>) {
+        let last = self.history.last_seq();
+        match seq {
+            None => self.message = Some("Already at oldest change".into()),
+            Some(seq) if seq > last && self.history.seq() == last => {
+                self.message = Some("Already at newest change".into());
+            }
+            Some(seq) => {
+                if let Some(text) = self.history.goto(seq.min(last)) {
+                    self.restore(text);
+                }
+            }
+        }
+    }
+
+    /// Replaces the buffer with a state from the undo history, putting the
+    /// cursor where the text changed
+    fn restore(&mut self, text: Rope) {
+        let changed_at = undo::first_difference(&self.text_buffer, &text);
+        self.text_buffer = text;
+        self.set_cursor_byte(changed_at);
+        self.clamp_cursor();
+        self.modified = !self.history.is_saved();
+        self.message = Some(format!(
+            "change {} of {}",
+            self.history.seq(),
+            self.history.last_seq()
+        ));
+    }
+
+    /// Inserts `s` at the cursor and moves the cursor past it
+    pub fn insert_at_cursor(&mut self, s: &str) {
+        let new = s.replace('\r', "");
+        let at = self.cursor_byte();
+        self.text_buffer.insert(at, &new);
+        self.modified |= !new.is_empty();
+        self.set_cursor_byte(at + new.len());
+        self.registers.record_insert(&new);
+    }
+
+    /// Starts an insert session, recorded anew in the `".` register
+    pub fn enter_insert(&mut self) {
+        self.mode = EditorMode::Insert;
+        self.registers.start_insert();
+    }
+
+    /// Deletes the given byte range and puts the cursor at its start
+    pub fn delete(&mut self, range: Range<usize>) {
+        let start = range.start;
+        self.modified |= !range.is_empty();
+        self.text_buffer.delete(range);
+        self.set_cursor_byte(start);
+    }
+}
+
+#[inline]
+// TODO: switch to the callback based system
+fn check_err() {
+    let errors: Vec<_> = iter::repeat(())
+        .map_while(|_| {
+            let res = unsafe { gl::GetError() };
+            if res == gl::NO_ERROR {
+                None
+            } else {
+                Some(res)
+            }
+        })
+        .collect();
+
+    assert!(errors.is_empty(), "Error(s) occurred: {:?}", errors);
 }
 
 #[derive(PartialEq)]
@@ -519,22 +1700,152 @@ struct GraphicsState {
     cursor_visible: bool,
 }
 
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Clone)]
 struct LogicState<'a> {
     exit: bool,
     font: Option<Font<'a>>,
-    text_buffer: String,
-    line_count: usize,
+    text_buffer: Rope,
     cursor_col: usize,
     cursor_row: usize,
+    /// Column the cursor goes back to when moving between lines
+    desired_col: usize,
+    /// Normal mode keys typed so far that don't form a full command yet
+    pending_keys: String,
+    /// Last `f`/`F`/`t`/`T`, repeated by `;` and `,`
+    last_find: Option<(FindKind, char)>,
+    /// Last `/`, `?`, `*` or `#`, repeated by `n` and `N`
+    last_search: Option<Search>,
+    /// Whether the matches of the last search are highlighted
+    highlight_search: bool,
+    /// Start and end (row, col) of the last visual selection, the `'<` and
+    /// `'>` marks
+    visual_marks: Option<((usize, usize), (usize, usize))>,
+    /// Filled by operators and put back by `p`
+    registers: Registers,
+    /// Command lines run so far, walked with Up and Down in command mode
+    command_history: History,
+    /// Settings from the config file
+    config: Rc<Config>,
+    /// Mappings from the config file and `:map`
+    keymaps: Keymaps,
+    /// Keys typed so far that start a mapping, waiting for the rest of it
+    typed_keys: Vec<Input>,
+    /// Colours things are drawn with
+    theme: Rc<Theme>,
+    history: UndoTree,
     mode: EditorMode,
+    /// File the buffer gets written to
+    file_path: Option<PathBuf>,
+    /// Syntax tree of the buffer, for highlighting, structural motions and
+    /// text objects
+    syntax: SyntaxCache,
+    /// Text of the buffer in one piece, for searches
+    flat_text: FlatText,
+    /// Whether the buffer changed since it was last written
+    modified: bool,
+    /// Status message shown in the footer outside of command mode
+    message: Option<String>,
+}
+
+/// The syntax tree and the flat text are left out, as they're derived from
+/// the text
+impl PartialEq for LogicState<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        // Listing the fields makes adding one without comparing it an error
+        let Self {
+            exit,
+            font,
+            text_buffer,
+            cursor_col,
+            cursor_row,
+            desired_col,
+            pending_keys,
+            last_find,
+            last_search,
+            highlight_search,
+            visual_marks,
+            registers,
+            command_history,
+            config,
+            keymaps,
+            typed_keys,
+            theme,
+            history,
+            mode,
+            file_path,
+            modified,
+            message,
+            syntax: _,
+            flat_text: _,
+        } = self;
+        *exit == other.exit
+            && *font == other.font
+            && *text_buffer == other.text_buffer
+            && *cursor_col == other.cursor_col
+            && *cursor_row == other.cursor_row
+            && *desired_col == other.desired_col
+            && *pending_keys == other.pending_keys
+            && *last_find == other.last_find
+            && *last_search == other.last_search
+            && *highlight_search == other.highlight_search
+            && *visual_marks == other.visual_marks
+            && *registers == other.registers
+            && *command_history == other.command_history
+            && *config == other.config
+            && *keymaps == other.keymaps
+            && *typed_keys == other.typed_keys
+            && *theme == other.theme
+            && *history == other.history
+            && *mode == other.mode
+            && *file_path == other.file_path
+            && *modified == other.modified
+            && *message == other.message
+    }
 }
 
 #[derive(Clone, PartialEq, Eq)]
 enum EditorMode {
     Insert,
     Normal,
-    Command(String),
+    /// An operator was typed and waits for a motion or text object
+    OperatorPending {
+        op: Operator,
+        count: Option<usize>,
+        register: Option<char>,
+    },
+    /// Text is selected between `anchor` (row, col) and the cursor
+    Visual {
+        kind: VisualKind,
+        anchor: (usize, usize),
+    },
+    Command(CommandLine),
+    /// `:s` with the `c` flag, waiting for a yes or no on the current match
+    Confirm(Substitution),
+}
+
+impl EditorMode {
+    /// Name of the mode, as Vim spells it
+    fn name(&self) -> &'static str {
+        match self {
+            EditorMode::Insert => "insert",
+            EditorMode::Normal => "normal",
+            EditorMode::OperatorPending { .. } => "operator-pending",
+            EditorMode::Visual { kind, .. } => match kind {
+                VisualKind::Char => "visual",
+                VisualKind::Line => "visual line",
+                VisualKind::Block => "visual block",
+            },
+            EditorMode::Command(_) => "command-line",
+            EditorMode::Confirm(_) => "confirm",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum VisualKind {
+    Char,
+    Line,
+    Block,
 }
 
 struct TimeInterpolator {
@@ -572,63 +1883,93 @@ impl TimeInterpolator {
 }
 
 fn render_footer(
-    shape_shader: &Shader<6>,
-    text_shader: &Shader<4>,
+    renderer: &mut dyn Renderer,
     atlas: &mut GlyphAtlas,
     drawable_size: (u32, u32),
     state: &LogicState,
+    match_count: usize,
 ) {
     let (w, h) = drawable_size;
     let x1 = 0.;
     let x2 = w as f32;
     let letter_height = 10;
     let prev_scale = atlas.select_scale(1., letter_height);
-    let footer_height = atlas.line_height();
+    let footer_text = match &state.mode {
+        // Searches show how many matches are highlighted as they are typed
+        EditorMode::Command(cmd)
+            if search_pattern(cmd.text()).is_some_and(|(p, _)| !p.is_empty()) =>
+        {
+            let plural = if match_count == 1 { "" } else { "es" };
+            Some(format!("{} [{match_count} match{plural}]", cmd.text()))
+        }
+        EditorMode::Command(cmd) => Some(cmd.text().to_owned()),
+        _ => state.message.clone(),
+    };
+    // Messages like `:map` listings take several lines
+    let line_count = footer_text.as_ref().map_or(1, |s| s.lines().count().max(1));
+    let footer_height = atlas.line_height() * line_count as f32;
     let y2 = h as f32 / 2.;
     let y1 = y2 - footer_height;
+    let [r, g, b, a] = state.theme.footer_background.0;
     let background_vertices = [
-        [x2, y1, 0., 1., 1., 1.],
-        [x2, y2, 0., 1., 1., 1.],
-        [x1, y2, 0., 1., 1., 1.],
-        [x1, y1, 0., 1., 1., 1.],
+        [x2, y1, r, g, b, a],
+        [x2, y2, r, g, b, a],
+        [x1, y2, r, g, b, a],
+        [x1, y1, r, g, b, a],
     ];
 
-    shape_shader.r#use();
-    shape_shader.uniform1f("scale", 1.);
-    shape_shader.uniform1f("yCenter", 0.);
-    shape_shader.upload_rectangles(&[background_vertices]);
-    unsafe { gl::DrawElements(gl::TRIANGLES, 6, gl::UNSIGNED_INT, ptr::null()) }
+    renderer.set_camera(1., 0.);
+    renderer.draw_rectangles(&[background_vertices]);
 
-    if let EditorMode::Command(s) = &state.mode {
+    if let Some(s) = footer_text {
         let mut text_x = x1;
-        let mut text_y = y2 + atlas.descender();
+        let mut text_y = y1 + atlas.line_height() + atlas.descender();
         let mut vertices = Vec::new();
-        for c in s.chars() {
+        let command_cursor = match &state.mode {
+            EditorMode::Command(cmd) => Some(cmd.cursor()),
+            _ => None,
+        };
+        let mut cursor_x = None;
+        for (idx, c) in s.char_indices() {
+            if command_cursor == Some(idx) {
+                cursor_x = Some(text_x);
+            }
+            if c == '\n' {
+                text_x = x1;
+                text_y += atlas.line_height();
+                continue;
+            }
             let (vertices_char, ax, ay) = atlas.get_glyph_data(c, text_x, text_y);
-            vertices.push(vertices_char);
+            vertices.push(render::coloured(
+                vertices_char,
+                state.theme.footer_foreground.0,
+            ));
             text_x += ax;
             text_y += ay;
         }
 
-        text_shader.r#use();
-        text_shader.uniform1f("scale", 1.);
-        text_shader.uniform1f("yCenter", 0.);
-        text_shader.upload_rectangles(&vertices);
-        unsafe {
-            gl::DrawElements(
-                gl::TRIANGLES,
-                (vertices.len() * 6) as i32,
-                gl::UNSIGNED_INT,
-                ptr::null(),
-            );
-        };
+        // The match count comes after the command, so the cursor can only be
+        // past the end of the text when there is none
+        if let Some(cx1) = cursor_x.or(command_cursor.map(|_| text_x)) {
+            let cx2 = cx1 + state.config.insert_cursor_width;
+            let [r, g, b, a] = state.theme.footer_cursor.0;
+            let cursor_vertices = [
+                [cx2, y1, r, g, b, a],
+                [cx2, y2, r, g, b, a],
+                [cx1, y2, r, g, b, a],
+                [cx1, y1, r, g, b, a],
+            ];
+            renderer.draw_rectangles(&[cursor_vertices]);
+        }
+
+        renderer.draw_glyphs(atlas, &vertices);
     }
     // Restore previous scale. Yeah, I think I might wanna refactor that.
     atlas.select_scale(prev_scale, 1);
 }
 
 fn render_cursor(
-    shape_shader: &Shader<6>,
+    renderer: &mut dyn Renderer,
     cursor_coords: (f32, f32),
     ascender: f32,
     descender: f32,
@@ -638,73 +1979,152 @@ fn render_cursor(
     let (x, y) = cursor_coords;
     let asc = ascender;
     let dsc = descender;
-    let alpha = 0.25 + f32::from(u8::from(cursor_visible)) * 0.5;
     let x1 = x;
-    let cursor_width = match state.mode {
-        EditorMode::Insert => INSERT_CURSOR_WIDTH,
-        _ => NORMAL_CURSOR_WIDTH,
+    let (cursor_width, colour) = match state.mode {
+        EditorMode::Insert => (state.config.insert_cursor_width, state.theme.cursor_insert),
+        EditorMode::OperatorPending { .. } => (
+            state.config.operator_pending_cursor_width,
+            state.theme.cursor_operator_pending,
+        ),
+        EditorMode::Visual { .. } => (state.config.normal_cursor_width, state.theme.cursor_visual),
+        _ => (state.config.normal_cursor_width, state.theme.cursor_normal),
     };
+    let [r, g, b, a] = colour.0;
+    let alpha = a * (0.25 + f32::from(u8::from(cursor_visible)) * 0.5);
 
     let x2 = x1 + cursor_width;
     let vertices = [
-        [x2, y - dsc, 1., 1., 1., alpha],
-        [x2, y - asc, 1., 1., 1., alpha],
-        [x1, y - asc, 1., 1., 1., alpha],
-        [x1, y - dsc, 1., 1., 1., alpha],
+        [x2, y - dsc, r, g, b, alpha],
+        [x2, y - asc, r, g, b, alpha],
+        [x1, y - asc, r, g, b, alpha],
+        [x1, y - dsc, r, g, b, alpha],
     ];
 
-    shape_shader.upload_rectangles(&[vertices]);
-    unsafe { gl::DrawElements(gl::TRIANGLES, 6, gl::UNSIGNED_INT, ptr::null()) }
+    renderer.draw_rectangles(&[vertices]);
 }
 
 // TODO: when building atlas, keep track of width of all characters (and be able
 // to predict how wide some text will be)
 // also I really need to document this kek
+/// Draws the text, along with the highlight of the visual selection under it.
+/// Returns the coordinates of the cursor.
 fn render_text(
     state: &LogicState,
     atlas: &mut GlyphAtlas,
     x_start: f32,
     y_start: f32,
-    text_shader: &Shader<4>,
+    search_matches: &[Range<usize>],
+    syntax: &SyntaxHighlight,
+    renderer: &mut dyn Renderer,
 ) -> (f32, f32) {
     let line_height = atlas.line_height();
+    let (asc, dsc) = (atlas.ascender(), atlas.descender());
     let text = &state.text_buffer;
+    let selection = state.visual_selection();
 
     atlas.add_characters(text.chars());
     let mut y0 = y_start;
     let mut cursor_coords = (x_start, y_start);
 
     // Pre-allocate 4 vertices per character. Possibly inexact, but good enough
-    let mut vertices_full = Vec::with_capacity(text.len() * 4);
-    for (row_idx, line) in text.split('\n').enumerate() {
+    let mut vertices_full = Vec::with_capacity(text.len_bytes() * 4);
+    let mut number_vertices = Vec::new();
+    let mut highlights = Vec::new();
+    let mut push_highlight = |x1: f32, x2: f32, y: f32, [r, g, b, a]: [f32; 4]| {
+        highlights.push([
+            [x2, y - dsc, r, g, b, a],
+            [x2, y - asc, r, g, b, a],
+            [x1, y - asc, r, g, b, a],
+            [x1, y - dsc, r, g, b, a],
+        ]);
+    };
+    // Matches are sorted, so only the ones ending after the current char
+    // need to be looked at
+    let mut matches = search_matches.iter().peekable();
+
+    for (row_idx, line) in text.lines().enumerate() {
         let mut x0 = x_start;
+        for c in line_number_label(state, row_idx)
+            .unwrap_or_default()
+            .chars()
+        {
+            let (vertices, ax, _) = atlas.get_glyph_data(c, x0, y0);
+            number_vertices.push(render::coloured(vertices, state.theme.line_number.0));
+            x0 += ax;
+        }
+        if row_idx == state.cursor_row {
+            cursor_coords.0 = x0;
+        }
+        let mut byte = text.line_to_byte(row_idx);
+        // Start and colour of the highlighted run of chars on this line
+        let mut run: Option<(f32, [f32; 4])> = None;
+        // Spans are sorted too, and their offsets are from the line start
+        let line_start = byte;
+        let mut spans = syntax.spans(row_idx).iter().peekable();
         for (col_idx, c) in line.chars().enumerate() {
+            while matches.next_if(|m| m.end <= byte).is_some() {}
+            let colour = if selection
+                .as_ref()
+                .is_some_and(|sel| sel.contains(row_idx, col_idx, byte))
+            {
+                Some(state.theme.selection.0)
+            } else if matches.peek().is_some_and(|m| m.start <= byte) {
+                Some(state.theme.search.0)
+            } else {
+                None
+            };
+            if run.map(|(_, colour)| colour) != colour {
+                if let Some((x1, colour)) = run {
+                    push_highlight(x1, x0, y0, colour);
+                }
+                run = colour.map(|colour| (x0, colour));
+            }
+
+            while spans
+                .next_if(|s| s.range.end <= byte - line_start)
+                .is_some()
+            {}
+            let colour = match spans.peek() {
+                Some(span) if span.range.start <= byte - line_start => {
+                    state.theme.syntax.colour(span.token)
+                }
+                _ => state.theme.foreground,
+            };
             let (vertices, ax, ay) = atlas.get_glyph_data(c, x0, y0);
-            vertices_full.push(vertices);
+            vertices_full.push(render::coloured(vertices, colour.0));
 
             x0 += ax;
             y0 += ay;
-            if col_idx + 1 == state.cursor_col {
+            byte += c.len_utf8();
+            if row_idx == state.cursor_row && col_idx + 1 == state.cursor_col {
                 cursor_coords.0 = x0;
             }
         }
 
+        if let Some((x1, colour)) = run {
+            push_highlight(x1, x0, y0, colour);
+        }
+        // Selected line ends get one cell of highlight
+        let line_end_selected = selection.as_ref().is_some_and(|sel| {
+            !sel.is_block() && sel.contains(row_idx, line.chars().count(), byte)
+        });
+        if line_end_selected {
+            push_highlight(
+                x0,
+                x0 + state.config.normal_cursor_width,
+                y0,
+                state.theme.selection.0,
+            );
+        }
+
         if row_idx == state.cursor_row {
             cursor_coords.1 = y0;
         }
         y0 += line_height;
     }
 
-    text_shader.upload_rectangles(&vertices_full);
-    check_err();
-    unsafe {
-        gl::DrawElements(
-            gl::TRIANGLES,
-            (vertices_full.len() * 6) as i32,
-            gl::UNSIGNED_INT,
-            ptr::null(),
-        );
-    }
-    check_err();
+    renderer.draw_rectangles(&highlights);
+    renderer.draw_glyphs(atlas, &number_vertices);
+    renderer.draw_glyphs(atlas, &vertices_full);
     cursor_coords
 }
@@ -0,0 +1,626 @@
+use std::ops::Range;
+
+use crate::{rope::Rope, search, LogicState};
+
+/// Result of parsing the keys typed so far
+pub enum Parsed<T> {
+    Done(T),
+    /// More keys are needed
+    Pending,
+    Invalid,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FindKind {
+    /// `f`
+    Forward,
+    /// `F`
+    Backward,
+    /// `t`
+    TillForward,
+    /// `T`
+    TillBackward,
+}
+
+impl FindKind {
+    fn reversed(self) -> Self {
+        match self {
+            Self::Forward => Self::Backward,
+            Self::Backward => Self::Forward,
+            Self::TillForward => Self::TillBackward,
+            Self::TillBackward => Self::TillForward,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Motion {
+    Left,
+    Right,
+    Up,
+    Down,
+    /// `w`/`W`
+    WordStart { big: bool },
+    /// `b`/`B`
+    WordBack { big: bool },
+    /// `e`/`E`
+    WordEnd { big: bool },
+    /// `0`
+    LineStart,
+    /// `^`
+    FirstNonBlank,
+    /// `$`
+    LineEnd,
+    /// `gg`
+    FirstLine,
+    /// `G`
+    LastLine,
+    Find(FindKind, char),
+    /// `;` and `,`
+    RepeatFind { reverse: bool },
+    /// `%`
+    MatchingPair,
+    /// `}`
+    ParagraphForward,
+    /// `{`
+    ParagraphBackward,
+    /// `n` and `N`
+    SearchNext {
+        reverse: bool,
+    },
+    /// `*` and `#`
+    SearchWord {
+        backward: bool,
+    },
+    /// `]f` and `[f`, between definitions in the syntax tree
+    Definition {
+        backward: bool,
+    },
+    /// `[z` and `]z`, to the start or end of the fold around the cursor
+    FoldEdge {
+        end: bool,
+    },
+    /// `zj` and `zk`, to the start of the next fold or end of the previous one
+    NextFold {
+        backward: bool,
+    },
+}
+
+/// How the text between the cursor and the target of a motion is selected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MotionKind {
+    /// The target is not included
+    Exclusive,
+    /// The target is included
+    Inclusive,
+    /// Whole lines are selected
+    Linewise,
+}
+
+impl Motion {
+    pub fn parse(keys: &str) -> Parsed<Self> {
+        use Motion::*;
+        use Parsed::*;
+        let mut chars = keys.chars();
+        let first = match chars.next() {
+            Some(c) => c,
+            None => return Pending,
+        };
+        let second = chars.next();
+        if chars.next().is_some() {
+            return Invalid;
+        }
+
+        let find = |kind| match second {
+            Some(c) => Done(Find(kind, c)),
+            None => Pending,
+        };
+
+        let motion = match first {
+            'f' => return find(FindKind::Forward),
+            'F' => return find(FindKind::Backward),
+            't' => return find(FindKind::TillForward),
+            'T' => return find(FindKind::TillBackward),
+            'g' => {
+                return match second {
+                    None => Pending,
+                    Some('g') => Done(FirstLine),
+                    Some(_) => Invalid,
+                }
+            }
+            '[' | ']' | 'z' => {
+                return match (first, second) {
+                    (_, None) => Pending,
+                    (_, Some('f')) if first != 'z' => Done(Definition {
+                        backward: first == '[',
+                    }),
+                    (_, Some('z')) if first != 'z' => Done(FoldEdge { end: first == ']' }),
+                    ('z', Some('j')) => Done(NextFold { backward: false }),
+                    ('z', Some('k')) => Done(NextFold { backward: true }),
+                    _ => Invalid,
+                }
+            }
+            _ if second.is_some() => return Invalid,
+            'h' => Left,
+            'l' | ' ' => Right,
+            'k' => Up,
+            'j' => Down,
+            'w' => WordStart { big: false },
+            'W' => WordStart { big: true },
+            'b' => WordBack { big: false },
+            'B' => WordBack { big: true },
+            'e' => WordEnd { big: false },
+            'E' => WordEnd { big: true },
+            '0' => LineStart,
+            '^' => FirstNonBlank,
+            '$' => LineEnd,
+            'G' => LastLine,
+            ';' => RepeatFind { reverse: false },
+            ',' => RepeatFind { reverse: true },
+            '%' => MatchingPair,
+            '}' => ParagraphForward,
+            '{' => ParagraphBackward,
+            'n' => SearchNext { reverse: false },
+            'N' => SearchNext { reverse: true },
+            '*' => SearchWord { backward: false },
+            '#' => SearchWord { backward: true },
+            _ => return Invalid,
+        };
+        Done(motion)
+    }
+
+    /// Turns `;` and `,` into the find they repeat
+    pub fn resolve(self, last_find: Option<(FindKind, char)>) -> Option<Self> {
+        match self {
+            Motion::RepeatFind { reverse } => {
+                let (kind, c) = last_find?;
+                let kind = if reverse { kind.reversed() } else { kind };
+                Some(Motion::Find(kind, c))
+            }
+            other => Some(other),
+        }
+    }
+
+    pub fn kind(&self) -> MotionKind {
+        use Motion::*;
+        match self {
+            Up | Down | FirstLine | LastLine | FoldEdge { .. } | NextFold { .. } => {
+                MotionKind::Linewise
+            }
+            WordEnd { .. } | LineEnd | MatchingPair => MotionKind::Inclusive,
+            Find(FindKind::Forward | FindKind::TillForward, _) => MotionKind::Inclusive,
+            _ => MotionKind::Exclusive,
+        }
+    }
+
+    /// Whether the motion keeps the desired column of the cursor
+    pub fn is_vertical(&self) -> bool {
+        matches!(self, Motion::Up | Motion::Down)
+    }
+
+    /// Computes the byte offset the motion moves the cursor to, or `None` if
+    /// the motion fails. `count` is `None` when no count was typed.
+    pub fn target(&self, state: &LogicState, count: Option<usize>) -> Option<usize> {
+        use Motion::*;
+        let buffer = &state.text_buffer;
+        let n = count.unwrap_or(1).max(1);
+        let pos = state.cursor_byte();
+        let row = state.cursor_row;
+        let last_row = buffer.len_lines() - 1;
+
+        let target = match *self {
+            Left => {
+                let col = state.cursor_col.checked_sub(1)?.saturating_sub(n - 1);
+                col_to_byte(buffer, row, col)
+            }
+            Right => {
+                let line_len = state.line_len(row);
+                if state.cursor_col >= line_len {
+                    return None;
+                }
+                col_to_byte(
+                    buffer,
+                    row,
+                    state.cursor_col.saturating_add(n).min(line_len),
+                )
+            }
+            Up => {
+                let row = row.checked_sub(1)?.saturating_sub(n - 1);
+                col_to_byte(buffer, row, state.desired_col)
+            }
+            Down => {
+                if row == last_row {
+                    return None;
+                }
+                let row = row.saturating_add(n).min(last_row);
+                col_to_byte(buffer, row, state.desired_col)
+            }
+            WordStart { big } => repeat(n, pos, |p| next_word_start(buffer, p, big)),
+            WordBack { big } => repeat(n, pos, |p| prev_word_start(buffer, p, big)),
+            WordEnd { big } => repeat(n, pos, |p| word_end(buffer, p, big)),
+            LineStart => buffer.line_to_byte(row),
+            FirstNonBlank => first_non_blank(buffer, row),
+            LineEnd => {
+                let row = row.saturating_add(n - 1).min(last_row);
+                last_char(buffer, row)
+            }
+            FirstLine => first_non_blank(buffer, count.map_or(0, |c| c - 1).min(last_row)),
+            LastLine => first_non_blank(buffer, count.map_or(last_row, |c| c - 1).min(last_row)),
+            Find(kind, c) => find_in_line(buffer, pos, row, kind, c, n)?,
+            RepeatFind { .. } => return None,
+            MatchingPair => match count {
+                // `N%` goes to N percent of the file
+                Some(percent) if percent <= 100 => {
+                    let row = (percent * (last_row + 1)).div_ceil(100).max(1) - 1;
+                    first_non_blank(buffer, row)
+                }
+                Some(_) => return None,
+                None => matching_pair(buffer, pos, row)?,
+            },
+            ParagraphForward => {
+                let row = repeat(n, row, |r| next_paragraph(buffer, r));
+                if row > last_row {
+                    buffer.len_bytes()
+                } else {
+                    buffer.line_to_byte(row)
+                }
+            }
+            ParagraphBackward => buffer.line_to_byte(repeat(n, row, |r| prev_paragraph(buffer, r))),
+            SearchNext { reverse } => {
+                let search = state.last_search.as_ref()?;
+                let text = state.flat_text.get(buffer);
+                search.find(&text, pos, search.backward != reverse, n)?
+            }
+            SearchWord { backward } => {
+                let (search, start) = search::word_search(buffer, pos, backward)?;
+                search.find(&state.flat_text.get(buffer), start, backward, n)?
+            }
+            Definition { backward } => {
+                state.with_syntax(|tree| tree.definition(pos, backward, n))?
+            }
+            FoldEdge { end } => {
+                let folds = state.with_syntax(|tree| Some(tree.folds()))?;
+                let row = (0..n).try_fold(row, |row, _| fold_edge(&folds, row, end))?;
+                first_non_blank(buffer, row)
+            }
+            NextFold { backward } => {
+                let folds = state.with_syntax(|tree| Some(tree.folds()))?;
+                let row = if backward {
+                    let mut ends: Vec<_> = folds.iter().map(|fold| fold.end - 1).collect();
+                    ends.sort_unstable();
+                    ends.dedup();
+                    ends.into_iter().rev().filter(|&end| end < row).nth(n - 1)?
+                } else {
+                    let mut starts: Vec<_> = folds.iter().map(|fold| fold.start).collect();
+                    starts.dedup();
+                    starts.into_iter().filter(|&start| start > row).nth(n - 1)?
+                };
+                first_non_blank(buffer, row)
+            }
+        };
+        Some(target)
+    }
+}
+
+/// Row of the start or end of the innermost fold around `row`, or of the
+/// one around it when `row` is already there
+fn fold_edge(folds: &[Range<usize>], row: usize, end: bool) -> Option<usize> {
+    let around = folds.iter().filter(|fold| fold.contains(&row));
+    if end {
+        around
+            .map(|fold| fold.end - 1)
+            .filter(|&end| end > row)
+            .min()
+    } else {
+        around
+            .map(|fold| fold.start)
+            .filter(|&start| start < row)
+            .max()
+    }
+}
+
+/// Applies `step` `n` times, stopping early once it stops moving, so that
+/// huge counts end at the edge of the buffer right away
+pub fn repeat(n: usize, start: usize, step: impl Fn(usize) -> usize) -> usize {
+    let moved = (0..n).try_fold(start, |pos, _| {
+        let next = step(pos);
+        if next == pos {
+            Err(pos)
+        } else {
+            Ok(next)
+        }
+    });
+    moved.unwrap_or_else(|pos| pos)
+}
+
+/// Byte offset of column `col` on line `row`, clamped to the end of the line
+pub fn col_to_byte(buffer: &Rope, row: usize, col: usize) -> usize {
+    let line = buffer.line_range(row);
+    let line_start = buffer.byte_to_char(line.start);
+    buffer.char_to_byte(line_start.saturating_add(col)).min(line.end)
+}
+
+/// Byte offset of the last char of line `row`, or of its start when it's empty
+pub fn last_char(buffer: &Rope, row: usize) -> usize {
+    let line = buffer.line_range(row);
+    buffer
+        .char_indices_before(line.end)
+        .next()
+        .filter(|&(idx, _)| idx >= line.start)
+        .map_or(line.start, |(idx, _)| idx)
+}
+
+pub fn first_non_blank(buffer: &Rope, row: usize) -> usize {
+    let line = buffer.line_range(row);
+    buffer
+        .char_indices_from(line.start)
+        .find(|&(idx, c)| idx >= line.end || !c.is_whitespace())
+        .map_or(line.end, |(idx, _)| idx.min(line.end))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CharClass {
+    Blank,
+    Punctuation,
+    Word,
+}
+
+pub fn class(c: char, big: bool) -> CharClass {
+    if c.is_whitespace() {
+        CharClass::Blank
+    } else if big || c.is_alphanumeric() || c == '_' {
+        CharClass::Word
+    } else {
+        CharClass::Punctuation
+    }
+}
+
+/// Whether `c`, preceded by `prev`, starts a word. Empty lines count as words.
+fn is_word_start(prev: char, c: char, big: bool) -> bool {
+    let k = class(c, big);
+    (prev == '\n' && c == '\n') || (k != CharClass::Blank && k != class(prev, big))
+}
+
+fn next_word_start(buffer: &Rope, pos: usize, big: bool) -> usize {
+    let mut chars = buffer.char_indices_from(pos);
+    let mut prev = match chars.next() {
+        Some((_, c)) => c,
+        None => return pos,
+    };
+    for (idx, c) in chars {
+        if is_word_start(prev, c, big) {
+            return idx;
+        }
+        prev = c;
+    }
+    buffer.len_bytes()
+}
+
+fn prev_word_start(buffer: &Rope, pos: usize, big: bool) -> usize {
+    let mut chars = buffer.char_indices_before(pos).peekable();
+    while let Some((idx, c)) = chars.next() {
+        match chars.peek() {
+            Some(&(_, prev)) if is_word_start(prev, c, big) => return idx,
+            Some(_) => (),
+            None => return idx,
+        }
+    }
+    0
+}
+
+pub fn word_end(buffer: &Rope, pos: usize, big: bool) -> usize {
+    let mut chars = buffer.char_indices_from(pos).skip(1).peekable();
+    while let Some((idx, c)) = chars.next() {
+        let k = class(c, big);
+        if k == CharClass::Blank {
+            continue;
+        }
+        match chars.peek() {
+            Some(&(_, next)) if class(next, big) == k => (),
+            _ => return idx,
+        }
+    }
+    pos
+}
+
+/// End of the word under `pos`, unlike [`word_end`] which always moves
+pub fn current_word_end(buffer: &Rope, pos: usize, big: bool) -> usize {
+    let mut chars = buffer.char_indices_from(pos).peekable();
+    let mut end = pos;
+    while let Some((idx, c)) = chars.next() {
+        end = idx;
+        match chars.peek() {
+            Some(&(_, next)) if next != '\n' && class(next, big) == class(c, big) => (),
+            _ => break,
+        }
+    }
+    end
+}
+
+fn find_in_line(
+    buffer: &Rope,
+    pos: usize,
+    row: usize,
+    kind: FindKind,
+    target: char,
+    n: usize,
+) -> Option<usize> {
+    let line = buffer.line_range(row);
+    match kind {
+        FindKind::Forward | FindKind::TillForward => {
+            let mut prev = pos;
+            let mut found = 0;
+            for (idx, c) in buffer.char_indices_from(pos).skip(1) {
+                if idx >= line.end {
+                    break;
+                }
+                if c == target {
+                    found += 1;
+                    if found == n {
+                        let till = kind == FindKind::TillForward;
+                        return Some(if till { prev } else { idx });
+                    }
+                }
+                prev = idx;
+            }
+            None
+        }
+        FindKind::Backward | FindKind::TillBackward => {
+            let mut next = pos;
+            let mut found = 0;
+            for (idx, c) in buffer.char_indices_before(pos) {
+                if idx < line.start {
+                    break;
+                }
+                if c == target {
+                    found += 1;
+                    if found == n {
+                        let till = kind == FindKind::TillBackward;
+                        return Some(if till { next } else { idx });
+                    }
+                }
+                next = idx;
+            }
+            None
+        }
+    }
+}
+
+const PAIRS: [(char, char); 3] = [('(', ')'), ('[', ']'), ('{', '}')];
+
+/// Finds the bracket matching the first bracket at or after `pos` on the line
+fn matching_pair(buffer: &Rope, pos: usize, row: usize) -> Option<usize> {
+    let line_end = buffer.line_range(row).end;
+    let (start, bracket) = buffer
+        .char_indices_from(pos)
+        .take_while(|&(idx, _)| idx < line_end)
+        .find(|&(_, c)| PAIRS.iter().any(|&(o, cl)| c == o || c == cl))?;
+
+    let mut depth = 0_usize;
+    if let Some(&(open, close)) = PAIRS.iter().find(|(o, _)| *o == bracket) {
+        for (idx, c) in buffer.char_indices_from(start) {
+            if c == open {
+                depth += 1;
+            } else if c == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(idx);
+                }
+            }
+        }
+    } else {
+        let &(open, close) = PAIRS.iter().find(|(_, cl)| *cl == bracket)?;
+        let after = start + bracket.len_utf8();
+        for (idx, c) in buffer.char_indices_before(after) {
+            if c == close {
+                depth += 1;
+            } else if c == open {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(idx);
+                }
+            }
+        }
+    }
+    None
+}
+
+pub fn is_blank_line(buffer: &Rope, row: usize) -> bool {
+    buffer.line_range(row).is_empty()
+}
+
+/// Row of the next blank line after the paragraph at `row`. Returns the row
+/// after the last one if there is none.
+fn next_paragraph(buffer: &Rope, mut row: usize) -> usize {
+    let line_count = buffer.len_lines();
+    while row < line_count && is_blank_line(buffer, row) {
+        row += 1;
+    }
+    while row < line_count && !is_blank_line(buffer, row) {
+        row += 1;
+    }
+    row
+}
+
+/// Row of the previous blank line before the paragraph at `row`, or 0
+fn prev_paragraph(buffer: &Rope, mut row: usize) -> usize {
+    while row > 0 && is_blank_line(buffer, row) {
+        row -= 1;
+    }
+    while row > 0 && !is_blank_line(buffer, row) {
+        row -= 1;
+    }
+    row
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "foo bar.baz  qux\n\nline three (a (b) c)\n  indented";
+    const HUGE: Option<usize> = Some(99_999_999_999);
+
+    /// Row and column `keys` moves the cursor to in `TEXT`, from `row` and
+    /// `col`
+    fn target(
+        keys: &str,
+        count: Option<usize>,
+        (row, col): (usize, usize),
+    ) -> Option<(usize, usize)> {
+        let mut state = LogicState::new(Rope::from(TEXT));
+        state.cursor_row = row;
+        state.cursor_col = col;
+        state.desired_col = col;
+        let Parsed::Done(motion) = Motion::parse(keys) else {
+            panic!("{keys} is not a motion");
+        };
+        let byte = motion.target(&state, count)?;
+        let buffer = &state.text_buffer;
+        let row = buffer.byte_to_line(byte);
+        Some((row, buffer.byte_to_char(byte) - buffer.line_to_char(row)))
+    }
+
+    #[test]
+    fn counts() {
+        assert_eq!(target("w", Some(3), (0, 0)), Some((0, 8)));
+        // Empty lines count as words
+        assert_eq!(target("w", Some(5), (0, 0)), Some((1, 0)));
+        assert_eq!(target("W", Some(2), (0, 0)), Some((0, 13)));
+        assert_eq!(target("b", Some(3), (0, 13)), Some((0, 4)));
+        assert_eq!(target("e", Some(2), (0, 0)), Some((0, 6)));
+        assert_eq!(target("}", Some(2), (0, 0)), Some((3, 10)));
+        assert_eq!(target("{", Some(1), (3, 4)), Some((1, 0)));
+        assert_eq!(target("l", Some(3), (0, 0)), Some((0, 3)));
+        assert_eq!(target("j", Some(2), (0, 5)), Some((2, 5)));
+        assert_eq!(target("G", Some(3), (0, 0)), Some((2, 0)));
+        assert!(matches!(Motion::parse("f"), Parsed::Pending));
+        assert_eq!(target("fa", Some(2), (0, 0)), Some((0, 9)));
+        assert_eq!(target("ta", Some(2), (0, 0)), Some((0, 8)));
+        assert_eq!(target("fa", Some(3), (0, 0)), None);
+        // `N%` goes to N percent of the lines
+        assert_eq!(target("%", Some(50), (0, 0)), Some((1, 0)));
+        assert_eq!(target("%", Some(100), (0, 0)), Some((3, 2)));
+        assert_eq!(target("%", Some(101), (0, 0)), None);
+    }
+
+    #[test]
+    fn huge_counts() {
+        // Every motion stops at the edge of the buffer, without stepping
+        // through the whole count
+        assert_eq!(target("w", HUGE, (0, 0)), Some((3, 10)));
+        assert_eq!(target("W", HUGE, (0, 0)), Some((3, 10)));
+        assert_eq!(target("e", HUGE, (0, 0)), Some((3, 9)));
+        assert_eq!(target("b", HUGE, (3, 4)), Some((0, 0)));
+        assert_eq!(target("}", HUGE, (0, 0)), Some((3, 10)));
+        assert_eq!(target("{", HUGE, (3, 4)), Some((0, 0)));
+        assert_eq!(target("h", HUGE, (0, 5)), Some((0, 0)));
+        assert_eq!(target("l", HUGE, (0, 5)), Some((0, 16)));
+        assert_eq!(target("k", HUGE, (3, 1)), Some((0, 1)));
+        assert_eq!(target("j", HUGE, (0, 1)), Some((3, 1)));
+        assert_eq!(target("$", HUGE, (0, 1)), Some((3, 9)));
+        assert_eq!(target("gg", HUGE, (0, 0)), Some((3, 2)));
+        assert_eq!(target("G", Some(usize::MAX), (0, 0)), Some((3, 2)));
+        assert_eq!(target("fa", HUGE, (0, 0)), None);
+        assert_eq!(target("%", Some(usize::MAX), (0, 0)), None);
+    }
+}
@@ -0,0 +1,891 @@
+use std::ops::Range;
+
+use crate::{
+    motion::{self, CharClass, Motion, MotionKind, Parsed},
+    register::{Registers, Yank, YankKind},
+    rope::Rope,
+    syntax::{self, Structure},
+    LogicState,
+};
+
+/// Puts that would insert more bytes than this are refused
+const MAX_PUT_LENGTH: usize = 1 << 28;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Delete,
+    Change,
+    Yank,
+    Indent,
+    Dedent,
+}
+
+impl Operator {
+    pub fn from_key(c: char) -> Option<Self> {
+        match c {
+            'd' => Some(Self::Delete),
+            'c' => Some(Self::Change),
+            'y' => Some(Self::Yank),
+            '>' => Some(Self::Indent),
+            '<' => Some(Self::Dedent),
+            _ => None,
+        }
+    }
+
+    pub fn key(self) -> char {
+        match self {
+            Self::Delete => 'd',
+            Self::Change => 'c',
+            Self::Yank => 'y',
+            Self::Indent => '>',
+            Self::Dedent => '<',
+        }
+    }
+}
+
+/// Text an operator acts on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selection {
+    /// Byte range
+    Chars(Range<usize>),
+    /// Range of rows
+    Lines(Range<usize>),
+    /// Rectangle of rows and char columns
+    Block {
+        rows: Range<usize>,
+        cols: Range<usize>,
+    },
+}
+
+impl Selection {
+    /// Rows touched by the selection
+    fn rows(&self, buffer: &Rope) -> Range<usize> {
+        match self {
+            Selection::Chars(range) => {
+                let last = range.end.saturating_sub(1).max(range.start);
+                buffer.byte_to_line(range.start)..buffer.byte_to_line(last) + 1
+            }
+            Selection::Lines(rows) | Selection::Block { rows, .. } => rows.clone(),
+        }
+    }
+
+    /// Whether the char at `byte`, on `row` and `col`, is selected
+    pub fn contains(&self, row: usize, col: usize, byte: usize) -> bool {
+        match self {
+            Selection::Chars(range) => range.contains(&byte),
+            Selection::Lines(rows) => rows.contains(&row),
+            Selection::Block { rows, cols } => rows.contains(&row) && cols.contains(&col),
+        }
+    }
+
+    pub fn is_block(&self) -> bool {
+        matches!(self, Selection::Block { .. })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextObject {
+    /// `iw`/`aw`/`iW`/`aW`
+    Word { big: bool, around: bool },
+    /// `i(`/`a(` and the other brackets
+    Pair {
+        open: char,
+        close: char,
+        around: bool,
+    },
+    /// `i"`/`a"`, also for `'` and `` ` ``
+    Quote { quote: char, around: bool },
+    /// `ip`/`ap`
+    Paragraph { around: bool },
+    /// `it`/`at`
+    Tag { around: bool },
+    /// `if`/`af`, `ic`/`ac` and `ia`/`aa`, from the syntax tree
+    Structure { structure: Structure, around: bool },
+}
+
+impl TextObject {
+    pub fn parse(keys: &str) -> Parsed<Self> {
+        use Parsed::*;
+        use TextObject::*;
+        let mut chars = keys.chars();
+        let around = match chars.next() {
+            Some('a') => true,
+            Some('i') => false,
+            _ => return Invalid,
+        };
+        let obj = match chars.next() {
+            Some(c) => c,
+            None => return Pending,
+        };
+        if chars.next().is_some() {
+            return Invalid;
+        }
+
+        let pair = |open, close| Pair {
+            open,
+            close,
+            around,
+        };
+        let obj = match obj {
+            'w' => Word { big: false, around },
+            'W' => Word { big: true, around },
+            '(' | ')' | 'b' => pair('(', ')'),
+            '[' | ']' => pair('[', ']'),
+            '{' | '}' | 'B' => pair('{', '}'),
+            '<' | '>' => pair('<', '>'),
+            '"' | '\'' | '`' => Quote { quote: obj, around },
+            'p' => Paragraph { around },
+            't' => Tag { around },
+            'f' => Structure {
+                structure: syntax::Structure::Function,
+                around,
+            },
+            'c' => Structure {
+                structure: syntax::Structure::Class,
+                around,
+            },
+            'a' => Structure {
+                structure: syntax::Structure::Argument,
+                around,
+            },
+            _ => return Invalid,
+        };
+        Done(obj)
+    }
+
+    pub fn selection(&self, state: &LogicState) -> Option<Selection> {
+        let buffer = &state.text_buffer;
+        let pos = state.cursor_byte();
+        match *self {
+            TextObject::Word { big, around } => word_object(state, big, around),
+            TextObject::Pair {
+                open,
+                close,
+                around,
+            } => pair_object(buffer, pos, open, close, around),
+            TextObject::Quote { quote, around } => quote_object(state, quote, around),
+            TextObject::Paragraph { around } => {
+                Some(paragraph_object(buffer, state.cursor_row, around))
+            }
+            TextObject::Tag { around } => tag_object(buffer, pos, around),
+            TextObject::Structure { structure, around } => {
+                let range = state.with_syntax(|tree| tree.text_object(pos, structure, around))?;
+                if structure == Structure::Argument {
+                    return Some(Selection::Chars(range));
+                }
+                node_selection(buffer, range)
+            }
+        }
+    }
+}
+
+/// Length of the char at `byte`, not counting line ends
+fn char_len(buffer: &Rope, byte: usize) -> usize {
+    buffer
+        .char_at(byte)
+        .filter(|&c| c != '\n')
+        .map_or(0, char::len_utf8)
+}
+
+/// Computes the text covered by `motion` when used after `op`
+pub fn motion_selection(
+    state: &LogicState,
+    op: Operator,
+    motion: Motion,
+    count: Option<usize>,
+) -> Option<Selection> {
+    use Selection::*;
+    let buffer = &state.text_buffer;
+    let pos = state.cursor_byte();
+
+    // Same as vim, `cw` on a word only changes up to the end of that word
+    if let (Operator::Change, Motion::WordStart { big }) = (op, motion) {
+        if buffer.char_at(pos).is_some_and(|c| !c.is_whitespace()) {
+            let first = motion::current_word_end(buffer, pos, big);
+            let n = count.unwrap_or(1).max(1);
+            let end = motion::repeat(n - 1, first, |p| motion::word_end(buffer, p, big));
+            return Some(Chars(pos..end + char_len(buffer, end)));
+        }
+    }
+
+    let target = motion.target(state, count)?;
+    let (start, end) = (pos.min(target), pos.max(target));
+    let selection = match motion.kind() {
+        MotionKind::Linewise => Lines(buffer.byte_to_line(start)..buffer.byte_to_line(end) + 1),
+        MotionKind::Inclusive => Chars(start..end + char_len(buffer, end)),
+        MotionKind::Exclusive => {
+            let mut end = end;
+            // The last word moved over by `w` ends the text, not the first
+            // word of the next line
+            if let Motion::WordStart { .. } = motion {
+                let row = buffer.byte_to_line(end);
+                if row > state.cursor_row {
+                    end = buffer.line_range(row - 1).end.max(start);
+                }
+            }
+            Chars(start..end)
+        }
+    };
+    Some(selection)
+}
+
+/// Applies `op` to `selection`, moving the cursor the way vim does
+pub fn apply(state: &mut LogicState, op: Operator, selection: Selection, register: Option<char>) {
+    if let Some(name) = register.filter(|&name| Registers::is_read_only(name)) {
+        state.message = Some(format!("E354: Invalid register name: '{name}'"));
+        return;
+    }
+    let buffer = &state.text_buffer;
+    let rows = selection.rows(buffer);
+    match op {
+        Operator::Yank => {
+            state.registers.yank(register, yank(buffer, &selection));
+            match selection {
+                Selection::Chars(range) => state.set_cursor_byte(range.start),
+                Selection::Lines(rows) => {
+                    state.cursor_row = rows.start;
+                    state.clamp_cursor();
+                }
+                Selection::Block { rows, cols } => {
+                    let target = motion::col_to_byte(buffer, rows.start, cols.start);
+                    state.set_cursor_byte(target);
+                }
+            }
+        }
+        Operator::Delete | Operator::Change if selection.is_block() => {
+            state.registers.delete(register, yank(buffer, &selection));
+            if let Selection::Block { rows, cols } = selection {
+                delete_block(state, rows.clone(), cols.clone());
+                let target = motion::col_to_byte(&state.text_buffer, rows.start, cols.start);
+                state.set_cursor_byte(target);
+            }
+            if op == Operator::Change {
+                state.enter_insert();
+            } else {
+                state.clamp_cursor();
+            }
+        }
+        Operator::Delete => {
+            state.registers.delete(register, yank(buffer, &selection));
+            let range = match &selection {
+                Selection::Chars(range) => range.clone(),
+                Selection::Block { .. } => unreachable!("Handled above"),
+                Selection::Lines(rows) => {
+                    let mut start = buffer.line_to_byte(rows.start);
+                    let end = buffer.line_to_byte(rows.end);
+                    // The last line has no `\n` of its own, take the previous one
+                    if rows.end >= buffer.len_lines() {
+                        start = start.saturating_sub(1);
+                    }
+                    start..end
+                }
+            };
+            state.delete(range);
+            if let Selection::Lines(_) = selection {
+                let row = rows.start.min(state.text_buffer.len_lines() - 1);
+                let target = motion::first_non_blank(&state.text_buffer, row);
+                state.set_cursor_byte(target);
+            }
+            state.clamp_cursor();
+        }
+        Operator::Change => {
+            state.registers.delete(register, yank(buffer, &selection));
+            let range = match selection {
+                Selection::Chars(range) => range,
+                Selection::Block { .. } => unreachable!("Handled above"),
+                // Keep one (empty) line to insert into
+                Selection::Lines(rows) => {
+                    buffer.line_to_byte(rows.start)..buffer.line_range(rows.end - 1).end
+                }
+            };
+            state.delete(range);
+            state.enter_insert();
+        }
+        Operator::Indent | Operator::Dedent => {
+            for row in rows.clone() {
+                shift_line(state, row, op == Operator::Indent);
+            }
+            let target = motion::first_non_blank(&state.text_buffer, rows.start);
+            state.set_cursor_byte(target);
+        }
+    }
+}
+
+pub fn yank(buffer: &Rope, selection: &Selection) -> Yank {
+    match selection {
+        Selection::Chars(range) => Yank::new(buffer.slice(range.clone()), YankKind::Chars),
+        Selection::Lines(rows) => {
+            let start = buffer.line_to_byte(rows.start);
+            let end = buffer.line_range(rows.end - 1).end;
+            let mut text = buffer.slice(start..end);
+            text.push('\n');
+            Yank::new(text, YankKind::Lines)
+        }
+        Selection::Block { rows, cols } => {
+            let lines: Vec<_> = rows
+                .clone()
+                .map(|row| {
+                    let range = block_line_range(buffer, row, cols.clone());
+                    buffer.slice(range)
+                })
+                .collect();
+            Yank::new(lines.join("\n"), YankKind::Block)
+        }
+    }
+}
+
+/// Byte range of the columns `cols` on line `row`, cut to the line's length
+fn block_line_range(buffer: &Rope, row: usize, cols: Range<usize>) -> Range<usize> {
+    motion::col_to_byte(buffer, row, cols.start)..motion::col_to_byte(buffer, row, cols.end)
+}
+
+fn delete_block(state: &mut LogicState, rows: Range<usize>, cols: Range<usize>) {
+    for row in rows {
+        let range = block_line_range(&state.text_buffer, row, cols.clone());
+        if !range.is_empty() {
+            state.text_buffer.delete(range);
+            state.modified = true;
+        }
+    }
+}
+
+/// Adds or removes one level of indentation on line `row`
+fn shift_line(state: &mut LogicState, row: usize, indent: bool) {
+    let shift_width = state.config.shift_width;
+    let buffer = &mut state.text_buffer;
+    let start = buffer.line_to_byte(row);
+    if indent {
+        if !motion::is_blank_line(buffer, row) {
+            buffer.insert(start, &" ".repeat(shift_width));
+            state.modified = true;
+        }
+        return;
+    }
+
+    let line = buffer.line(row);
+    let removed = if line.starts_with('\t') {
+        1
+    } else {
+        line.bytes()
+            .take(shift_width)
+            .take_while(|&b| b == b' ')
+            .count()
+    };
+    if removed > 0 {
+        buffer.delete(start..start + removed);
+        state.modified = true;
+    }
+}
+
+/// Puts register `name`, the unnamed one by default, after (`p`) or before
+/// (`P`) the cursor
+pub fn put(state: &mut LogicState, before: bool, count: Option<usize>, name: Option<char>) {
+    let name = name.unwrap_or('"');
+    let yank = match state.registers.get(name) {
+        Some(yank) if !yank.text.is_empty() => yank,
+        _ => {
+            state.message = Some(format!("E353: Nothing in register {name}"));
+            return;
+        }
+    };
+    let count = count.unwrap_or(1).max(1);
+    if put_length(&yank, count).is_none() {
+        state.message = Some("E1240: Resulting text too long".into());
+        return;
+    }
+    let pos = state.cursor_byte();
+    let buffer = &mut state.text_buffer;
+
+    match yank.kind {
+        YankKind::Lines => {
+            let text = yank.text.repeat(count);
+            let row = state.cursor_row + usize::from(!before);
+            if row < buffer.len_lines() {
+                buffer.insert(buffer.line_to_byte(row), &text);
+            } else {
+                // After the last line, which has no `\n` to insert after
+                let end = buffer.len_bytes();
+                buffer.insert(end, &format!("\n{}", &text[..text.len() - 1]));
+            }
+            state.modified = true;
+            let target = motion::first_non_blank(&state.text_buffer, row);
+            state.set_cursor_byte(target);
+        }
+        YankKind::Chars => {
+            let text = yank.text.repeat(count);
+            let at = if before {
+                pos
+            } else {
+                pos + char_len(buffer, pos)
+            };
+            buffer.insert(at, &text);
+            state.modified = true;
+            let last_char = text.chars().next_back().map_or(0, char::len_utf8);
+            state.set_cursor_byte(at + text.len() - last_char);
+        }
+        YankKind::Block => {
+            let empty_line = state.line_len(state.cursor_row) == 0;
+            let col = state.cursor_col + usize::from(!before && !empty_line);
+            put_block(state, &yank.text, col, count);
+        }
+    }
+}
+
+/// Bytes putting `yank` `count` times inserts at most, if that is no more
+/// than `MAX_PUT_LENGTH`
+fn put_length(yank: &Yank, count: usize) -> Option<usize> {
+    let once = match yank.kind {
+        YankKind::Lines | YankKind::Chars => yank.text.len(),
+        // Every line gets padded to the width of the block
+        YankKind::Block => {
+            let lines = yank.text.split('\n');
+            let width = lines.clone().map(|line| line.chars().count()).max();
+            yank.text.len() + lines.count() * width.unwrap_or(0)
+        }
+    };
+    once.checked_mul(count).filter(|&len| len <= MAX_PUT_LENGTH)
+}
+
+/// Puts each line of `text` at column `col` of the lines from the cursor
+/// down, padding short lines and adding missing ones
+fn put_block(state: &mut LogicState, text: &str, col: usize, count: usize) {
+    let top = state.cursor_row;
+    let width = text.split('\n').map(|line| line.chars().count()).max();
+    let width = width.unwrap_or(0);
+    for (idx, piece) in text.split('\n').enumerate() {
+        let row = top + idx;
+        let buffer = &mut state.text_buffer;
+        if row >= buffer.len_lines() {
+            buffer.insert(buffer.len_bytes(), "\n");
+        }
+        let len = buffer.line_len_chars(row);
+        let padded = format!("{piece:width$}");
+        let mut insert = " ".repeat(col.saturating_sub(len));
+        insert.push_str(&padded.repeat(count - 1));
+        // No trailing blanks if nothing comes after the block
+        insert.push_str(if col < len { &padded } else { piece });
+        if !insert.is_empty() {
+            let at = motion::col_to_byte(buffer, row, col);
+            buffer.insert(at, &insert);
+        }
+    }
+    state.modified = true;
+    state.cursor_row = top;
+    state.cursor_col = col;
+    state.desired_col = col;
+}
+
+fn word_object(state: &LogicState, big: bool, around: bool) -> Option<Selection> {
+    let line: Vec<char> = state.text_buffer.line(state.cursor_row).chars().collect();
+    if line.is_empty() {
+        return None;
+    }
+    let col = state.cursor_col.min(line.len() - 1);
+    let class = |idx: usize| motion::class(line[idx], big);
+    // Run of chars of the same class around `idx`
+    let run = |idx: usize| {
+        let k = class(idx);
+        let mut start = idx;
+        while start > 0 && class(start - 1) == k {
+            start -= 1;
+        }
+        let mut end = idx + 1;
+        while end < line.len() && class(end) == k {
+            end += 1;
+        }
+        (start, end)
+    };
+
+    let (mut start, mut end) = run(col);
+    if around {
+        if class(col) == CharClass::Blank {
+            if end < line.len() {
+                end = run(end).1;
+            }
+        } else if end < line.len() && class(end) == CharClass::Blank {
+            end = run(end).1;
+        } else if start > 0 && class(start - 1) == CharClass::Blank {
+            start = run(start - 1).0;
+        }
+    }
+
+    let buffer = &state.text_buffer;
+    let row = state.cursor_row;
+    Some(Selection::Chars(
+        motion::col_to_byte(buffer, row, start)..motion::col_to_byte(buffer, row, end),
+    ))
+}
+
+fn pair_object(
+    buffer: &Rope,
+    pos: usize,
+    open: char,
+    close: char,
+    around: bool,
+) -> Option<Selection> {
+    let open_pos = if buffer.char_at(pos) == Some(open) {
+        pos
+    } else {
+        let mut depth = 0_usize;
+        let (idx, _) = buffer.char_indices_before(pos).find(|&(_, c)| {
+            if c == close {
+                depth += 1;
+            } else if c == open {
+                if depth == 0 {
+                    return true;
+                }
+                depth -= 1;
+            }
+            false
+        })?;
+        idx
+    };
+
+    let mut depth = 0_usize;
+    let (close_pos, _) = buffer.char_indices_from(open_pos).find(|&(_, c)| {
+        if c == open {
+            depth += 1;
+        } else if c == close {
+            depth -= 1;
+            return depth == 0;
+        }
+        false
+    })?;
+
+    let range = if around {
+        open_pos..close_pos + close.len_utf8()
+    } else {
+        open_pos + open.len_utf8()..close_pos
+    };
+    Some(Selection::Chars(range))
+}
+
+fn quote_object(state: &LogicState, quote: char, around: bool) -> Option<Selection> {
+    let line: Vec<char> = state.text_buffer.line(state.cursor_row).chars().collect();
+    let mut quotes = Vec::new();
+    let mut escaped = false;
+    for (idx, &c) in line.iter().enumerate() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == quote {
+            quotes.push(idx);
+        }
+    }
+
+    // First string ending after the cursor, same as vim
+    let (open, close) = quotes
+        .chunks_exact(2)
+        .map(|pair| (pair[0], pair[1]))
+        .find(|&(_, close)| state.cursor_col <= close)?;
+
+    let (mut start, mut end) = if around {
+        (open, close + 1)
+    } else {
+        (open + 1, close)
+    };
+    if around {
+        let trailing_start = end;
+        while end < line.len() && line[end].is_whitespace() {
+            end += 1;
+        }
+        if end == trailing_start {
+            while start > 0 && line[start - 1].is_whitespace() {
+                start -= 1;
+            }
+        }
+    }
+
+    let buffer = &state.text_buffer;
+    let row = state.cursor_row;
+    Some(Selection::Chars(
+        motion::col_to_byte(buffer, row, start)..motion::col_to_byte(buffer, row, end),
+    ))
+}
+
+/// Selects the text of a syntax node without the blanks around it, as whole
+/// lines when nothing else is on them
+fn node_selection(buffer: &Rope, range: Range<usize>) -> Option<Selection> {
+    let text = buffer.slice(range.clone());
+    let start = range.start + text.len() - text.trim_start().len();
+    let end = range.start + text.trim_end().len();
+    if start >= end {
+        return None;
+    }
+    let (first, last) = (buffer.byte_to_line(start), buffer.byte_to_line(end - 1));
+    let before = buffer.slice(buffer.line_to_byte(first)..start);
+    let after = buffer.slice(end..buffer.line_range(last).end);
+    if before.trim().is_empty() && after.trim().is_empty() {
+        Some(Selection::Lines(first..last + 1))
+    } else {
+        Some(Selection::Chars(start..end))
+    }
+}
+
+fn paragraph_object(buffer: &Rope, row: usize, around: bool) -> Selection {
+    let line_count = buffer.len_lines();
+    let blank = |r| motion::is_blank_line(buffer, r);
+    let on_blank = blank(row);
+
+    let mut start = row;
+    while start > 0 && blank(start - 1) == on_blank {
+        start -= 1;
+    }
+    let mut end = row + 1;
+    while end < line_count && blank(end) == on_blank {
+        end += 1;
+    }
+
+    if around {
+        if end < line_count {
+            let next_blank = blank(end);
+            while end < line_count && blank(end) == next_blank {
+                end += 1;
+            }
+        } else if !on_blank {
+            while start > 0 && blank(start - 1) {
+                start -= 1;
+            }
+        }
+    }
+    Selection::Lines(start..end)
+}
+
+/// Finds the innermost `<tag>...</tag>` block around `pos`
+fn tag_object(buffer: &Rope, pos: usize, around: bool) -> Option<Selection> {
+    let text = buffer.to_string();
+    let is_name = |name: &str| {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_alphanumeric() || "-_:.".contains(c))
+    };
+
+    // Open tags not closed yet: name, start of the open tag, end of the open tag
+    let mut stack: Vec<(&str, usize, usize)> = Vec::new();
+    let mut search_from = 0;
+    while let Some(offset) = text[search_from..].find('<') {
+        let start = search_from + offset;
+        search_from = start + 1;
+        let end = match text[start..].find('>') {
+            Some(len) => start + len + 1,
+            None => break,
+        };
+        let inner = &text[start + 1..end - 1];
+
+        if let Some(name) = inner.strip_prefix('/') {
+            let name = name.trim();
+            if !is_name(name) {
+                continue;
+            }
+            search_from = end;
+            if let Some(idx) = stack.iter().rposition(|(open, ..)| *open == name) {
+                let (_, open_start, open_end) = stack[idx];
+                stack.truncate(idx);
+                // Tags close from the inside out, so the first match is the
+                // innermost one
+                if open_start <= pos && pos < end {
+                    let range = if around {
+                        open_start..end
+                    } else {
+                        open_end..start
+                    };
+                    return Some(Selection::Chars(range));
+                }
+            }
+        } else if !inner.ends_with('/') {
+            let name = inner.split(char::is_whitespace).next().unwrap_or("");
+            if is_name(name) {
+                search_from = end;
+                stack.push((name, start, end));
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// State editing `text`, with `yank` in the unnamed register and the
+    /// cursor at `row` and `col`
+    fn with_yank(text: &str, yank: Yank, row: usize, col: usize) -> LogicState<'static> {
+        let mut state = LogicState::new(Rope::from(text));
+        state.registers.yank(None, yank);
+        state.cursor_row = row;
+        state.cursor_col = col;
+        state
+    }
+
+    /// Text left once the text object `keys` is deleted from `text`, with
+    /// the cursor at `row` and `col`
+    fn delete(text: &str, (row, col): (usize, usize), keys: &str) -> Option<String> {
+        let mut state = LogicState::new(Rope::from(text));
+        state.cursor_row = row;
+        state.cursor_col = col;
+        let Parsed::Done(object) = TextObject::parse(keys) else {
+            panic!("{keys} is not a text object");
+        };
+        let selection = object.selection(&state)?;
+        apply(&mut state, Operator::Delete, selection, None);
+        Some(state.text_buffer.to_string())
+    }
+
+    #[test]
+    fn text_objects() {
+        assert_eq!(
+            delete("foo bar baz", (0, 4), "iw").as_deref(),
+            Some("foo  baz")
+        );
+        assert_eq!(
+            delete("foo bar baz", (0, 4), "aw").as_deref(),
+            Some("foo baz")
+        );
+        // Without blanks after the word, `aw` takes the ones before it
+        assert_eq!(
+            delete("foo bar baz", (0, 10), "aw").as_deref(),
+            Some("foo bar")
+        );
+        assert_eq!(delete("a.b c", (0, 0), "aW").as_deref(), Some("c"));
+        assert_eq!(delete("", (0, 0), "iw"), None);
+
+        let call = "f(a, (b), c) x";
+        assert_eq!(delete(call, (0, 4), "i(").as_deref(), Some("f() x"));
+        assert_eq!(delete(call, (0, 4), "ab").as_deref(), Some("f x"));
+        assert_eq!(delete(call, (0, 6), "i)").as_deref(), Some("f(a, (), c) x"));
+        assert_eq!(delete(call, (0, 13), "i("), None);
+        assert_eq!(delete("[a [b]]", (0, 1), "a[").as_deref(), Some(""));
+
+        let quoted = "say \"hi there\" ok";
+        assert_eq!(
+            delete(quoted, (0, 0), "i\"").as_deref(),
+            Some("say \"\" ok")
+        );
+        assert_eq!(delete(quoted, (0, 5), "a\"").as_deref(), Some("say ok"));
+        assert_eq!(delete(quoted, (0, 5), "i'"), None);
+
+        let paragraphs = "a\nb\n\nc\nd";
+        assert_eq!(delete(paragraphs, (0, 0), "ap").as_deref(), Some("c\nd"));
+        assert_eq!(delete(paragraphs, (4, 0), "ip").as_deref(), Some("a\nb\n"));
+
+        let tags = "<a><b>x</b> y</a>";
+        assert_eq!(
+            delete(tags, (0, 6), "it").as_deref(),
+            Some("<a><b></b> y</a>")
+        );
+        assert_eq!(delete(tags, (0, 6), "at").as_deref(), Some("<a> y</a>"));
+        assert_eq!(delete(tags, (0, 12), "at").as_deref(), Some(""));
+        assert_eq!(delete("<br/> x", (0, 6), "it"), None);
+
+        assert!(matches!(TextObject::parse("i"), Parsed::Pending));
+        assert!(matches!(TextObject::parse("iq"), Parsed::Invalid));
+    }
+
+    #[test]
+    fn block_selections() {
+        let text = "abcd\nx\néfgh\n";
+        let block = Selection::Block {
+            rows: 0..3,
+            cols: 1..3,
+        };
+        // Short lines give what they have, chars count as one column
+        let mut state = LogicState::new(Rope::from(text));
+        assert_eq!(
+            yank(&state.text_buffer, &block),
+            Yank::new("bc\n\nfg", YankKind::Block)
+        );
+        state.cursor_row = 2;
+        apply(&mut state, Operator::Yank, block.clone(), None);
+        assert_eq!((state.cursor_row, state.cursor_col), (0, 1));
+        assert_eq!(state.text_buffer.to_string(), text);
+        assert!(!state.modified);
+
+        apply(&mut state, Operator::Delete, block.clone(), None);
+        assert_eq!(state.text_buffer.to_string(), "ad\nx\néh\n");
+        assert_eq!((state.cursor_row, state.cursor_col), (0, 1));
+        assert_eq!(
+            state.registers.get('"'),
+            Some(Yank::new("bc\n\nfg", YankKind::Block))
+        );
+        assert!(state.modified);
+
+        // Past the end of every line, there is nothing to delete
+        let mut state = LogicState::new(Rope::from(text));
+        let past = Selection::Block {
+            rows: 0..2,
+            cols: 5..7,
+        };
+        apply(&mut state, Operator::Delete, past, None);
+        assert_eq!(state.text_buffer.to_string(), text);
+        assert!(!state.modified);
+
+        let mut state = LogicState::new(Rope::from(text));
+        apply(&mut state, Operator::Change, block, None);
+        assert_eq!(state.text_buffer.to_string(), "ad\nx\néh\n");
+        assert!(state.mode == crate::EditorMode::Insert);
+    }
+
+    #[test]
+    fn change_word_huge_count() {
+        let state = LogicState::new(Rope::from("foo bar baz"));
+        let motion = Motion::WordStart { big: false };
+        let selection = motion_selection(&state, Operator::Change, motion, Some(2));
+        assert_eq!(selection, Some(Selection::Chars(0..7)));
+        let selection = motion_selection(&state, Operator::Change, motion, Some(99_999_999_999));
+        assert_eq!(selection, Some(Selection::Chars(0..11)));
+    }
+
+    #[test]
+    fn put_counts() {
+        let mut state = with_yank("ab", Yank::new("xy", YankKind::Chars), 0, 0);
+        put(&mut state, false, Some(3), None);
+        assert_eq!(state.text_buffer.to_string(), "axyxyxyb");
+        assert_eq!((state.cursor_row, state.cursor_col), (0, 6));
+
+        let mut state = with_yank("a\nb", Yank::new("l\n", YankKind::Lines), 1, 0);
+        put(&mut state, false, Some(2), None);
+        assert_eq!(state.text_buffer.to_string(), "a\nb\nl\nl");
+        assert_eq!((state.cursor_row, state.cursor_col), (2, 0));
+        put(&mut state, true, None, None);
+        assert_eq!(state.text_buffer.to_string(), "a\nb\nl\nl\nl");
+    }
+
+    #[test]
+    fn put_huge_counts() {
+        for kind in [YankKind::Chars, YankKind::Lines, YankKind::Block] {
+            let mut state = with_yank("ab", Yank::new("xy\n", kind), 0, 0);
+            for count in [99_999_999_999, usize::MAX] {
+                put(&mut state, false, Some(count), None);
+                assert_eq!(state.text_buffer.to_string(), "ab");
+                assert_eq!(
+                    state.message.as_deref(),
+                    Some("E1240: Resulting text too long")
+                );
+                assert!(!state.modified);
+            }
+        }
+    }
+
+    #[test]
+    fn put_blocks() {
+        let block = || Yank::new("12\n3\n456", YankKind::Block);
+        // Short lines get padded up to the column, and missing ones added
+        let mut state = with_yank("abc\nd", block(), 0, 1);
+        put(&mut state, false, None, None);
+        assert_eq!(state.text_buffer.to_string(), "ab12 c\nd 3\n  456");
+        assert_eq!((state.cursor_row, state.cursor_col), (0, 2));
+
+        // Before the cursor, repeated, and without trailing blanks at the
+        // ends of lines
+        let mut state = with_yank("abc\nd\nef", block(), 0, 1);
+        put(&mut state, true, Some(2), None);
+        assert_eq!(state.text_buffer.to_string(), "a12 12 bc\nd3  3\ne456456f");
+
+        // Empty lines take the block at their start
+        let mut state = with_yank("\n\n", block(), 0, 0);
+        put(&mut state, false, None, None);
+        assert_eq!(state.text_buffer.to_string(), "12\n3\n456");
+    }
+}
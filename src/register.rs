@@ -0,0 +1,292 @@
+use std::{collections::BTreeMap, mem, rc::Rc};
+
+use crate::rope::Rope;
+
+/// How a yank is put back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum YankKind {
+    /// Put inside the cursor's line
+    Chars,
+    /// Whole lines, put below or above the cursor's
+    Lines,
+    /// A rectangle with one line of text per row, put at the cursor's column
+    Block,
+}
+
+/// Text stored by `y`, `d` and `c`, and put back by `p`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Yank {
+    pub text: Rc<str>,
+    pub kind: YankKind,
+}
+
+impl Yank {
+    pub fn new(text: impl Into<Rc<str>>, kind: YankKind) -> Self {
+        Self {
+            text: text.into(),
+            kind,
+        }
+    }
+
+    /// Appends `other`, as `"A` to `"Z` do. Anything linewise makes the
+    /// result linewise.
+    fn append(&self, other: &Yank) -> Yank {
+        let (old, new) = (&*self.text, &*other.text);
+        match (self.kind, other.kind) {
+            (YankKind::Chars, YankKind::Chars) => Yank::new(format!("{old}{new}"), YankKind::Chars),
+            (YankKind::Lines, _) => {
+                let newline = if new.ends_with('\n') { "" } else { "\n" };
+                Yank::new(format!("{old}{new}{newline}"), YankKind::Lines)
+            }
+            (_, YankKind::Lines) => Yank::new(format!("{old}\n{new}"), YankKind::Lines),
+            (kind, _) => Yank::new(format!("{old}\n{new}"), kind),
+        }
+    }
+}
+
+/// Vim-like registers:
+/// - `""`, the unnamed register, is whichever register was written last
+/// - `"0` holds the last yank
+/// - `"1` to `"9` hold the last deletions spanning lines, newest first
+/// - `"-` holds the last deletion inside a line
+/// - `"a` to `"z` are only written when named, `"A` to `"Z` append to them
+/// - `"+` is the system clipboard
+/// - `".` (last inserted text), `":` (last command) and `"/` (last search)
+///   are read-only
+#[derive(Clone, PartialEq, Eq, Default)]
+pub struct Registers {
+    /// Shared between the copies of the editor state until written
+    contents: Rc<BTreeMap<char, Yank>>,
+    unnamed: Option<char>,
+    /// Whether `"+` was written since it was last copied to the clipboard
+    clipboard_dirty: bool,
+    /// `".`, kept apart as it grows with every key typed in insert mode
+    inserted: Rope,
+}
+
+impl Registers {
+    /// Whether `name` can follow `"` in front of a command
+    pub fn is_valid(name: char) -> bool {
+        name.is_ascii_alphanumeric() || matches!(name, '"' | '-' | '+' | '.' | ':' | '/')
+    }
+
+    pub fn is_read_only(name: char) -> bool {
+        matches!(name, '.' | ':' | '/')
+    }
+
+    pub fn get(&self, name: char) -> Option<Yank> {
+        let name = match name {
+            '"' => self.unnamed?,
+            '.' => return Some(Yank::new(self.inserted.to_string(), YankKind::Chars)),
+            name => name.to_ascii_lowercase(),
+        };
+        self.contents.get(&name).cloned()
+    }
+
+    /// Stores yanked text in register `name`, `"0` if there is none
+    pub fn yank(&mut self, name: Option<char>, yank: Yank) {
+        let name = name.filter(|&name| name != '"');
+        self.write(name.unwrap_or('0'), yank);
+    }
+
+    /// Stores deleted text in register `name`. Without one, it goes in the
+    /// numbered ring if it spans lines, in `"-` otherwise.
+    pub fn delete(&mut self, name: Option<char>, yank: Yank) {
+        if let Some(name) = name.filter(|&name| name != '"') {
+            self.write(name, yank);
+        } else if yank.kind == YankKind::Chars && !yank.text.contains('\n') {
+            self.write('-', yank);
+        } else {
+            let contents = Rc::make_mut(&mut self.contents);
+            for n in (1..9).rev() {
+                let (from, to) = (digit(n), digit(n + 1));
+                match contents.remove(&from) {
+                    Some(old) => contents.insert(to, old),
+                    None => contents.remove(&to),
+                };
+            }
+            self.write('1', yank);
+        }
+    }
+
+    fn write(&mut self, name: char, yank: Yank) {
+        if Self::is_read_only(name) {
+            return;
+        }
+        let lower = name.to_ascii_lowercase();
+        let contents = Rc::make_mut(&mut self.contents);
+        let yank = match contents.get(&lower) {
+            Some(old) if name.is_ascii_uppercase() => old.append(&yank),
+            _ => yank,
+        };
+        contents.insert(lower, yank);
+        self.unnamed = Some(lower);
+        self.clipboard_dirty |= lower == '+';
+    }
+
+    /// Sets one of the read-only registers
+    pub fn set_read_only(&mut self, name: char, text: &str) {
+        debug_assert!(Self::is_read_only(name));
+        Rc::make_mut(&mut self.contents).insert(name, Yank::new(text, YankKind::Chars));
+    }
+
+    /// Empties `".` for a new insert session
+    pub fn start_insert(&mut self) {
+        self.inserted = Rope::new();
+    }
+
+    /// Adds text typed in insert mode to `".`
+    pub fn record_insert(&mut self, text: &str) {
+        self.inserted.insert(self.inserted.len_bytes(), text);
+    }
+
+    /// Removes the last char typed in insert mode from `".`
+    pub fn record_backspace(&mut self) {
+        let end = self.inserted.len_bytes();
+        let last = self.inserted.char_indices_before(end).next();
+        if let Some((start, _)) = last {
+            self.inserted.delete(start..end);
+        }
+    }
+
+    /// Sets `"+` to the current content of the system clipboard
+    pub fn load_clipboard(&mut self, text: String) {
+        if self.get('+').is_some_and(|yank| *yank.text == *text) {
+            return;
+        }
+        let kind = if text.ends_with('\n') {
+            YankKind::Lines
+        } else {
+            YankKind::Chars
+        };
+        Rc::make_mut(&mut self.contents).insert('+', Yank::new(text, kind));
+    }
+
+    /// Text to copy to the system clipboard, if `"+` was written since the
+    /// last call
+    pub fn take_clipboard(&mut self) -> Option<Rc<str>> {
+        if !mem::take(&mut self.clipboard_dirty) {
+            return None;
+        }
+        self.get('+').map(|yank| yank.text)
+    }
+}
+
+fn digit(n: u32) -> char {
+    char::from_digit(n, 10).expect("Register numbers are single digits")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chars(text: &str) -> Yank {
+        Yank::new(text, YankKind::Chars)
+    }
+
+    fn lines(text: &str) -> Yank {
+        Yank::new(text, YankKind::Lines)
+    }
+
+    /// Text of register `name`, if it is set
+    fn text(registers: &Registers, name: char) -> Option<String> {
+        registers.get(name).map(|yank| yank.text.to_string())
+    }
+
+    #[test]
+    fn yanks_and_deletes() {
+        let mut registers = Registers::default();
+        assert_eq!(registers.get('"'), None);
+        registers.yank(None, chars("y"));
+        registers.delete(None, chars("word"));
+        assert_eq!(text(&registers, '0').as_deref(), Some("y"));
+        assert_eq!(text(&registers, '-').as_deref(), Some("word"));
+        assert_eq!(text(&registers, '"').as_deref(), Some("word"));
+        assert_eq!(text(&registers, '1'), None);
+
+        // Deletions spanning lines go down the numbered ring, the tenth
+        // one dropping the oldest
+        for n in 1..=10 {
+            registers.delete(None, lines(&format!("{n}\n")));
+        }
+        registers.delete(None, chars("a\nb"));
+        assert_eq!(text(&registers, '1').as_deref(), Some("a\nb"));
+        assert_eq!(text(&registers, '2').as_deref(), Some("10\n"));
+        assert_eq!(text(&registers, '9').as_deref(), Some("3\n"));
+        assert_eq!(text(&registers, '-').as_deref(), Some("word"));
+        assert_eq!(text(&registers, '"').as_deref(), Some("a\nb"));
+
+        // Named registers leave the others alone
+        registers.delete(Some('a'), lines("x\n"));
+        registers.yank(Some('"'), chars("z"));
+        assert_eq!(text(&registers, 'a').as_deref(), Some("x\n"));
+        assert_eq!(text(&registers, '0').as_deref(), Some("z"));
+        assert_eq!(text(&registers, '1').as_deref(), Some("a\nb"));
+    }
+
+    #[test]
+    fn uppercase_appends() {
+        let mut registers = Registers::default();
+        registers.yank(Some('A'), chars("new"));
+        assert_eq!(registers.get('a'), Some(chars("new")));
+        registers.yank(Some('A'), chars("er"));
+        assert_eq!(registers.get('a'), Some(chars("newer")));
+        registers.yank(Some('A'), lines("line\n"));
+        assert_eq!(registers.get('A'), Some(lines("newer\nline\n")));
+        registers.yank(Some('A'), chars("end"));
+        assert_eq!(registers.get('a'), Some(lines("newer\nline\nend\n")));
+
+        registers.yank(Some('b'), Yank::new("1\n2", YankKind::Block));
+        registers.yank(Some('B'), chars("3"));
+        assert_eq!(
+            registers.get('b'),
+            Some(Yank::new("1\n2\n3", YankKind::Block))
+        );
+        assert_eq!(registers.get('"'), registers.get('b'));
+    }
+
+    #[test]
+    fn read_only_registers() {
+        let mut registers = Registers::default();
+        registers.yank(Some(':'), chars("nope"));
+        registers.delete(Some('/'), chars("nope"));
+        assert_eq!(registers.get(':'), None);
+        assert_eq!(registers.get('"'), None);
+
+        registers.set_read_only(':', "w");
+        registers.set_read_only('/', "foo");
+        assert_eq!(text(&registers, ':').as_deref(), Some("w"));
+        assert_eq!(text(&registers, '/').as_deref(), Some("foo"));
+        assert_eq!(registers.get('"'), None);
+
+        registers.start_insert();
+        registers.record_insert("héllo");
+        registers.record_backspace();
+        registers.record_backspace();
+        assert_eq!(text(&registers, '.').as_deref(), Some("hél"));
+        registers.record_backspace();
+        registers.record_backspace();
+        registers.record_insert("ip");
+        assert_eq!(text(&registers, '.').as_deref(), Some("hip"));
+        registers.start_insert();
+        registers.record_backspace();
+        assert_eq!(text(&registers, '.').as_deref(), Some(""));
+    }
+
+    #[test]
+    fn clipboard() {
+        let mut registers = Registers::default();
+        assert_eq!(registers.take_clipboard(), None);
+        registers.load_clipboard("line\n".to_string());
+        assert_eq!(registers.get('+'), Some(lines("line\n")));
+        // Only what was written goes back to the clipboard
+        assert_eq!(registers.take_clipboard(), None);
+
+        registers.yank(Some('+'), chars("copied"));
+        assert_eq!(registers.take_clipboard().as_deref(), Some("copied"));
+        assert_eq!(registers.take_clipboard(), None);
+        registers.load_clipboard("copied".to_string());
+        assert_eq!(registers.take_clipboard(), None);
+        assert_eq!(registers.get('"'), Some(chars("copied")));
+    }
+}
@@ -0,0 +1,367 @@
+use std::{
+    io::{self, Write},
+    ptr,
+};
+
+use gl::types::GLuint;
+
+use crate::{
+    atlas::{Format, GlyphAtlas, GlyphMode},
+    check_err,
+    shader::Shader,
+};
+
+/// Corners of a rectangle, in the order `Shader::upload_rectangles` takes
+/// them: top right, bottom right, bottom left, top left
+pub type Quad<const N: usize> = [[f32; N]; 4];
+
+/// Where frames get drawn. Coordinates are in pixels of the text at scale 1,
+/// going right and down from the left of the line at `y_center`.
+pub trait Renderer {
+    /// Starts a frame of `size` pixels, filled with `colour`
+    fn clear(&mut self, size: (u32, u32), colour: [f32; 4]);
+    /// Sets the zoom, and which y coordinate is shown in the middle of the
+    /// screen
+    fn set_camera(&mut self, scale: f32, y_center: f32);
+    /// Draws plain rectangles, with vertices of `[x, y, r, g, b, a]`
+    fn draw_rectangles(&mut self, rectangles: &[Quad<6>]);
+    /// Draws glyphs of `atlas`, with vertices of `[x, y, s, t, page, format,
+    /// r, g, b, a]`, `s` and `t` being coordinates in that page of the atlas
+    /// bitmap
+    fn draw_glyphs(&mut self, atlas: &GlyphAtlas, glyphs: &[Quad<10>]);
+}
+
+/// Glyph vertices of `[x, y, s, t, page, format]`, as the atlas gives them,
+/// in `colour`
+pub fn coloured(glyph: Quad<6>, [r, g, b, a]: [f32; 4]) -> Quad<10> {
+    glyph.map(|[x, y, s, t, page, format]| [x, y, s, t, page, format, r, g, b, a])
+}
+
+/// How many glyph maps keep their textures, so that switching between scales
+/// doesn't upload them again
+const ATLAS_TEXTURES: usize = 4;
+
+/// Draws to the current GL context
+pub struct GlRenderer {
+    vbo: GLuint,
+    /// Textures of the glyph maps drawn last, the most recent at the end
+    textures: Vec<AtlasTextures>,
+    text_shader: Shader<10>,
+    sdf_text_shader: Shader<10>,
+    shape_shader: Shader<6>,
+}
+
+/// Texture arrays of the gray and RGBA pages of a glyph map
+struct AtlasTextures {
+    map: u64,
+    textures: [GLuint; 2],
+    /// How many layers each texture has allocated
+    layers: [usize; 2],
+}
+
+impl AtlasTextures {
+    unsafe fn new(map: u64) -> Self {
+        let mut textures = [0; 2];
+        gl::GenTextures(2, textures.as_mut_ptr());
+        for texture in textures {
+            gl::BindTexture(gl::TEXTURE_2D_ARRAY, texture);
+
+            // wrapping params
+            gl::TexParameteri(
+                gl::TEXTURE_2D_ARRAY,
+                gl::TEXTURE_WRAP_S,
+                gl::CLAMP_TO_EDGE as i32,
+            );
+            gl::TexParameteri(
+                gl::TEXTURE_2D_ARRAY,
+                gl::TEXTURE_WRAP_T,
+                gl::CLAMP_TO_EDGE as i32,
+            );
+            // filtering params
+            gl::TexParameteri(
+                gl::TEXTURE_2D_ARRAY,
+                gl::TEXTURE_MIN_FILTER,
+                gl::LINEAR as i32,
+            );
+            gl::TexParameteri(
+                gl::TEXTURE_2D_ARRAY,
+                gl::TEXTURE_MAG_FILTER,
+                gl::LINEAR as i32,
+            );
+        }
+        Self {
+            map,
+            textures,
+            layers: [0; 2],
+        }
+    }
+}
+
+impl Drop for AtlasTextures {
+    fn drop(&mut self) {
+        unsafe { gl::DeleteTextures(2, self.textures.as_ptr()) };
+    }
+}
+
+impl GlRenderer {
+    /// ### Safety
+    /// Needs a current GL context with its functions loaded, which has to
+    /// outlive the renderer
+    pub unsafe fn new() -> Self {
+        // setup blending
+        gl::Enable(gl::BLEND);
+        gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+
+        let mut vbo = 0;
+        gl::GenBuffers(1, &mut vbo);
+        check_err();
+
+        let text_shader = Shader::text_shader(vbo);
+        let sdf_text_shader = Shader::sdf_text_shader(vbo);
+        let shape_shader = Shader::shape_shader(vbo);
+
+        // Gray pages are bound to the first unit, RGBA ones to the second
+        for shader in [&text_shader, &sdf_text_shader] {
+            shader.r#use();
+            shader.uniform1i("grayPages", 0);
+            shader.uniform1i("rgbaPages", 1);
+        }
+
+        Self {
+            vbo,
+            textures: Vec::new(),
+            text_shader,
+            sdf_text_shader,
+            shape_shader,
+        }
+    }
+}
+
+impl Renderer for GlRenderer {
+    fn clear(&mut self, (width, height): (u32, u32), [r, g, b, a]: [f32; 4]) {
+        unsafe {
+            gl::Viewport(0, 0, width as i32, height as i32);
+            gl::ClearColor(r, g, b, a);
+            gl::Clear(gl::COLOR_BUFFER_BIT);
+        }
+        let size = [width as i32, height as i32];
+        self.shape_shader.r#use();
+        self.shape_shader.uniform2i("screenSize", size);
+        for shader in [&self.text_shader, &self.sdf_text_shader] {
+            shader.r#use();
+            shader.uniform2i("screenSize", size);
+        }
+    }
+
+    fn set_camera(&mut self, scale: f32, y_center: f32) {
+        self.shape_shader.r#use();
+        self.shape_shader.uniform1f("scale", scale);
+        self.shape_shader.uniform1f("yCenter", y_center);
+        for shader in [&self.text_shader, &self.sdf_text_shader] {
+            shader.r#use();
+            shader.uniform1f("scale", scale);
+            shader.uniform1f("yCenter", y_center);
+        }
+    }
+
+    fn draw_rectangles(&mut self, rectangles: &[Quad<6>]) {
+        if rectangles.is_empty() {
+            return;
+        }
+        self.shape_shader.r#use();
+        self.shape_shader.upload_rectangles(rectangles);
+        draw_elements(rectangles.len());
+    }
+
+    fn draw_glyphs(&mut self, atlas: &GlyphAtlas, glyphs: &[Quad<10>]) {
+        let bitmap = atlas.bitmap();
+        let cached = self.textures.iter().position(|t| t.map == bitmap.id());
+        let mut textures = match cached {
+            Some(idx) => self.textures.remove(idx),
+            None => {
+                if self.textures.len() >= ATLAS_TEXTURES {
+                    self.textures.remove(0);
+                }
+                unsafe { AtlasTextures::new(bitmap.id()) }
+            }
+        };
+        unsafe {
+            bitmap.upload(textures.textures, &mut textures.layers, cached.is_none());
+            for (unit, texture) in (gl::TEXTURE0..).zip(textures.textures) {
+                gl::ActiveTexture(unit);
+                gl::BindTexture(gl::TEXTURE_2D_ARRAY, texture);
+            }
+        }
+        self.textures.push(textures);
+        if glyphs.is_empty() {
+            return;
+        }
+        let shader = match atlas.mode() {
+            GlyphMode::Bitmap => &self.text_shader,
+            GlyphMode::Sdf => &self.sdf_text_shader,
+        };
+        shader.r#use();
+        shader.upload_rectangles(glyphs);
+        check_err();
+        draw_elements(glyphs.len());
+        check_err();
+    }
+}
+
+impl Drop for GlRenderer {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteBuffers(1, &self.vbo);
+        }
+    }
+}
+
+fn draw_elements(rectangle_count: usize) {
+    unsafe {
+        gl::DrawElements(
+            gl::TRIANGLES,
+            (rectangle_count * 6) as i32,
+            gl::UNSIGNED_INT,
+            ptr::null(),
+        );
+    }
+}
+
+/// Draws to an RGBA framebuffer in memory, without a GPU or a window. It
+/// does what the shaders do, without their filtering: pixels are either in a
+/// rectangle or out of it, and glyphs are sampled at the nearest texel.
+pub struct CpuRenderer {
+    width: usize,
+    height: usize,
+    pixels: Vec<[u8; 4]>,
+    scale: f32,
+    y_center: f32,
+}
+
+impl CpuRenderer {
+    pub fn new() -> Self {
+        Self {
+            width: 0,
+            height: 0,
+            pixels: Vec::new(),
+            scale: 1.,
+            y_center: 0.,
+        }
+    }
+
+    /// Writes the framebuffer as a binary PPM image, dropping the alpha
+    pub fn write_ppm(&self, mut out: impl Write) -> io::Result<()> {
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+        let rgb: Vec<_> = self
+            .pixels
+            .iter()
+            .flat_map(|p| [p[0], p[1], p[2]])
+            .collect();
+        out.write_all(&rgb)
+    }
+
+    /// Where `(x, y)` lands on the screen, in pixels from the top left. The
+    /// same transform as the vertex shaders.
+    fn to_screen(&self, x: f32, y: f32) -> (f32, f32) {
+        let screen_y = (y - self.y_center) * self.scale + self.height as f32 / 2.;
+        (x * self.scale, screen_y)
+    }
+
+    /// Pixels whose centre is in the rectangle between two opposite corners,
+    /// given in screen coordinates
+    fn covered(
+        &self,
+        (x1, y1): (f32, f32),
+        (x2, y2): (f32, f32),
+    ) -> impl Iterator<Item = (usize, usize)> {
+        let first = |v: f32, max: usize| ((v - 0.5).ceil().max(0.) as usize).min(max);
+        let (cols, rows) = (
+            first(x1.min(x2), self.width)..first(x1.max(x2), self.width),
+            first(y1.min(y2), self.height)..first(y1.max(y2), self.height),
+        );
+        rows.flat_map(move |row| cols.clone().map(move |col| (col, row)))
+    }
+
+    /// Blends `colour` over a pixel, like `SRC_ALPHA, ONE_MINUS_SRC_ALPHA`
+    fn blend(&mut self, col: usize, row: usize, colour: [f32; 4]) {
+        let alpha = colour[3];
+        let pixel = &mut self.pixels[row * self.width + col];
+        for (channel, src) in pixel.iter_mut().zip(colour) {
+            let dst = f32::from(*channel) / 255.;
+            let blended = src * alpha + dst * (1. - alpha);
+            *channel = (blended.clamp(0., 1.) * 255.).round() as u8;
+        }
+    }
+}
+
+impl Default for CpuRenderer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Renderer for CpuRenderer {
+    fn clear(&mut self, (width, height): (u32, u32), colour: [f32; 4]) {
+        self.width = width as usize;
+        self.height = height as usize;
+        let colour = colour.map(|c| (c.clamp(0., 1.) * 255.).round() as u8);
+        self.pixels = vec![colour; self.width * self.height];
+    }
+
+    fn set_camera(&mut self, scale: f32, y_center: f32) {
+        self.scale = scale;
+        self.y_center = y_center;
+    }
+
+    fn draw_rectangles(&mut self, rectangles: &[Quad<6>]) {
+        for [top_right, _, bottom_left, _] in rectangles {
+            // Rectangles are all of one colour
+            let [x2, y1, r, g, b, a] = *top_right;
+            let [x1, y2, ..] = *bottom_left;
+            let (from, to) = (self.to_screen(x1, y1), self.to_screen(x2, y2));
+            let pixels: Vec<_> = self.covered(from, to).collect();
+            for (col, row) in pixels {
+                self.blend(col, row, [r, g, b, a]);
+            }
+        }
+    }
+
+    fn draw_glyphs(&mut self, atlas: &GlyphAtlas, glyphs: &[Quad<10>]) {
+        let bitmap = atlas.bitmap();
+        let size = bitmap.page_size();
+        for [top_right, _, bottom_left, _] in glyphs {
+            // Glyphs are all of one colour
+            let [x2, y1, s2, t1, page, format, r, g, b, a] = *top_right;
+            let colour = format == 2.;
+            let format = if format == 0. {
+                Format::Gray
+            } else {
+                Format::Rgba
+            };
+            let [x1, y2, s1, t2, ..] = *bottom_left;
+            let (from, to) = (self.to_screen(x1, y1), self.to_screen(x2, y2));
+            // How many texels a pixel spans
+            let texels = (s2 - s1) * size as f32 / (to.0 - from.0);
+            let pixels: Vec<_> = self.covered(from, to).collect();
+            for (col, row) in pixels {
+                // Position of the pixel centre in the glyph, from 0 to 1
+                let u = (col as f32 + 0.5 - from.0) / (to.0 - from.0);
+                let v = (row as f32 + 0.5 - from.1) / (to.1 - from.1);
+                let s = s1 + u * (s2 - s1);
+                let t = t1 + v * (t2 - t1);
+                let texel_x = ((s * size as f32) as usize).min(size - 1);
+                let texel_y = ((t * size as f32) as usize).min(size - 1);
+                let page = page as usize;
+                // Colour glyphs aren't tinted, only faded
+                if colour {
+                    let [r, g, b, alpha] = bitmap.colour(page, texel_x, texel_y);
+                    self.blend(col, row, [r, g, b, a * alpha]);
+                    continue;
+                }
+                let texel = bitmap.texel(format, page, texel_x, texel_y);
+                let coverage = bitmap.coverage(texel, texels);
+                self.blend(col, row, [r, g, b, a * coverage]);
+            }
+        }
+    }
+}
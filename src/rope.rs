@@ -1,53 +1,807 @@
-#![allow(unused)] // remove when finished
-use std::rc::Rc;
+#![allow(dead_code)] // Not all of the API is used by the editor yet
+use std::{
+    fmt,
+    io::{self, Write},
+    ops::{Add, Range},
+    rc::Rc,
+};
 
 /// Determines the minimum leaf length when concatenating. I.e., any string with
-/// fewer than `MIN_LEAF_LENGTH` graphemes is considered "short"
-const MIN_LEAF_LENGTH: u32 = 4096;
+/// fewer than `MIN_LEAF_LENGTH` bytes is considered "short"
+const MIN_LEAF_LENGTH: usize = 4096;
+/// Leaves are never built longer than this when splitting up large strings
+const MAX_LEAF_LENGTH: usize = 2 * MIN_LEAF_LENGTH;
+/// Edits that make the tree deeper than this trigger a rebalance
+const MAX_DEPTH: u32 = 48;
 
-enum Rope {
+/// Summary of a piece of text, used to index the rope by byte, char or line.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Weight {
+    bytes: usize,
+    chars: usize,
+    /// Number of `\n` characters
+    newlines: usize,
+}
+
+impl Weight {
+    fn of(s: &str) -> Self {
+        Self {
+            bytes: s.len(),
+            chars: s.chars().count(),
+            newlines: s.bytes().filter(|&b| b == b'\n').count(),
+        }
+    }
+}
+
+impl Add for Weight {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        Self {
+            bytes: self.bytes + rhs.bytes,
+            chars: self.chars + rhs.chars,
+            newlines: self.newlines + rhs.newlines,
+        }
+    }
+}
+
+/// Persistent rope. Nodes are shared through `Rc`, so cloning is cheap and
+/// edits only copy the path from the root to the edited leaves.
+///
+/// All positions are byte offsets unless the method name says otherwise, and
+/// must lie on char boundaries.
+#[derive(Clone)]
+pub enum Rope {
     Concat {
         left: Rc<Rope>,
         right: Rc<Rope>,
-        weight: u32,
+        /// Weight of the left subtree
+        weight: Weight,
+        /// Weight of the whole subtree
+        total: Weight,
+        depth: u32,
     },
     Leaf(Rc<str>),
 }
 
 use Rope::*;
 impl Rope {
-    fn concat(rope1: Self, rope2: Self) -> Self {
-        use Rope::*;
-        match rope2 {
-            Leaf(ref s1) if s1.len() < MIN_LEAF_LENGTH as usize => match rope1 {
-                Leaf(s2) if s2.len() < MIN_LEAF_LENGTH as usize => {
-                    let mut new_leaf = String::with_capacity(s1.len() + s2.len());
-                    new_leaf.push_str(s1);
-                    new_leaf.push_str(&s2);
-                    return new_leaf.into();
-                }
-                _ => (),
-            },
-            _ => (),
+    pub fn new() -> Self {
+        Leaf("".into())
+    }
+
+    pub fn concat(rope1: Self, rope2: Self) -> Self {
+        if rope1.is_empty() {
+            return rope2;
+        }
+        if rope2.is_empty() {
+            return rope1;
         }
 
+        if let (Leaf(s1), Leaf(s2)) = (&rope1, &rope2) {
+            if s1.len() < MIN_LEAF_LENGTH && s2.len() < MIN_LEAF_LENGTH {
+                let mut new_leaf = String::with_capacity(s1.len() + s2.len());
+                new_leaf.push_str(s1);
+                new_leaf.push_str(s2);
+                return Leaf(new_leaf.into());
+            }
+        }
+
+        Self::join(Rc::new(rope1), Rc::new(rope2))
+    }
+
+    /// Creates a concat node without trying to merge the children
+    fn join(left: Rc<Rope>, right: Rc<Rope>) -> Self {
+        let weight = left.weight();
         Concat {
-            weight: rope1.weight(),
-            left: Rc::new(rope1),
-            right: Rc::new(rope2),
+            total: weight + right.weight(),
+            depth: 1 + left.depth().max(right.depth()),
+            weight,
+            left,
+            right,
+        }
+    }
+
+    /// Weight of the whole rope
+    fn weight(&self) -> Weight {
+        match self {
+            Concat { total, .. } => *total,
+            Leaf(s) => Weight::of(s),
         }
     }
 
-    fn weight(&self) -> u32 {
+    fn depth(&self) -> u32 {
         match self {
-            Concat { weight, .. } => *weight,
-            Leaf(s) => s.len() as u32,
+            Concat { depth, .. } => *depth,
+            Leaf(_) => 0,
         }
     }
+
+    pub fn len_bytes(&self) -> usize {
+        match self {
+            Concat { total, .. } => total.bytes,
+            Leaf(s) => s.len(),
+        }
+    }
+
+    pub fn len_chars(&self) -> usize {
+        self.weight().chars
+    }
+
+    /// Number of lines. A trailing `\n` starts a new (empty) line, same as
+    /// `str::split('\n')`.
+    pub fn len_lines(&self) -> usize {
+        self.weight().newlines + 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len_bytes() == 0
+    }
+
+    pub fn byte_to_char(&self, byte: usize) -> usize {
+        match self {
+            Concat {
+                left,
+                right,
+                weight,
+                ..
+            } => {
+                if byte < weight.bytes {
+                    left.byte_to_char(byte)
+                } else {
+                    weight.chars + right.byte_to_char(byte - weight.bytes)
+                }
+            }
+            Leaf(s) => s[..byte.min(s.len())].chars().count(),
+        }
+    }
+
+    /// Byte offset of the `char_idx`th char. Clamps to the end of the rope.
+    pub fn char_to_byte(&self, char_idx: usize) -> usize {
+        match self {
+            Concat {
+                left,
+                right,
+                weight,
+                ..
+            } => {
+                if char_idx < weight.chars {
+                    left.char_to_byte(char_idx)
+                } else {
+                    weight.bytes + right.char_to_byte(char_idx - weight.chars)
+                }
+            }
+            Leaf(s) => s
+                .char_indices()
+                .nth(char_idx)
+                .map_or(s.len(), |(idx, _)| idx),
+        }
+    }
+
+    /// Index of the line containing `byte`
+    pub fn byte_to_line(&self, byte: usize) -> usize {
+        match self {
+            Concat {
+                left,
+                right,
+                weight,
+                ..
+            } => {
+                if byte < weight.bytes {
+                    left.byte_to_line(byte)
+                } else {
+                    weight.newlines + right.byte_to_line(byte - weight.bytes)
+                }
+            }
+            Leaf(s) => s.as_bytes()[..byte.min(s.len())]
+                .iter()
+                .filter(|&&b| b == b'\n')
+                .count(),
+        }
+    }
+
+    /// Byte offset of the start of line `line`. Clamps to the end of the rope.
+    pub fn line_to_byte(&self, line: usize) -> usize {
+        if line == 0 {
+            return 0;
+        }
+        match self {
+            Concat {
+                left,
+                right,
+                weight,
+                ..
+            } => {
+                if line <= weight.newlines {
+                    left.line_to_byte(line)
+                } else {
+                    weight.bytes + right.line_to_byte(line - weight.newlines)
+                }
+            }
+            Leaf(s) => s
+                .match_indices('\n')
+                .nth(line - 1)
+                .map_or(s.len(), |(idx, _)| idx + 1),
+        }
+    }
+
+    pub fn char_to_line(&self, char_idx: usize) -> usize {
+        self.byte_to_line(self.char_to_byte(char_idx))
+    }
+
+    pub fn line_to_char(&self, line: usize) -> usize {
+        self.byte_to_char(self.line_to_byte(line))
+    }
+
+    /// Returns the char starting at `byte`, if any
+    pub fn char_at(&self, byte: usize) -> Option<char> {
+        self.chunks_in(byte..self.len_bytes())
+            .next()
+            .and_then(|s| s.chars().next())
+    }
+
+    /// Splits the rope in two at `byte`. Both halves share their nodes with
+    /// `self`.
+    pub fn split(&self, byte: usize) -> (Self, Self) {
+        match self {
+            Concat {
+                left,
+                right,
+                weight,
+                ..
+            } => {
+                if byte < weight.bytes {
+                    let (l1, l2) = left.split(byte);
+                    (l1, Self::concat(l2, Self::clone(right)))
+                } else if byte > weight.bytes {
+                    let (r1, r2) = right.split(byte - weight.bytes);
+                    (Self::concat(Self::clone(left), r1), r2)
+                } else {
+                    (Self::clone(left), Self::clone(right))
+                }
+            }
+            Leaf(s) => {
+                let (s1, s2) = s.split_at(byte);
+                (Leaf(s1.into()), Leaf(s2.into()))
+            }
+        }
+    }
+
+    pub fn insert(&mut self, byte: usize, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        let (left, right) = self.split(byte);
+        let middle = Self::from(text);
+        *self = Self::concat(Self::concat(left, middle), right);
+        self.rebalance_if_needed();
+    }
+
+    pub fn delete(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        let (left, rest) = self.split(range.start);
+        let (_, right) = rest.split(range.end - range.start);
+        *self = Self::concat(left, right);
+        self.rebalance_if_needed();
+    }
+
+    fn rebalance_if_needed(&mut self) {
+        if self.depth() > MAX_DEPTH {
+            self.rebalance();
+        }
+    }
+
+    /// Rebuilds the rope as a balanced tree. Leaves of at least
+    /// `MIN_LEAF_LENGTH` bytes are kept as they are, so the new tree still
+    /// shares them with older versions of the rope, and only runs of short
+    /// leaves get merged.
+    pub fn rebalance(&mut self) {
+        let mut old_leaves = Vec::new();
+        self.collect_leaves(&mut old_leaves);
+
+        let mut leaves: Vec<Rc<str>> = Vec::with_capacity(old_leaves.len());
+        // Short leaves waiting to be merged, and their total length
+        let mut pending: Vec<&Rc<str>> = Vec::new();
+        let mut pending_len = 0;
+        let mut flush = |pending: &mut Vec<&Rc<str>>, pending_len: &mut usize| {
+            match pending.as_slice() {
+                [] => {}
+                [leaf] => leaves.push(Rc::clone(leaf)),
+                _ => {
+                    let mut merged = String::with_capacity(*pending_len);
+                    merged.extend(pending.iter().map(|leaf| &***leaf));
+                    leaves.push(merged.into());
+                }
+            }
+            pending.clear();
+            *pending_len = 0;
+        };
+        for leaf in old_leaves {
+            if leaf.len() >= MIN_LEAF_LENGTH {
+                flush(&mut pending, &mut pending_len);
+                pending.push(leaf);
+                flush(&mut pending, &mut pending_len);
+                continue;
+            }
+            if pending_len + leaf.len() > MAX_LEAF_LENGTH {
+                flush(&mut pending, &mut pending_len);
+            }
+            pending.push(leaf);
+            pending_len += leaf.len();
+            if pending_len >= MIN_LEAF_LENGTH {
+                flush(&mut pending, &mut pending_len);
+            }
+        }
+        flush(&mut pending, &mut pending_len);
+
+        *self = Self::build(&leaves);
+    }
+
+    /// Pushes the non-empty leaves of the rope to `leaves`, in order
+    fn collect_leaves<'a>(&'a self, leaves: &mut Vec<&'a Rc<str>>) {
+        match self {
+            Concat { left, right, .. } => {
+                left.collect_leaves(leaves);
+                right.collect_leaves(leaves);
+            }
+            Leaf(s) if !s.is_empty() => leaves.push(s),
+            Leaf(_) => {}
+        }
+    }
+
+    /// Builds a balanced tree from a list of leaves
+    fn build(leaves: &[Rc<str>]) -> Self {
+        match leaves {
+            [] => Self::new(),
+            [leaf] => Leaf(leaf.clone()),
+            _ => {
+                let (left, right) = leaves.split_at(leaves.len() / 2);
+                Self::join(Rc::new(Self::build(left)), Rc::new(Self::build(right)))
+            }
+        }
+    }
+
+    /// Iterates over the leaves of the rope, in order
+    pub fn chunks(&self) -> Chunks<'_> {
+        self.chunks_in(0..self.len_bytes())
+    }
+
+    /// Iterates over the leaves of the rope, cut to the given byte range
+    pub fn chunks_in(&self, range: Range<usize>) -> Chunks<'_> {
+        Chunks {
+            stack: vec![(self, 0)],
+            range,
+            reversed: false,
+        }
+    }
+
+    /// Same as [`Rope::chunks_in`], but starting from the end of the range
+    pub fn chunks_in_rev(&self, range: Range<usize>) -> Chunks<'_> {
+        Chunks {
+            stack: vec![(self, 0)],
+            range,
+            reversed: true,
+        }
+    }
+
+    pub fn chars(&self) -> impl Iterator<Item = char> + '_ {
+        self.chunks().flat_map(str::chars)
+    }
+
+    /// Iterates over the chars starting at `byte`, along with their byte offset
+    pub fn char_indices_from(&self, byte: usize) -> impl Iterator<Item = (usize, char)> + '_ {
+        let mut offset = byte;
+        self.chunks_in(byte..self.len_bytes())
+            .flat_map(move |chunk| {
+                let start = offset;
+                offset += chunk.len();
+                chunk.char_indices().map(move |(idx, c)| (start + idx, c))
+            })
+    }
+
+    /// Iterates backwards over the chars before `byte`, along with their byte
+    /// offset
+    pub fn char_indices_before(&self, byte: usize) -> impl Iterator<Item = (usize, char)> + '_ {
+        let mut offset = byte;
+        self.chunks_in_rev(0..byte).flat_map(move |chunk| {
+            offset -= chunk.len();
+            let start = offset;
+            chunk.char_indices().rev().map(move |(idx, c)| (start + idx, c))
+        })
+    }
+
+    /// Writes the whole rope to `writer`, one leaf at a time
+    pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+        for chunk in self.chunks() {
+            writer.write_all(chunk.as_bytes())?;
+        }
+        writer.flush()
+    }
+
+    /// Copies the given byte range out of the rope
+    pub fn slice(&self, range: Range<usize>) -> String {
+        let mut res = String::with_capacity(range.len());
+        res.extend(self.chunks_in(range));
+        res
+    }
+
+    /// Byte range of line `line`, without its `\n`
+    pub fn line_range(&self, line: usize) -> Range<usize> {
+        let start = self.line_to_byte(line);
+        let end = if line + 1 < self.len_lines() {
+            self.line_to_byte(line + 1) - 1
+        } else {
+            self.len_bytes()
+        };
+        start..end
+    }
+
+    /// Contents of line `line`, without its `\n`
+    pub fn line(&self, line: usize) -> String {
+        self.slice(self.line_range(line))
+    }
+
+    pub fn line_len_chars(&self, line: usize) -> usize {
+        let range = self.line_range(line);
+        self.byte_to_char(range.end) - self.byte_to_char(range.start)
+    }
+
+    /// Iterates over the lines, without their `\n`. Behaves like
+    /// `str::split('\n')`
+    pub fn lines(&self) -> impl Iterator<Item = String> + '_ {
+        (0..self.len_lines()).map(|line| self.line(line))
+    }
+
+    /// Whether both ropes are (structurally) the same nodes
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (
+                Concat {
+                    left: l1,
+                    right: r1,
+                    ..
+                },
+                Concat {
+                    left: l2,
+                    right: r2,
+                    ..
+                },
+            ) => Rc::ptr_eq(l1, l2) && Rc::ptr_eq(r1, r2),
+            (Leaf(s1), Leaf(s2)) => Rc::ptr_eq(s1, s2),
+            _ => false,
+        }
+    }
+}
+
+impl Default for Rope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<&str> for Rope {
+    fn from(other: &str) -> Self {
+        if other.len() <= MAX_LEAF_LENGTH {
+            return Leaf(other.into());
+        }
+
+        let mut leaves = Vec::with_capacity(other.len() / MIN_LEAF_LENGTH + 1);
+        let mut rest = other;
+        while !rest.is_empty() {
+            let mut end = rest.len().min(MIN_LEAF_LENGTH);
+            while !rest.is_char_boundary(end) {
+                end += 1;
+            }
+            let (leaf, tail) = rest.split_at(end);
+            leaves.push(leaf.into());
+            rest = tail;
+        }
+        Self::build(&leaves)
+    }
 }
 
 impl From<String> for Rope {
     fn from(other: String) -> Self {
-        Self::Leaf(other.into())
+        if other.len() <= MAX_LEAF_LENGTH {
+            Self::Leaf(other.into())
+        } else {
+            Self::from(other.as_str())
+        }
+    }
+}
+
+impl PartialEq for Rope {
+    fn eq(&self, other: &Self) -> bool {
+        if self.ptr_eq(other) {
+            return true;
+        }
+        self.len_bytes() == other.len_bytes()
+            && self
+                .chunks()
+                .flat_map(str::bytes)
+                .eq(other.chunks().flat_map(str::bytes))
+    }
+}
+
+impl Eq for Rope {}
+
+impl fmt::Display for Rope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.chunks().try_for_each(|chunk| f.write_str(chunk))
+    }
+}
+
+impl fmt::Debug for Rope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.to_string(), f)
+    }
+}
+
+/// Iterator over the leaves of a rope. See [`Rope::chunks_in`]
+pub struct Chunks<'a> {
+    /// Nodes left to visit, with the byte offset they start at
+    stack: Vec<(&'a Rope, usize)>,
+    range: Range<usize>,
+    reversed: bool,
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        while let Some((node, offset)) = self.stack.pop() {
+            let before_range = offset + node.len_bytes() <= self.range.start;
+            let after_range = offset >= self.range.end;
+            let (past, ahead) = if self.reversed {
+                (before_range, after_range)
+            } else {
+                (after_range, before_range)
+            };
+            if past {
+                // Nodes are visited in order, so everything left is past the end
+                self.stack.clear();
+                return None;
+            }
+            if ahead {
+                continue;
+            }
+
+            match node {
+                Concat {
+                    left,
+                    right,
+                    weight,
+                    ..
+                } => {
+                    let left = (&**left, offset);
+                    let right = (&**right, offset + weight.bytes);
+                    if self.reversed {
+                        self.stack.extend([left, right]);
+                    } else {
+                        self.stack.extend([right, left]);
+                    }
+                }
+                Leaf(s) => {
+                    let start = self.range.start.saturating_sub(offset);
+                    let end = (self.range.end - offset).min(s.len());
+                    if start < end {
+                        return Some(&s[start..end]);
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Text spanning several leaves, with multibyte chars and newlines
+    /// landing on either side of the leaf boundaries
+    fn sample() -> String {
+        let mut text = String::new();
+        for i in 0..1500 {
+            text.push_str(["ab", "é", "日本", "\n", "🦀x", "\n\n"][i % 6]);
+            text.push_str(&"-".repeat(i % 7));
+        }
+        text
+    }
+
+    /// Checks every query of `rope` against the same query on `text`
+    fn check(rope: &Rope, text: &str) {
+        assert_eq!(rope.to_string(), text);
+        assert_eq!(rope.len_bytes(), text.len());
+        assert_eq!(rope.len_chars(), text.chars().count());
+        assert_eq!(rope.len_lines(), text.split('\n').count());
+        assert!(rope.chunks().all(|chunk| !chunk.is_empty()));
+
+        let boundaries = text.char_indices().map(|(i, _)| i).chain([text.len()]);
+        for (char_idx, byte) in boundaries.enumerate() {
+            assert_eq!(rope.byte_to_char(byte), char_idx, "byte {byte}");
+            assert_eq!(rope.char_to_byte(char_idx), byte, "char {char_idx}");
+            let line = text[..byte].matches('\n').count();
+            assert_eq!(rope.byte_to_line(byte), line, "byte {byte}");
+            assert_eq!(rope.char_to_line(char_idx), line, "char {char_idx}");
+            assert_eq!(rope.char_at(byte), text[byte..].chars().next());
+        }
+
+        let mut start = 0;
+        for (i, line) in text.split('\n').enumerate() {
+            assert_eq!(rope.line_to_byte(i), start, "line {i}");
+            assert_eq!(rope.line_to_char(i), text[..start].chars().count());
+            assert_eq!(rope.line_range(i), start..start + line.len());
+            assert_eq!(rope.line(i), line);
+            assert_eq!(rope.line_len_chars(i), line.chars().count());
+            start += line.len() + 1;
+        }
+        assert!(rope.lines().eq(text.split('\n')));
+        assert_eq!(rope.line_to_byte(rope.len_lines()), text.len());
+    }
+
+    #[test]
+    fn from_str() {
+        check(&Rope::new(), "");
+        check(&Rope::from("é\n日本"), "é\n日本");
+        let text = sample();
+        let rope = Rope::from(text.as_str());
+        assert!(rope.depth() > 0);
+        check(&rope, &text);
+        check(&Rope::from(text.clone()), &text);
+    }
+
+    #[test]
+    fn split() {
+        let text = sample();
+        let rope = Rope::from(text.as_str());
+        let leaves: Vec<_> = rope.chunks().map(str::len).collect();
+        let mut boundary = 0;
+        let mut offsets = vec![0, 1, text.len()];
+        for len in leaves {
+            boundary += len;
+            // Around each leaf boundary, on char boundaries
+            offsets.extend(
+                (boundary.saturating_sub(4)..(boundary + 4).min(text.len()))
+                    .filter(|&i| text.is_char_boundary(i)),
+            );
+        }
+        for byte in offsets {
+            let (left, right) = rope.split(byte);
+            check(&left, &text[..byte]);
+            check(&right, &text[byte..]);
+        }
+    }
+
+    #[test]
+    fn insert_and_delete() {
+        let mut text = sample();
+        let mut rope = Rope::from(text.as_str());
+        let old = rope.clone();
+        let mut byte = 0;
+        for (i, piece) in ["x", "é\n", "🦀", "\n\n日", ""]
+            .iter()
+            .cycle()
+            .take(300)
+            .enumerate()
+        {
+            byte = (byte + 4099) % (text.len() + 1);
+            while !text.is_char_boundary(byte) {
+                byte -= 1;
+            }
+            rope.insert(byte, piece);
+            text.insert_str(byte, piece);
+            if i % 3 == 0 {
+                let end = text[byte..]
+                    .char_indices()
+                    .nth(5)
+                    .map_or(text.len(), |(j, _)| byte + j);
+                rope.delete(byte..end);
+                text.replace_range(byte..end, "");
+            }
+            assert!(rope.depth() <= MAX_DEPTH);
+        }
+        check(&rope, &text);
+        // Edits don't touch the ropes they started from
+        check(&old, &sample());
+
+        rope.delete(0..text.len());
+        check(&rope, "");
+        rope.insert(0, "é");
+        check(&rope, "é");
+    }
+
+    #[test]
+    fn rebalance() {
+        let mut rope = Rope::new();
+        let mut text = String::new();
+        // Joining one char at a time makes a degenerate tree
+        for i in 0..200 {
+            let piece = if i % 40 == 0 { "\n" } else { "é" };
+            rope = Rope::join(Rc::new(rope), Rc::new(Rope::from(piece)));
+            text.push_str(piece);
+        }
+        assert!(rope.depth() > MAX_DEPTH);
+        rope.rebalance();
+        assert_eq!(rope.depth(), 0);
+        assert!(rope.chunks().all(|chunk| chunk.len() <= MAX_LEAF_LENGTH));
+        check(&rope, &text);
+    }
+
+    #[test]
+    fn typing_keeps_sharing_leaves() {
+        let mut text = sample().repeat(8);
+        let mut rope = Rope::from(text.as_str());
+        let old = rope.clone();
+        let mut byte = text.len() / 2;
+        while !text.is_char_boundary(byte) {
+            byte += 1;
+        }
+        let typed_in = old.byte_to_char(byte);
+        for i in 0..10_000 {
+            let piece = if i % 50 == 49 { "\n" } else { "é" };
+            rope.insert(byte, piece);
+            text.insert_str(byte, piece);
+            byte += piece.len();
+            assert!(rope.depth() <= MAX_DEPTH);
+        }
+        assert_eq!(rope.to_string(), text);
+
+        // Every leaf but the one typed into is still the snapshot's
+        let mut old_leaves = Vec::new();
+        old.collect_leaves(&mut old_leaves);
+        let mut new_leaves = Vec::new();
+        rope.collect_leaves(&mut new_leaves);
+        let mut start = 0;
+        for leaf in old_leaves {
+            let chars = leaf.chars().count();
+            if !(start..start + chars).contains(&typed_in) {
+                assert!(
+                    new_leaves.iter().any(|new| Rc::ptr_eq(new, leaf)),
+                    "leaf at char {start} was copied"
+                );
+            }
+            start += chars;
+        }
+    }
+
+    #[test]
+    fn slice_and_write_to() {
+        let text = sample();
+        let rope = Rope::from(text.as_str());
+        let mut out = Vec::new();
+        rope.write_to(&mut out).unwrap();
+        assert_eq!(out, text.as_bytes());
+
+        for start in [0, 1, 4095, 4096, 4097, 8191, 8192, text.len()] {
+            for end in [start, start + 1, start + 3, start + 5000, text.len()] {
+                let (mut start, mut end) = (start.min(text.len()), end.min(text.len()));
+                while !text.is_char_boundary(start) {
+                    start -= 1;
+                }
+                while !text.is_char_boundary(end) {
+                    end += 1;
+                }
+                assert_eq!(rope.slice(start..end), text[start..end]);
+                let mut rev: Vec<_> = rope.chunks_in_rev(start..end).collect();
+                rev.reverse();
+                assert_eq!(rev.concat(), text[start..end]);
+            }
+        }
+        let before: Vec<_> = rope.char_indices_before(text.len()).collect();
+        let expected: Vec<_> = text.char_indices().rev().collect();
+        assert_eq!(before, expected);
+        let mut byte = MIN_LEAF_LENGTH + 1;
+        while !text.is_char_boundary(byte) {
+            byte += 1;
+        }
+        let from: Vec<_> = rope.char_indices_from(byte).collect();
+        let expected: Vec<_> = text[byte..]
+            .char_indices()
+            .map(|(i, c)| (byte + i, c))
+            .collect();
+        assert_eq!(from, expected);
     }
 }
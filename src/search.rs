@@ -0,0 +1,269 @@
+use std::{cell::RefCell, collections::HashMap, ops::Range, rc::Rc};
+
+use regex::{Regex, RegexBuilder};
+
+use crate::{
+    motion::{self, CharClass},
+    rope::Rope,
+};
+
+/// A `/` or `?` search
+#[derive(Debug, Clone)]
+pub struct Search {
+    regex: Regex,
+    /// Whether the search was made with `?`, which `n` follows
+    pub backward: bool,
+}
+
+impl PartialEq for Search {
+    fn eq(&self, other: &Self) -> bool {
+        self.backward == other.backward && self.pattern() == other.pattern()
+    }
+}
+
+impl Eq for Search {}
+
+impl Search {
+    pub fn new(pattern: &str, backward: bool) -> Result<Self, regex::Error> {
+        Ok(Self {
+            // `^` and `$` match at the start and end of lines
+            regex: RegexBuilder::new(pattern).multi_line(true).build()?,
+            backward,
+        })
+    }
+
+    pub fn pattern(&self) -> &str {
+        self.regex.as_str()
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.regex.is_match(text)
+    }
+
+    /// First match in `line` starting at or after byte `from`
+    pub fn find_in_line(&self, line: &str, from: usize) -> Option<Range<usize>> {
+        self.regex.find_at(line, from).map(|m| m.range())
+    }
+
+    /// Expands the `$1`-style references of `replacement` for the match
+    /// starting at byte `start` of `text`
+    pub fn expand(&self, text: &str, start: usize, replacement: &str) -> String {
+        let mut expanded = String::new();
+        if let Some(captures) = self.regex.captures_at(text, start) {
+            captures.expand(replacement, &mut expanded);
+        }
+        expanded
+    }
+
+    /// Non-empty matches in `text`, in order
+    pub fn matches(&self, text: &str) -> Vec<Range<usize>> {
+        self.regex
+            .find_iter(text)
+            .map(|m| m.range())
+            .filter(|range| !range.is_empty())
+            .collect()
+    }
+
+    /// Finds the start of the `count`th non-empty match after `from`, or
+    /// before it if `reverse`, wrapping around the ends of `text`
+    pub fn find(&self, text: &str, from: usize, reverse: bool, count: usize) -> Option<usize> {
+        if reverse {
+            let starts: Vec<_> = self.matches(text).iter().map(|m| m.start).collect();
+            go_around(count, from, |pos| {
+                match starts.iter().rev().find(|&&start| start < pos) {
+                    Some(&start) => Some(start),
+                    None => starts.last().copied(),
+                }
+            })
+        } else {
+            go_around(count, from, |pos| {
+                let next = text[pos..]
+                    .chars()
+                    .next()
+                    .map_or(pos, |c| pos + c.len_utf8());
+                self.find_from(text, next)
+                    .or_else(|| self.find_from(text, 0))
+            })
+        }
+    }
+
+    /// Start of the first non-empty match starting at or after byte `from`
+    fn find_from(&self, text: &str, mut from: usize) -> Option<usize> {
+        loop {
+            let m = self.regex.find_at(text, from)?;
+            if !m.is_empty() {
+                return Some(m.start());
+            }
+            from = m.start() + text[m.start()..].chars().next()?.len_utf8();
+        }
+    }
+}
+
+/// Takes `count` steps from `start`, skipping the full turns once a step
+/// comes back to a match it went to before, so that huge counts don't go
+/// around the text again and again
+fn go_around(
+    count: usize,
+    start: usize,
+    mut step: impl FnMut(usize) -> Option<usize>,
+) -> Option<usize> {
+    let mut visited = Vec::new();
+    // Index in `visited` of each position
+    let mut seen = HashMap::new();
+    let mut pos = start;
+    for idx in 0..count {
+        pos = step(pos)?;
+        if let Some(&first) = seen.get(&pos) {
+            let turn = idx - first;
+            return Some(visited[first + (count - 1 - first) % turn]);
+        }
+        seen.insert(pos, idx);
+        visited.push(pos);
+    }
+    Some(pos)
+}
+
+/// Search for the word under or after `pos` on its line, as made by `*` and
+/// `#`, and where that word starts
+pub fn word_search(buffer: &Rope, pos: usize, backward: bool) -> Option<(Search, usize)> {
+    let line_end = buffer.line_range(buffer.byte_to_line(pos)).end;
+    let is_word = |c| motion::class(c, false) == CharClass::Word;
+    let (start, _) = buffer
+        .char_indices_from(pos)
+        .take_while(|&(idx, _)| idx < line_end)
+        .find(|&(_, c)| is_word(c))?;
+    // Line breaks are not part of words, no need to check for the line start
+    let start = buffer
+        .char_indices_before(start)
+        .take_while(|&(_, c)| is_word(c))
+        .last()
+        .map_or(start, |(idx, _)| idx);
+    let end = buffer
+        .char_indices_from(start)
+        .find(|&(_, c)| !is_word(c))
+        .map_or(buffer.len_bytes(), |(idx, _)| idx);
+
+    let pattern = format!(r"\b{}\b", regex::escape(&buffer.slice(start..end)));
+    let search = Search::new(&pattern, backward).expect("Escaped words are valid patterns");
+    Some((search, start))
+}
+
+/// Text of a rope in one piece, as the regex engine needs it. Shared by the
+/// clones of a state, and only copied out of the rope again when it changes.
+#[derive(Clone, Default)]
+pub struct FlatText(Rc<RefCell<Option<Flattened>>>);
+
+struct Flattened {
+    rope: Rope,
+    text: Rc<str>,
+}
+
+impl FlatText {
+    pub fn get(&self, rope: &Rope) -> Rc<str> {
+        let mut cache = self.0.borrow_mut();
+        match &*cache {
+            Some(flat) if flat.rope.ptr_eq(rope) => Rc::clone(&flat.text),
+            _ => {
+                let text: Rc<str> = rope.to_string().into();
+                *cache = Some(Flattened {
+                    rope: rope.clone(),
+                    text: Rc::clone(&text),
+                });
+                text
+            }
+        }
+    }
+}
+
+/// Matches of the highlighted search, only recomputed when the search or
+/// the text change
+#[derive(Default)]
+pub struct SearchHighlight {
+    search: Option<Search>,
+    text: Rope,
+    matches: Vec<Range<usize>>,
+}
+
+impl SearchHighlight {
+    pub fn update(&mut self, search: Option<Search>, text: &Rope, flat: &FlatText) {
+        if self.search == search && self.text.ptr_eq(text) {
+            return;
+        }
+        self.matches = match &search {
+            Some(search) => search.matches(&flat.get(text)),
+            None => Vec::new(),
+        };
+        self.search = search;
+        self.text = text.clone();
+    }
+
+    pub fn matches(&self) -> &[Range<usize>] {
+        &self.matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_counts() {
+        let text = "ab ab\nxab é ab";
+        let search = Search::new("ab", false).unwrap();
+        assert_eq!(search.find(text, 0, false, 1), Some(3));
+        assert_eq!(search.find(text, 0, false, 2), Some(7));
+        // Wraps around the end, and the start going backward
+        assert_eq!(search.find(text, 0, false, 4), Some(0));
+        assert_eq!(search.find(text, 0, false, 6), Some(7));
+        assert_eq!(search.find(text, 3, true, 1), Some(0));
+        assert_eq!(search.find(text, 3, true, 2), Some(13));
+        assert_eq!(search.find(text, 13, true, 5), Some(7));
+
+        // Empty matches are skipped
+        let search = Search::new("x*", false).unwrap();
+        assert_eq!(search.find(text, 0, false, 1), Some(6));
+        assert_eq!(search.find(text, 0, true, 1), Some(6));
+        let search = Search::new("z", false).unwrap();
+        assert_eq!(search.find(text, 0, false, 1), None);
+        assert_eq!(search.find(text, 0, true, 1), None);
+    }
+
+    #[test]
+    fn find_huge_counts() {
+        let text = "ab ab\nxab é ab";
+        let search = Search::new("ab", false).unwrap();
+        // 4 matches: 99_999_999_999 is 3 after a whole number of turns
+        for (from, reverse, target) in [(0, false, 13), (3, false, 0), (3, true, 7), (0, true, 3)] {
+            assert_eq!(
+                search.find(text, from, reverse, 99_999_999_999),
+                Some(target)
+            );
+        }
+        assert_eq!(search.find(text, 0, false, usize::MAX), Some(13));
+        // Overlapping matches are all visited going forward
+        let search = Search::new("aa", false).unwrap();
+        assert_eq!(search.find("aaaa", 0, false, 99_999_999_999), Some(0));
+    }
+
+    #[test]
+    fn word_search_escapes() {
+        let buffer = Rope::from("x a.b+ a_b1 (a_b1)");
+        let (search, start) = word_search(&buffer, 6, false).unwrap();
+        assert_eq!((search.pattern(), start), (r"\ba_b1\b", 7));
+        assert_eq!(search.find("x a.b+ a_b1 (a_b1)", start, false, 1), Some(13));
+        assert!(word_search(&Rope::from("a ++\nb"), 2, false).is_none());
+    }
+
+    #[test]
+    fn flat_text_is_copied_once_per_text() {
+        let flat = FlatText::default();
+        let mut rope = Rope::from("one\ntwo");
+        let text = flat.get(&rope);
+        assert_eq!(&*text, "one\ntwo");
+        assert!(Rc::ptr_eq(&text, &flat.clone().get(&rope.clone())));
+        rope.insert(3, "!");
+        let changed = flat.get(&rope);
+        assert_eq!(&*changed, "one!\ntwo");
+        assert!(!Rc::ptr_eq(&text, &changed));
+    }
+}
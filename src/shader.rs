@@ -35,15 +35,23 @@ struct AttributeInfo<'a> {
     name: &'a str,
 }
 
-const TEXT_SHADER_ATTR_INFO: [AttributeInfo; 2] = [
+const TEXT_SHADER_ATTR_INFO: [AttributeInfo; 4] = [
     AttributeInfo {
         size: 2,
         name: "aPos",
     },
     AttributeInfo {
-        size: 2,
+        size: 3,
         name: "aTexCoord",
     },
+    AttributeInfo {
+        size: 1,
+        name: "aFormat",
+    },
+    AttributeInfo {
+        size: 4,
+        name: "inColour",
+    },
 ];
 
 const SHAPE_SHADER_ATTR_INFO: [AttributeInfo; 2] = [
@@ -200,17 +208,6 @@ impl<const N: usize> Shader<N> {
         }
     }
 
-    pub fn uniform4vf(&self, name: &str, val: [GLfloat; 4]) {
-        let name = c_str(name);
-        unsafe {
-            gl::Uniform4fv(
-                gl::GetUniformLocation(self.program_id, name.as_ptr()),
-                1,
-                val.as_ptr(),
-            );
-        }
-    }
-
     pub fn uniform2i(&self, name: &str, val: [GLint; 2]) {
         let name = c_str(name);
         unsafe {
@@ -223,10 +220,10 @@ impl<const N: usize> Shader<N> {
     }
 }
 
-impl Shader<4> {
+impl Shader<10> {
     pub fn text_shader(vbo: GLuint) -> Self {
         unsafe {
-            // Safety: the sizes in TEXT_SHADER_ATTR_INFO sum up to 4
+            // Safety: the sizes in TEXT_SHADER_ATTR_INFO sum up to 10
             Self::new(
                 vbo,
                 include_str!("shaders/text_vertex.glsl"),
@@ -235,6 +232,19 @@ impl Shader<4> {
             )
         }
     }
+
+    /// Text shader for atlases of distance fields
+    pub fn sdf_text_shader(vbo: GLuint) -> Self {
+        unsafe {
+            // Safety: the sizes in TEXT_SHADER_ATTR_INFO sum up to 10
+            Self::new(
+                vbo,
+                include_str!("shaders/text_vertex.glsl"),
+                include_str!("shaders/text_sdf_fragment.glsl"),
+                &TEXT_SHADER_ATTR_INFO,
+            )
+        }
+    }
 }
 
 impl Shader<6> {
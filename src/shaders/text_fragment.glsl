@@ -1,14 +1,28 @@
 #version 330 core
 out vec4 FragColor;
 
-in vec2 texCoord;
-uniform vec4 color;
+in vec3 texCoord;
+flat in float format;
+in vec4 colour;
 
 // texture samplers
-uniform sampler2D texture1;
+// One layer per page of the atlas, gray pages being apart from RGBA ones
+uniform sampler2DArray grayPages;
+uniform sampler2DArray rgbaPages;
 
 void main()
 {
-	// linearly interpolate between both textures (80% container, 20% awesomeface)
-	FragColor = vec4(1, 1, 1, texture(texture1, texCoord).r);
+	// Colour glyphs keep their own colours, premultiplied in the atlas,
+	// and only take the alpha of the text
+	if (format == 2.0) {
+		vec4 texel = texture(rgbaPages, texCoord);
+		vec3 rgb = texel.a > 0.0 ? texel.rgb / texel.a : vec3(0.0);
+		FragColor = vec4(rgb, colour.a * texel.a);
+		return;
+	}
+	// Otherwise the atlas only has coverage, in the red channel
+	float coverage = format == 0.0
+		? texture(grayPages, texCoord).r
+		: texture(rgbaPages, texCoord).r;
+	FragColor = vec4(colour.rgb, colour.a * coverage);
 }
\ No newline at end of file
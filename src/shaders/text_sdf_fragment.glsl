@@ -0,0 +1,27 @@
+#version 330 core
+out vec4 FragColor;
+
+in vec3 texCoord;
+flat in float format;
+in vec4 colour;
+
+// Distance fields are always in gray pages, one layer per page. Colour
+// glyphs stay bitmaps, in RGBA pages.
+uniform sampler2DArray grayPages;
+uniform sampler2DArray rgbaPages;
+
+void main()
+{
+	if (format == 2.0) {
+		vec4 texel = texture(rgbaPages, texCoord);
+		vec3 rgb = texel.a > 0.0 ? texel.rgb / texel.a : vec3(0.0);
+		FragColor = vec4(rgb, colour.a * texel.a);
+		return;
+	}
+	// The atlas has distances to the edges of glyphs in the red channel,
+	// the edges being at 0.5. They get smoothed over a pixel.
+	float distance = texture(grayPages, texCoord).r;
+	float width = 0.5 * fwidth(distance);
+	float alpha = smoothstep(0.5 - width, 0.5 + width, distance);
+	FragColor = vec4(colour.rgb, colour.a * alpha);
+}
\ No newline at end of file
@@ -1,11 +1,15 @@
 #version 330 core
 layout (location = 0) in vec2 aPos;
-layout (location = 1) in vec2 aTexCoord;
+layout (location = 1) in vec3 aTexCoord;
+layout (location = 2) in float aFormat;
+layout (location = 3) in vec4 inColour;
 uniform ivec2 screenSize;
 uniform float scale;
 uniform float yCenter;
 
-out vec2 texCoord;
+out vec3 texCoord;
+flat out float format;
+out vec4 colour;
 
 void main()
 {
@@ -16,5 +20,7 @@ void main()
 	screenPos.x -= 1.0;
 	screenPos.y *= -1.0;
 	gl_Position = vec4(screenPos, 0.0, 1.0);
-	texCoord = vec2(aTexCoord.x, aTexCoord.y);
+	texCoord = aTexCoord;
+	format = aFormat;
+	colour = inColour;
 }
\ No newline at end of file
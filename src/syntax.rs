@@ -0,0 +1,697 @@
+use std::{
+    cell::{OnceCell, RefCell},
+    cmp::Reverse,
+    iter, mem,
+    ops::Range,
+    rc::Rc,
+};
+
+use tree_sitter::{InputEdit, Node, Parser, Point, Query, QueryCursor, Tree};
+
+use crate::{
+    highlight::{self, Language, Span, Token},
+    rope::Rope,
+};
+
+/// Kinds of nodes of each grammar, separated by spaces
+const RUST_FUNCTIONS: &str = "function_item function_signature_item closure_expression";
+const RUST_CLASSES: &str = "struct_item enum_item union_item trait_item impl_item mod_item";
+const RUST_ARGUMENT_LISTS: &str = "parameters arguments type_parameters type_arguments \
+    closure_parameters tuple_expression tuple_pattern tuple_type";
+const RUST_DEFINITIONS: &str = "function_item function_signature_item struct_item enum_item \
+    union_item trait_item impl_item mod_item macro_definition";
+const RUST_FOLDS: &str = "block declaration_list field_declaration_list enum_variant_list \
+    match_block field_initializer_list use_list token_tree arguments parameters block_comment";
+const MARKDOWN_DEFINITIONS: &str = "atx_heading setext_heading";
+const MARKDOWN_FOLDS: &str = "section fenced_code_block list block_quote html_block";
+const MARKDOWN_INLINES: &str = "inline pipe_table_cell";
+
+/// Structural text objects
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Structure {
+    /// Functions and closures, or fenced code blocks in Markdown
+    Function,
+    /// Types, traits, impls and modules, or sections in Markdown
+    Class,
+    /// Parameters, arguments and tuple fields
+    Argument,
+}
+
+/// Syntax tree of a text, for the languages there is a grammar for. Edits
+/// are reparsed incrementally, reusing the nodes they didn't touch.
+pub struct SyntaxTree {
+    text: Rope,
+    grammar: Grammar,
+    /// Highlight queries, compiled the first time they are needed
+    queries: OnceCell<Vec<Query>>,
+}
+
+enum Grammar {
+    Rust {
+        parser: Parser,
+        tree: Tree,
+    },
+    /// Block tree, and the inline tree of each of its inline nodes, with the
+    /// byte range of the node
+    Markdown {
+        parser: Parser,
+        inline_parser: Parser,
+        tree: Tree,
+        inlines: Vec<(Range<usize>, Tree)>,
+    },
+}
+
+impl SyntaxTree {
+    /// Parses `text`, or `None` if there is no grammar for `language`
+    pub fn new(language: Language, text: &Rope) -> Option<Self> {
+        let grammar = match language {
+            Language::Rust => {
+                let mut parser = new_parser(&tree_sitter_rust::LANGUAGE.into());
+                let tree = parse(&mut parser, text, None);
+                Grammar::Rust { parser, tree }
+            }
+            Language::Markdown => {
+                let mut parser = new_parser(&tree_sitter_md::LANGUAGE.into());
+                let mut inline_parser = new_parser(&tree_sitter_md::INLINE_LANGUAGE.into());
+                let tree = parse(&mut parser, text, None);
+                let inlines =
+                    parse_inlines(&mut inline_parser, &tree, text, Vec::new(), &mut Vec::new());
+                Grammar::Markdown {
+                    parser,
+                    inline_parser,
+                    tree,
+                    inlines,
+                }
+            }
+            Language::Toml | Language::Glsl => return None,
+        };
+        Some(Self {
+            text: text.clone(),
+            grammar,
+            queries: OnceCell::new(),
+        })
+    }
+
+    pub fn language(&self) -> Language {
+        match self.grammar {
+            Grammar::Rust { .. } => Language::Rust,
+            Grammar::Markdown { .. } => Language::Markdown,
+        }
+    }
+
+    /// Reparses the tree for `text`. Returns the rows whose highlights may
+    /// have changed.
+    pub fn update(&mut self, text: &Rope) -> Vec<Range<usize>> {
+        if self.text.ptr_eq(text) {
+            return Vec::new();
+        }
+        let old = &self.text;
+        let (prefix, suffix) = highlight::common_ends(old, text);
+        // Edits start and end on line boundaries, so never inside a char
+        let start = old.line_to_byte(old.byte_to_line(prefix));
+        let new_end = text.len_bytes() - suffix;
+        let new_end = match text.byte_to_line(new_end) {
+            row if text.line_to_byte(row) == new_end => new_end,
+            row => text.line_range(row).end,
+        };
+        let old_end = old.len_bytes() - (text.len_bytes() - new_end);
+        let edit = InputEdit {
+            start_byte: start,
+            old_end_byte: old_end,
+            new_end_byte: new_end,
+            start_position: point(old, start),
+            old_end_position: point(old, old_end),
+            new_end_position: point(text, new_end),
+        };
+
+        let changed = match &mut self.grammar {
+            Grammar::Rust { parser, tree } => {
+                tree.edit(&edit);
+                let new = parse(parser, text, Some(tree));
+                let changed = tree.changed_ranges(&new).collect();
+                *tree = new;
+                changed
+            }
+            Grammar::Markdown {
+                parser,
+                inline_parser,
+                tree,
+                inlines,
+            } => {
+                tree.edit(&edit);
+                let new = parse(parser, text, Some(tree));
+                let mut changed = tree.changed_ranges(&new).collect();
+                *tree = new;
+                // Inline trees away from the edit are kept, moved by it
+                let kept = mem::take(inlines)
+                    .into_iter()
+                    .filter_map(|(range, mut inline)| {
+                        let range = if range.end < start {
+                            range
+                        } else if range.start >= old_end {
+                            range.start - old_end + new_end..range.end - old_end + new_end
+                        } else {
+                            return None;
+                        };
+                        inline.edit(&edit);
+                        Some((range, inline))
+                    })
+                    .collect();
+                *inlines = parse_inlines(inline_parser, tree, text, kept, &mut changed);
+                changed
+            }
+        };
+        self.text = text.clone();
+
+        // The node around the edit may read differently, like a paragraph
+        // whose inline tree changed
+        let root = self.root();
+        let edited = root
+            .descendant_for_byte_range(start, new_end)
+            .unwrap_or(root);
+        iter::once(edited.range())
+            .chain(changed)
+            .map(|range| range.start_point.row..range.end_point.row + 1)
+            .collect()
+    }
+
+    fn root(&self) -> Node<'_> {
+        match &self.grammar {
+            Grammar::Rust { tree, .. } | Grammar::Markdown { tree, .. } => tree.root_node(),
+        }
+    }
+
+    fn queries(&self) -> &[Query] {
+        self.queries.get_or_init(|| {
+            let sources = match self.grammar {
+                Grammar::Rust { .. } => {
+                    vec![(
+                        tree_sitter_rust::LANGUAGE,
+                        tree_sitter_rust::HIGHLIGHTS_QUERY,
+                    )]
+                }
+                Grammar::Markdown { .. } => vec![
+                    (
+                        tree_sitter_md::LANGUAGE,
+                        tree_sitter_md::HIGHLIGHT_QUERY_BLOCK,
+                    ),
+                    (
+                        tree_sitter_md::INLINE_LANGUAGE,
+                        tree_sitter_md::HIGHLIGHT_QUERY_INLINE,
+                    ),
+                ],
+            };
+            sources
+                .into_iter()
+                .map(|(language, source)| {
+                    Query::new(&language.into(), source).expect("Bundled queries are valid")
+                })
+                .collect()
+        })
+    }
+
+    /// Spans of each line in `rows`, from the highlight queries of the
+    /// grammar. Inner nodes win over the ones around them, and the first
+    /// pattern over the next ones for the same node.
+    pub fn highlights(&self, rows: Range<usize>) -> Vec<Vec<Span>> {
+        let text = &self.text;
+        let rows = rows.start..rows.end.min(text.len_lines());
+        if rows.is_empty() {
+            return Vec::new();
+        }
+        let lines: Vec<_> = rows.clone().map(|row| text.line_range(row)).collect();
+        let range = lines[0].start..lines[lines.len() - 1].end;
+        let mut painted: Vec<Vec<Option<Token>>> =
+            lines.iter().map(|line| vec![None; line.len()]).collect();
+
+        let queries = self.queries();
+        let trees: Vec<(&Query, Node)> = match &self.grammar {
+            Grammar::Rust { tree, .. } => vec![(&queries[0], tree.root_node())],
+            // Inline trees go last, as their nodes are inside block ones
+            Grammar::Markdown { tree, inlines, .. } => iter::once((&queries[0], tree.root_node()))
+                .chain(
+                    inlines
+                        .iter()
+                        .filter(|(node, _)| node.start < range.end && node.end > range.start)
+                        .map(|(_, tree)| (&queries[1], tree.root_node())),
+                )
+                .collect(),
+        };
+        let mut cursor = QueryCursor::new();
+        cursor.set_byte_range(range.clone());
+        for (query, root) in trees {
+            let mut captures = Vec::new();
+            let node_text = |node: Node| text.chunks_in(node.byte_range()).map(str::as_bytes);
+            for found in cursor.matches(query, root, node_text) {
+                for capture in found.captures {
+                    let name = query.capture_names()[capture.index as usize];
+                    let token = match name {
+                        "none" => None,
+                        name => match token(name, capture.node.kind()) {
+                            Some(token) => Some(token),
+                            None => continue,
+                        },
+                    };
+                    captures.push((capture.node.byte_range(), found.pattern_index, token));
+                }
+            }
+            captures.sort_by_key(|(node, pattern, _)| {
+                (node.start, Reverse(node.end), Reverse(*pattern))
+            });
+            for (node, _, token) in captures {
+                for (line, painted) in lines.iter().zip(&mut painted) {
+                    let start = node.start.max(line.start);
+                    let end = node.end.min(line.end);
+                    if start < end {
+                        painted[start - line.start..end - line.start].fill(token);
+                    }
+                }
+            }
+        }
+
+        painted
+            .into_iter()
+            .map(|painted| {
+                let mut spans: Vec<Span> = Vec::new();
+                for (pos, token) in painted.into_iter().enumerate() {
+                    let Some(token) = token else { continue };
+                    match spans.last_mut() {
+                        Some(span) if span.range.end == pos && span.token == token => {
+                            span.range.end += 1
+                        }
+                        _ => spans.push(Span {
+                            range: pos..pos + 1,
+                            token,
+                        }),
+                    }
+                }
+                spans
+            })
+            .collect()
+    }
+
+    /// Start of the `n`th definition after `byte`, or before it when
+    /// `backward`
+    pub fn definition(&self, byte: usize, backward: bool, n: usize) -> Option<usize> {
+        let kinds = match self.grammar {
+            Grammar::Rust { .. } => RUST_DEFINITIONS,
+            Grammar::Markdown { .. } => MARKDOWN_DEFINITIONS,
+        };
+        let starts = nodes(self.root(), kinds)
+            .into_iter()
+            .map(|node| node.start_byte());
+        if backward {
+            let before: Vec<_> = starts.filter(|&start| start < byte).collect();
+            before.into_iter().rev().nth(n - 1)
+        } else {
+            starts.filter(|&start| start > byte).nth(n - 1)
+        }
+    }
+
+    /// Byte range of the innermost `structure` around `byte`. Without
+    /// `around`, only its body, or the argument without its comma.
+    pub fn text_object(
+        &self,
+        byte: usize,
+        structure: Structure,
+        around: bool,
+    ) -> Option<Range<usize>> {
+        let (kinds, markdown) = match (&self.grammar, structure) {
+            (Grammar::Rust { .. }, Structure::Function) => (RUST_FUNCTIONS, false),
+            (Grammar::Rust { .. }, Structure::Class) => (RUST_CLASSES, false),
+            (Grammar::Rust { .. }, Structure::Argument) => (RUST_ARGUMENT_LISTS, false),
+            (Grammar::Markdown { .. }, Structure::Function) => ("fenced_code_block", true),
+            (Grammar::Markdown { .. }, Structure::Class) => ("section", true),
+            (Grammar::Markdown { .. }, Structure::Argument) => return None,
+        };
+        // The char under the cursor, or the one before at the end
+        let len = self.text.len_bytes();
+        let char = if byte < len {
+            byte..byte + 1
+        } else {
+            len.saturating_sub(1)..len
+        };
+        let mut node = self
+            .root()
+            .descendant_for_byte_range(char.start, char.end)?;
+        if structure == Structure::Argument {
+            while !(node.is_named() && is(node.parent()?.kind(), kinds)) {
+                node = node.parent()?;
+            }
+            return Some(argument(node, around));
+        }
+        while !is(node.kind(), kinds) {
+            node = node.parent()?;
+        }
+        if around {
+            return Some(node.byte_range());
+        }
+        if markdown {
+            let mut cursor = node.walk();
+            let mut children = node.named_children(&mut cursor);
+            return match structure {
+                Structure::Function => children
+                    .find(|child| child.kind() == "code_fence_content")
+                    .map(|content| content.byte_range()),
+                _ => Some(children.next()?.end_byte()..node.end_byte()),
+            };
+        }
+        let body = node.child_by_field_name("body")?;
+        let first = body.child(0)?;
+        let last = body.child(body.child_count() - 1)?;
+        if is(first.kind(), "{ ( [") && is(last.kind(), "} ) ]") && first != last {
+            Some(first.end_byte()..last.start_byte())
+        } else {
+            Some(body.byte_range())
+        }
+    }
+
+    /// Rows of the nodes that can be folded, in the order they start.
+    /// Nodes on a single line aren't folds.
+    pub fn folds(&self) -> Vec<Range<usize>> {
+        let kinds = match self.grammar {
+            Grammar::Rust { .. } => RUST_FOLDS,
+            Grammar::Markdown { .. } => MARKDOWN_FOLDS,
+        };
+        let mut folds: Vec<Range<usize>> = Vec::new();
+        for node in nodes(self.root(), kinds) {
+            let (start, mut end) = (node.start_position(), node.end_position());
+            // Markdown blocks end at the start of the line after them
+            if end.column == 0 && end.row > start.row {
+                end.row -= 1;
+            }
+            let rows = start.row..end.row + 1;
+            if rows.len() > 1 && !folds.contains(&rows) {
+                folds.push(rows);
+            }
+        }
+        folds
+    }
+}
+
+/// Nodes under `root` of the given kinds, in the order they start
+fn nodes<'a>(root: Node<'a>, kinds: &str) -> Vec<Node<'a>> {
+    let mut nodes = Vec::new();
+    let mut cursor = root.walk();
+    loop {
+        let node = cursor.node();
+        if is(node.kind(), kinds) {
+            nodes.push(node);
+        }
+        if cursor.goto_first_child() {
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                return nodes;
+            }
+        }
+    }
+}
+
+/// Trees of the inline nodes of the Markdown block `tree`. Trees in `old`
+/// are kept for the nodes with the same range, unless it `changed`. The
+/// others are parsed, and their range added to `changed`.
+fn parse_inlines(
+    parser: &mut Parser,
+    tree: &Tree,
+    text: &Rope,
+    old: Vec<(Range<usize>, Tree)>,
+    changed: &mut Vec<tree_sitter::Range>,
+) -> Vec<(Range<usize>, Tree)> {
+    let block_changes = changed.len();
+    let mut old = old.into_iter().peekable();
+    let mut inlines = Vec::new();
+    for node in nodes(tree.root_node(), MARKDOWN_INLINES) {
+        let range = node.byte_range();
+        while old.next_if(|(old, _)| old.start < range.start).is_some() {}
+        let unchanged = changed[..block_changes]
+            .iter()
+            .all(|change| change.end_byte <= range.start || change.start_byte >= range.end);
+        if let Some((_, inline)) = old.next_if(|(old, _)| *old == range && unchanged) {
+            inlines.push((range, inline));
+            continue;
+        }
+        // The inline text leaves out the block markers inside of it, like
+        // the `>` of the next lines in a quote
+        let mut ranges = Vec::new();
+        let mut rest = node.range();
+        let mut cursor = node.walk();
+        for child in node.named_children(&mut cursor) {
+            ranges.push(tree_sitter::Range {
+                end_byte: child.start_byte(),
+                end_point: child.start_position(),
+                ..rest
+            });
+            rest.start_byte = child.end_byte();
+            rest.start_point = child.end_position();
+        }
+        ranges.push(rest);
+        parser
+            .set_included_ranges(&ranges)
+            .expect("Children of a node are in order");
+        inlines.push((range, parse(parser, text, None)));
+        changed.push(node.range());
+    }
+    inlines
+}
+
+/// Argument `node` of a list, with the comma after it and the space until
+/// the next argument when `around`. The last argument takes the comma
+/// before it instead.
+fn argument(node: Node, around: bool) -> Range<usize> {
+    let range = node.byte_range();
+    if !around {
+        return range;
+    }
+    match (node.next_sibling(), node.prev_sibling()) {
+        (Some(comma), _) if comma.kind() == "," => {
+            let next = comma.next_sibling().filter(|next| next.is_named());
+            range.start..next.map_or(comma.end_byte(), |next| next.start_byte())
+        }
+        (_, Some(comma)) if comma.kind() == "," => {
+            let prev = comma.prev_sibling();
+            prev.map_or(comma.start_byte(), |prev| prev.end_byte())..range.end
+        }
+        _ => range,
+    }
+}
+
+/// Token a highlight capture is drawn as, `None` for the ones that keep the
+/// colour of the text
+fn token(capture: &str, kind: &str) -> Option<Token> {
+    let token = match capture.split('.').next()? {
+        "comment" => Token::Comment,
+        "keyword" => Token::Keyword,
+        "variable" if capture == "variable.builtin" => Token::Keyword,
+        "type" | "constructor" | "label" => Token::Type,
+        "function" if capture == "function.macro" => Token::Macro,
+        "function" => Token::Function,
+        "attribute" => Token::Macro,
+        "string" | "escape" => Token::String,
+        "constant" if kind.ends_with("_literal") => Token::Number,
+        "constant" => Token::Constant,
+        "property" => Token::Property,
+        "text" => match capture {
+            "text.title" => Token::Heading,
+            "text.literal" => Token::String,
+            "text.emphasis" | "text.strong" => Token::Emphasis,
+            "text.uri" | "text.reference" => Token::Link,
+            _ => return None,
+        },
+        "punctuation" if capture == "punctuation.special" => {
+            if kind.starts_with("atx_h") || kind.starts_with("setext_h") {
+                Token::Heading
+            } else if kind == "block_quote_marker" {
+                Token::Comment
+            } else {
+                Token::Keyword
+            }
+        }
+        _ => return None,
+    };
+    Some(token)
+}
+
+fn is(kind: &str, kinds: &str) -> bool {
+    kinds.split_whitespace().any(|k| k == kind)
+}
+
+fn new_parser(language: &tree_sitter::Language) -> Parser {
+    let mut parser = Parser::new();
+    parser
+        .set_language(language)
+        .expect("Grammars are built for this version of tree-sitter");
+    parser
+}
+
+/// Parses `text`, reusing the nodes of `old` that weren't edited
+fn parse(parser: &mut Parser, text: &Rope, old: Option<&Tree>) -> Tree {
+    parser
+        .parse_with(&mut chunk_at(text), old)
+        .expect("Parsing only fails after a timeout or when cancelled")
+}
+
+/// Feeds the parser the chunk of `text` starting at the byte it asks for
+fn chunk_at<'a>(text: &'a Rope) -> impl FnMut(usize, Point) -> &'a str + 'a {
+    move |byte, _| text.chunks_in(byte..text.len_bytes()).next().unwrap_or("")
+}
+
+fn point(text: &Rope, byte: usize) -> Point {
+    let row = text.byte_to_line(byte);
+    Point::new(row, byte - text.line_to_byte(row))
+}
+
+/// Syntax tree shared by the clones of a state, updated to the text of the
+/// one that asks for it
+#[derive(Clone, Default)]
+pub struct SyntaxCache(Rc<RefCell<Option<SyntaxTree>>>);
+
+impl SyntaxCache {
+    /// Runs `f` on the tree of `text`, parsed as `language`. `None` if there
+    /// is no grammar for it.
+    pub fn with<T>(
+        &self,
+        language: Language,
+        text: &Rope,
+        f: impl FnOnce(&SyntaxTree) -> Option<T>,
+    ) -> Option<T> {
+        self.with_changes(language, text, text, |tree, _| f(tree))?
+    }
+
+    /// Runs `f` on the tree of `text`, and the rows whose highlights may have
+    /// changed since `old`. `None` for the rows if the tree wasn't parsed
+    /// for `old` last, and `None` if there is no grammar for `language`.
+    pub fn with_changes<T>(
+        &self,
+        language: Language,
+        old: &Rope,
+        text: &Rope,
+        f: impl FnOnce(&SyntaxTree, Option<Vec<Range<usize>>>) -> T,
+    ) -> Option<T> {
+        let mut cache = self.0.borrow_mut();
+        let changed = match &mut *cache {
+            Some(tree) if tree.language() == language => {
+                let from_old = tree.text.ptr_eq(old);
+                let changed = tree.update(text);
+                from_old.then_some(changed)
+            }
+            cache => {
+                *cache = SyntaxTree::new(language, text);
+                None
+            }
+        };
+        Some(f(cache.as_ref()?, changed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RUST: &str =
+        "/// Doc\nfn main() {\n    let s = \"é\";\n    call(s, 1);\n}\n\nstruct S;\n";
+    const MARKDOWN: &str = "# Title\n\nSome *text* and `code`.\n\n```rust\nfn f() {}\n```\n";
+
+    /// Checks that updating the tree of `text` for each of `edits`, byte
+    /// ranges and what replaces them, gives the tree parsing the result
+    /// would, and the rows of each edit among the changed ones
+    fn check_edits(language: Language, text: &str, edits: &[(Range<usize>, &str)]) {
+        let mut text = Rope::from(text);
+        let mut tree = SyntaxTree::new(language, &text).unwrap();
+        for (range, replacement) in edits {
+            text.delete(range.clone());
+            text.insert(range.start, replacement);
+            let changed = tree.update(&text);
+
+            let fresh = SyntaxTree::new(language, &text).unwrap();
+            assert_eq!(tree.root().to_sexp(), fresh.root().to_sexp());
+            let rows = 0..text.len_lines();
+            assert_eq!(tree.highlights(rows.clone()), fresh.highlights(rows));
+            let first = text.byte_to_line(range.start);
+            let last = text.byte_to_line(range.start + replacement.len());
+            for row in first..=last {
+                assert!(
+                    changed.iter().any(|rows| rows.contains(&row)),
+                    "{row} not in {changed:?} after {range:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn update_rust() {
+        check_edits(
+            Language::Rust,
+            RUST,
+            &[
+                (33..35, "ê"),
+                (30..30, "\n"),
+                (48..48, "x, "),
+                (8..8, "/* "),
+                (62..62, " */"),
+                (0..8, ""),
+                (0..0, "use std::rc::Rc;\n"),
+            ],
+        );
+        // Appending, and then removing everything
+        check_edits(
+            Language::Rust,
+            RUST,
+            &[
+                (RUST.len()..RUST.len(), "enum E {}"),
+                (0..RUST.len() + 9, ""),
+            ],
+        );
+    }
+
+    #[test]
+    fn update_markdown() {
+        check_edits(
+            Language::Markdown,
+            MARKDOWN,
+            &[
+                (14..14, "*"),
+                (20..20, "*"),
+                (0..2, ""),
+                (42..42, "\n"),
+                (7..7, "- item\n"),
+            ],
+        );
+    }
+
+    #[test]
+    fn update_same_text() {
+        let text = Rope::from(RUST);
+        let mut tree = SyntaxTree::new(Language::Rust, &text).unwrap();
+        assert!(tree.update(&text).is_empty());
+    }
+
+    #[test]
+    fn changes_since_last_text() {
+        let cache = SyntaxCache::default();
+        let old = Rope::from(RUST);
+        let mut text = old.clone();
+        text.insert(RUST.len(), "\nfn g() {}");
+        let changes = |old: &Rope, text: &Rope| {
+            cache
+                .with_changes(Language::Rust, old, text, |tree, changed| {
+                    assert!(tree.text.ptr_eq(text));
+                    changed
+                })
+                .unwrap()
+        };
+        // The first tree is parsed whole
+        assert_eq!(changes(&old, &old), None);
+        assert!(changes(&old, &text)
+            .is_some_and(|changed| changed.iter().any(|rows| rows.contains(&8))));
+        // Parsed for another text than `old` since
+        assert_eq!(changes(&old, &text), None);
+        assert_eq!(changes(&text, &text), Some(Vec::new()));
+
+        // A language without a grammar drops the tree
+        assert_eq!(cache.with(Language::Toml, &text, |_| Some(())), None);
+        assert_eq!(changes(&text, &text), None);
+        assert_eq!(cache.with(Language::Rust, &text, |_| Some(1)), Some(1));
+    }
+}
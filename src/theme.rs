@@ -0,0 +1,206 @@
+use std::{fs, io, iter, path::PathBuf};
+
+use serde::Deserialize;
+
+use crate::{
+    config::{self, parse_toml},
+    highlight::Token,
+};
+
+/// Themes that come with the editor besides the default one, by name
+const BUNDLED: &[(&str, &str)] = &[("light", include_str!("themes/light.toml"))];
+
+/// Colours of everything drawn, read from a theme file. Colours it leaves
+/// out are the ones of the default theme.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Theme {
+    /// Name `:colorscheme` loaded it with
+    #[serde(skip)]
+    pub name: String,
+    /// What the screen is cleared with
+    pub background: Colour,
+    /// The text
+    pub foreground: Colour,
+    /// The cursor in normal mode. Cursors blink between a quarter and three
+    /// quarters of their alpha.
+    pub cursor_normal: Colour,
+    /// The cursor in insertion mode
+    pub cursor_insert: Colour,
+    /// The cursor in visual mode
+    pub cursor_visual: Colour,
+    /// The cursor while an operator waits for its motion
+    pub cursor_operator_pending: Colour,
+    /// The bar at the bottom
+    pub footer_background: Colour,
+    /// Text of the bar at the bottom
+    pub footer_foreground: Colour,
+    /// The command line cursor
+    pub footer_cursor: Colour,
+    /// Highlight of the visual mode selection
+    pub selection: Colour,
+    /// Highlight of the search matches
+    pub search: Colour,
+    /// Line numbers, when the `number` setting shows them
+    pub line_number: Colour,
+    /// Syntax highlighting, under `[syntax]`
+    pub syntax: Syntax,
+}
+
+/// Colours of the kinds of spans syntax highlighting finds
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Syntax {
+    pub comment: Colour,
+    pub keyword: Colour,
+    pub r#type: Colour,
+    pub function: Colour,
+    /// Macros, attributes and preprocessor directives
+    pub r#macro: Colour,
+    pub string: Colour,
+    pub number: Colour,
+    /// `true`, `false` and constants in capitals
+    pub constant: Colour,
+    /// Keys of TOML tables
+    pub property: Colour,
+    /// Markdown headings and TOML table headers
+    pub heading: Colour,
+    pub emphasis: Colour,
+    pub link: Colour,
+}
+
+impl Syntax {
+    pub fn colour(&self, token: Token) -> Colour {
+        match token {
+            Token::Comment => self.comment,
+            Token::Keyword => self.keyword,
+            Token::Type => self.r#type,
+            Token::Function => self.function,
+            Token::Macro => self.r#macro,
+            Token::String => self.string,
+            Token::Number => self.number,
+            Token::Constant => self.constant,
+            Token::Property => self.property,
+            Token::Heading => self.heading,
+            Token::Emphasis => self.emphasis,
+            Token::Link => self.link,
+        }
+    }
+}
+
+/// RGBA colour, written `#rrggbb` or `#rrggbbaa` in theme files
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Colour(pub [f32; 4]);
+
+impl TryFrom<String> for Colour {
+    type Error = String;
+
+    fn try_from(hex: String) -> Result<Self, String> {
+        let invalid = || format!("invalid colour `{hex}`, expected one like `#rrggbb`");
+        let digits = hex.strip_prefix('#').ok_or_else(invalid)?;
+        if !matches!(digits.len(), 6 | 8) || !digits.is_ascii() {
+            return Err(invalid());
+        }
+        let mut colour = [1.; 4];
+        for (channel, idx) in colour.iter_mut().zip((0..digits.len()).step_by(2)) {
+            let value = u8::from_str_radix(&digits[idx..idx + 2], 16).map_err(|_| invalid())?;
+            *channel = f32::from(value) / 255.;
+        }
+        Ok(Self(colour))
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            name: "default".into(),
+            background: Colour([0.2, 0.3, 0.3, 1.]),
+            foreground: Colour([1., 1., 1., 1.]),
+            cursor_normal: Colour([1., 1., 1., 1.]),
+            cursor_insert: Colour([1., 1., 1., 1.]),
+            cursor_visual: Colour([1., 1., 1., 1.]),
+            cursor_operator_pending: Colour([1., 1., 1., 1.]),
+            footer_background: Colour([0., 1., 1., 1.]),
+            footer_foreground: Colour([1., 1., 1., 1.]),
+            footer_cursor: Colour([0., 0., 0., 1.]),
+            selection: Colour([0.3, 0.5, 0.8, 0.5]),
+            search: Colour([0.9, 0.8, 0.2, 0.5]),
+            line_number: Colour([0.56, 0.65, 0.65, 1.]),
+            syntax: Syntax::default(),
+        }
+    }
+}
+
+impl Default for Syntax {
+    fn default() -> Self {
+        Self {
+            comment: Colour([0.6, 0.7, 0.68, 1.]),
+            keyword: Colour([1., 0.8, 0.4, 1.]),
+            r#type: Colour([0.5, 0.9, 0.85, 1.]),
+            function: Colour([0.65, 0.8, 1., 1.]),
+            r#macro: Colour([0.95, 0.6, 0.85, 1.]),
+            string: Colour([0.7, 0.95, 0.55, 1.]),
+            number: Colour([1., 0.65, 0.5, 1.]),
+            constant: Colour([1., 0.65, 0.5, 1.]),
+            property: Colour([0.65, 0.8, 1., 1.]),
+            heading: Colour([1., 0.8, 0.4, 1.]),
+            emphasis: Colour([0.95, 0.85, 0.7, 1.]),
+            link: Colour([0.5, 0.9, 0.85, 1.]),
+        }
+    }
+}
+
+impl Theme {
+    /// Loads the theme called `name`, from `themes/{name}.toml` in the config
+    /// directory, or one of the bundled ones. `default` is the colours the
+    /// editor starts with, unless a file replaces it.
+    pub fn load(name: &str) -> Result<Self, String> {
+        let path = user_dir().map(|dir| dir.join(format!("{name}.toml")));
+        let user_theme = match path.as_deref().map(|path| (path, fs::read_to_string(path))) {
+            Some((path, Ok(text))) => Some(parse_toml(path, &text)?),
+            Some((path, Err(e))) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(format!("Couldn't read {}: {e}", path.display()));
+            }
+            _ => None,
+        };
+        let theme = match user_theme {
+            Some(theme) => theme,
+            None if name == "default" => Self::default(),
+            None => match BUNDLED.iter().find(|(bundled, _)| *bundled == name) {
+                Some((_, text)) => toml::from_str(text).expect("Bundled theme is valid"),
+                None => return Err(format!("E185: Cannot find color scheme '{name}'")),
+            },
+        };
+        Ok(Self {
+            name: name.into(),
+            ..theme
+        })
+    }
+}
+
+/// Names of the themes there are, for completion
+pub fn names() -> Vec<String> {
+    let bundled = BUNDLED.iter().map(|(name, _)| name.to_string());
+    let mut names: Vec<_> = iter::once("default".to_string()).chain(bundled).collect();
+    if let Some(entries) = user_dir().and_then(|dir| fs::read_dir(dir).ok()) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "toml") {
+                names.extend(
+                    path.file_stem()
+                        .and_then(|stem| stem.to_str())
+                        .map(String::from),
+                );
+            }
+        }
+    }
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// `themes` in the config directory
+fn user_dir() -> Option<PathBuf> {
+    Some(config::config_dir()?.join("themes"))
+}
@@ -0,0 +1,151 @@
+use std::rc::Rc;
+
+use crate::rope::Rope;
+
+/// Branching undo history. Every node holds a snapshot of the whole buffer,
+/// which is cheap since ropes share their nodes.
+///
+/// Nodes are only ever appended, so a node's index is also its change number,
+/// the one `g-`/`g+` and `:undo N` walk through.
+#[derive(Clone, PartialEq, Eq)]
+pub struct UndoTree {
+    nodes: Rc<Vec<UndoNode>>,
+    current: usize,
+    /// Node matching the file on disk
+    saved: Option<usize>,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+struct UndoNode {
+    text: Rope,
+    parent: Option<usize>,
+    /// Child `redo` goes to: the last one created or visited
+    redo_child: Option<usize>,
+}
+
+impl UndoTree {
+    pub fn new(text: Rope) -> Self {
+        Self {
+            nodes: Rc::new(vec![UndoNode {
+                text,
+                parent: None,
+                redo_child: None,
+            }]),
+            current: 0,
+            saved: Some(0),
+        }
+    }
+
+    /// Records `text` as a new change if it differs from the current state.
+    /// Returns whether a change was recorded.
+    pub fn commit(&mut self, text: &Rope) -> bool {
+        if self.nodes[self.current].text.ptr_eq(text) {
+            return false;
+        }
+
+        let nodes = Rc::make_mut(&mut self.nodes);
+        let new = nodes.len();
+        nodes.push(UndoNode {
+            text: text.clone(),
+            parent: Some(self.current),
+            redo_child: None,
+        });
+        nodes[self.current].redo_child = Some(new);
+        self.current = new;
+        true
+    }
+
+    /// Goes back to the parent state and returns its text
+    pub fn undo(&mut self) -> Option<Rope> {
+        let parent = self.nodes[self.current].parent?;
+        self.current = parent;
+        Some(self.nodes[parent].text.clone())
+    }
+
+    /// Goes forward along the last visited branch and returns its text
+    pub fn redo(&mut self) -> Option<Rope> {
+        let child = self.nodes[self.current].redo_child?;
+        self.current = child;
+        Some(self.nodes[child].text.clone())
+    }
+
+    /// Jumps to change number `seq`, whatever branch it is on, and returns its
+    /// text. Redo then follows the path to it.
+    pub fn goto(&mut self, seq: usize) -> Option<Rope> {
+        if seq >= self.nodes.len() {
+            return None;
+        }
+
+        let nodes = Rc::make_mut(&mut self.nodes);
+        let mut child = seq;
+        while let Some(parent) = nodes[child].parent {
+            nodes[parent].redo_child = Some(child);
+            child = parent;
+        }
+        self.current = seq;
+        Some(nodes[seq].text.clone())
+    }
+
+    /// Number of the current change, 0 being the original text
+    pub fn seq(&self) -> usize {
+        self.current
+    }
+
+    /// Number of the latest change
+    pub fn last_seq(&self) -> usize {
+        self.nodes.len() - 1
+    }
+
+    pub fn mark_saved(&mut self) {
+        self.saved = Some(self.current);
+    }
+
+    pub fn is_saved(&self) -> bool {
+        self.saved == Some(self.current)
+    }
+}
+
+/// Byte offset of the first char that differs between both ropes
+pub fn first_difference(a: &Rope, b: &Rope) -> usize {
+    a.char_indices_from(0)
+        .zip(b.chars())
+        .find(|&((_, ca), cb)| ca != cb)
+        .map_or(a.len_bytes().min(b.len_bytes()), |((idx, _), _)| idx)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    /// Bytes of `rope` held in the same allocations as the chunks of `other`
+    fn shared_bytes(rope: &Rope, other: &Rope) -> usize {
+        let theirs: HashSet<_> = other.chunks().map(str::as_ptr).collect();
+        rope.chunks()
+            .filter(|chunk| theirs.contains(&chunk.as_ptr()))
+            .map(str::len)
+            .sum()
+    }
+
+    #[test]
+    fn snapshots_share_text() {
+        let original = Rope::from("lorem ipsum\n".repeat(5000).as_str());
+        let mut text = original.clone();
+        let mut history = UndoTree::new(original.clone());
+        for i in 0..1000 {
+            text.insert(30_000 + i, "x");
+            assert!(history.commit(&text));
+            assert!(!history.commit(&text));
+        }
+
+        for seq in (0..=history.last_seq()).rev() {
+            let snapshot = history.goto(seq).unwrap();
+            assert_eq!(snapshot.len_bytes(), original.len_bytes() + seq);
+            // Everything but the 4 KiB leaf typed into is the original's
+            assert!(shared_bytes(&snapshot, &original) >= original.len_bytes() - 4096);
+        }
+        assert_eq!(history.undo(), None);
+        assert_eq!(history.redo(), Some(history.nodes[1].text.clone()));
+    }
+}
@@ -0,0 +1,5 @@
+/// Adds one, the long way
+fn add_one(x: u8) -> u8 {
+    let one = 1; // "one"
+    x + one
+}
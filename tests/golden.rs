@@ -0,0 +1,29 @@
+//! Renders a fixed file with `--screenshot`, which draws with the CPU
+//! renderer, and compares it to the frame checked in next to it
+use std::{env, fs, path::Path, process::Command};
+
+#[test]
+fn screenshot_matches_golden_frame() {
+    let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+    let out = env::temp_dir().join(format!("saphedit-golden-{}.ppm", std::process::id()));
+    // Run from the data, so that the footer shows the same file name
+    let status = Command::new(env!("CARGO_BIN_EXE_sedit"))
+        .current_dir(&data)
+        .arg("golden.rs")
+        .arg("--screenshot")
+        .arg(&out)
+        .env("FONTCONFIG_FILE", data.join("fonts.conf"))
+        .status()
+        .expect("Couldn't run the editor");
+    assert!(status.success());
+
+    let frame = fs::read(&out).expect("No screenshot was written");
+    let golden = fs::read(data.join("golden.ppm")).expect("Couldn't read the golden frame");
+    // Kept for inspection when it differs
+    assert!(
+        frame == golden,
+        "{} differs from the golden frame",
+        out.display()
+    );
+    let _ = fs::remove_file(out);
+}
@@ -0,0 +1,44 @@
+//! Replays a recorded session with `--replay`, which feeds the inputs to
+//! `handle_input` as the window would, and checks where it ends up
+use std::{path::Path, process::Command};
+
+#[test]
+fn replay_reproduces_recorded_session() {
+    let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+    // `dw`, `j0ciwfirst<Esc>`, `wibig <Esc>` then `V`, typed as SDL reports
+    // them: every key press, followed by its text
+    let output = Command::new(env!("CARGO_BIN_EXE_sedit"))
+        .current_dir(&data)
+        .args(["replay.txt", "--replay", "replay.keys"])
+        .output()
+        .expect("Couldn't run the editor");
+    assert!(output.status.success());
+
+    let dump = String::from_utf8(output.stdout).expect("The dump is UTF-8");
+    assert_eq!(
+        dump,
+        "cursor 2:10\nmode visual line\nworld\nfirst big line\n"
+    );
+}
+
+#[test]
+fn replay_uses_recorded_config_and_clipboard() {
+    let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+    // `Q` and `<C-p>` only delete the line and paste with the config, and
+    // what they paste is what the clipboard held when recording
+    let output = Command::new(env!("CARGO_BIN_EXE_sedit"))
+        .current_dir(&data)
+        .args([
+            "replay.txt",
+            "--config",
+            "paste.toml",
+            "--replay",
+            "paste.keys",
+        ])
+        .output()
+        .expect("Couldn't run the editor");
+    assert!(output.status.success());
+
+    let dump = String::from_utf8(output.stdout).expect("The dump is UTF-8");
+    assert_eq!(dump, "cursor 1:7\nmode normal\npasted second line\n");
+}